@@ -0,0 +1,15 @@
+// Echoes the CSRF cookie in a header on every HTMX request and shows why one was refused.
+(function () {
+  function csrfToken() {
+    var match = document.cookie.match(/(?:^|; )mptt_csrf=([^;]*)/);
+    return match ? match[1] : "";
+  }
+  document.addEventListener("htmx:configRequest", function (event) {
+    event.detail.headers["X-CSRF-Token"] = csrfToken();
+  });
+  document.addEventListener("htmx:responseError", function (event) {
+    if (event.detail.xhr.status === 403) {
+      alert(event.detail.xhr.responseText);
+    }
+  });
+})();
@@ -0,0 +1,24 @@
+// Keeps the register table and the status bar up to date from the /ws endpoint.
+// Every message is an HTML fragment whose root id names the element to refresh.
+(function () {
+  function connect() {
+    var scheme = window.location.protocol === "https:" ? "wss://" : "ws://";
+    var socket = new WebSocket(scheme + window.location.host + "/ws?format=html");
+    socket.onmessage = function (event) {
+      var template = document.createElement("template");
+      template.innerHTML = event.data;
+      var fragment = template.content.firstElementChild;
+      if (!fragment) {
+        return;
+      }
+      var target = document.getElementById(fragment.id);
+      if (target) {
+        target.innerHTML = fragment.innerHTML;
+      }
+    };
+    socket.onclose = function () {
+      setTimeout(connect, 1000);
+    };
+  }
+  connect();
+})();
@@ -0,0 +1,693 @@
+use axum::extract::{Form, State};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::historian::now_millis;
+use crate::modbus::{modbus_status_bar, ModbusState, MARGIN, TABLE_WIDTH, WINDOW_WIDTH};
+use crate::notify::{NotificationEvent, Notifier};
+
+const HISTORY_CAPACITY: usize = 500;
+
+/// When an alarm is active, evaluated on every good read of its tag.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlarmCondition {
+    /// Active above `limit`, clears below `limit - deadband`.
+    High {
+        limit: f64,
+        #[serde(default)]
+        deadband: f64,
+    },
+    /// Active below `limit`, clears above `limit + deadband`.
+    Low {
+        limit: f64,
+        #[serde(default)]
+        deadband: f64,
+    },
+    /// Active while the coil is on, or bit `bit` of a status word is set.
+    BitOn {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bit: Option<u8>,
+    },
+    BitOff {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bit: Option<u8>,
+    },
+    /// Active while the value changes by more than `limit` per second.
+    RateOfChange { limit: f64 },
+    /// Active when the tag had no good read for `seconds`.
+    Stale { seconds: u64 },
+}
+
+impl fmt::Display for AlarmCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlarmCondition::High { limit, deadband } => {
+                write!(f, "> {} (deadband {})", limit, deadband)
+            }
+            AlarmCondition::Low { limit, deadband } => {
+                write!(f, "< {} (deadband {})", limit, deadband)
+            }
+            AlarmCondition::BitOn { bit: None } => write!(f, "On"),
+            AlarmCondition::BitOn { bit: Some(bit) } => write!(f, "Bit {} on", bit),
+            AlarmCondition::BitOff { bit: None } => write!(f, "Off"),
+            AlarmCondition::BitOff { bit: Some(bit) } => write!(f, "Bit {} off", bit),
+            AlarmCondition::RateOfChange { limit } => write!(f, "Changes > {} /s", limit),
+            AlarmCondition::Stale { seconds } => write!(f, "No good read for {} s", seconds),
+        }
+    }
+}
+
+/// A named condition on a tag, saved with the project.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct AlarmRule {
+    pub name: String,
+    /// Tag name, or the register name (e.g. `HR10`) of an untagged register.
+    pub tag: String,
+    pub condition: AlarmCondition,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct ActiveAlarm {
+    pub name: String,
+    pub tag: String,
+    pub message: String,
+    /// Latest value of the tag, none for stale data.
+    pub value: Option<f64>,
+    pub raised: i64,
+    /// Set when the condition is gone but the alarm isn't acknowledged yet.
+    pub cleared: Option<i64>,
+    pub acknowledged: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmEventKind {
+    Raised,
+    Cleared,
+    Acknowledged,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct AlarmEvent {
+    pub timestamp: i64,
+    pub name: String,
+    pub tag: String,
+    pub kind: AlarmEventKind,
+    pub value: Option<f64>,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Reading {
+    timestamp: i64,
+    value: f64,
+}
+
+/// Active alarms and their history, fed with the poller's values.
+#[derive(Default)]
+pub struct Alarms {
+    /// Raised alarms, kept until they are both cleared and acknowledged.
+    pub active: Vec<ActiveAlarm>,
+    /// Latest events, oldest first.
+    pub history: VecDeque<AlarmEvent>,
+    readings: HashMap<String, Reading>,
+    /// Start of stale data detection for tags that were never read.
+    watching_since: Option<i64>,
+    pub notifier: Notifier,
+}
+
+fn bit_set(value: f64, bit: Option<u8>) -> bool {
+    match bit {
+        Some(bit) => (value as i64 as u16) >> (bit & 15) & 1 == 1,
+        None => value != 0.0,
+    }
+}
+
+impl AlarmCondition {
+    /// New alarm state after a good read, `None` when the read doesn't decide it.
+    fn on_value(
+        &self,
+        active: bool,
+        value: f64,
+        previous: Option<Reading>,
+        now: i64,
+    ) -> Option<bool> {
+        match self {
+            AlarmCondition::High { limit, deadband } => Some(if active {
+                value >= limit - deadband
+            } else {
+                value > *limit
+            }),
+            AlarmCondition::Low { limit, deadband } => Some(if active {
+                value <= limit + deadband
+            } else {
+                value < *limit
+            }),
+            AlarmCondition::BitOn { bit } => Some(bit_set(value, *bit)),
+            AlarmCondition::BitOff { bit } => Some(!bit_set(value, *bit)),
+            AlarmCondition::RateOfChange { limit } => {
+                let previous = previous.filter(|p| now > p.timestamp)?;
+                let seconds = (now - previous.timestamp) as f64 / 1000.0;
+                Some((value - previous.value).abs() / seconds > *limit)
+            }
+            AlarmCondition::Stale { .. } => Some(false),
+        }
+    }
+}
+
+impl Alarms {
+    fn is_active(&self, name: &str) -> bool {
+        self.active
+            .iter()
+            .any(|alarm| alarm.name == name && alarm.cleared.is_none())
+    }
+
+    fn log(&mut self, alarm: &ActiveAlarm, kind: AlarmEventKind, timestamp: i64) {
+        println!("Alarm {:?}: {} ({})", kind, alarm.name, alarm.tag);
+        if self.history.len() == HISTORY_CAPACITY {
+            self.history.pop_front();
+        }
+        let event = AlarmEvent {
+            timestamp,
+            name: alarm.name.clone(),
+            tag: alarm.tag.clone(),
+            kind,
+            value: alarm.value,
+            message: alarm.message.clone(),
+        };
+        self.notifier
+            .notify(NotificationEvent::Alarm(event.clone()));
+        self.history.push_back(event);
+    }
+
+    fn update(&mut self, rule: &AlarmRule, active: bool, value: Option<f64>, now: i64) {
+        let index = self.active.iter().position(|alarm| alarm.name == rule.name);
+        match (index, active) {
+            (None, true) => {
+                let alarm = ActiveAlarm {
+                    name: rule.name.clone(),
+                    tag: rule.tag.clone(),
+                    message: rule.message.clone(),
+                    value,
+                    raised: now,
+                    cleared: None,
+                    acknowledged: None,
+                };
+                self.log(&alarm, AlarmEventKind::Raised, now);
+                self.active.push(alarm);
+            }
+            (Some(i), _) => {
+                let alarm = &mut self.active[i];
+                alarm.value = value.or(alarm.value);
+                let kind = match (alarm.cleared.is_some(), active) {
+                    // Back before anyone acknowledged the last occurrence.
+                    (true, true) => {
+                        alarm.raised = now;
+                        alarm.cleared = None;
+                        alarm.acknowledged = None;
+                        AlarmEventKind::Raised
+                    }
+                    (false, false) => {
+                        alarm.cleared = Some(now);
+                        AlarmEventKind::Cleared
+                    }
+                    _ => return,
+                };
+                let alarm = alarm.clone();
+                self.log(&alarm, kind, now);
+                if alarm.cleared.is_some() && alarm.acknowledged.is_some() {
+                    self.active.remove(i);
+                }
+            }
+            (None, false) => {}
+        }
+    }
+
+    /// Checks the value conditions against one poll worth of good reads.
+    pub fn evaluate(&mut self, rules: &[AlarmRule], values: &[(String, f64)], now: i64) {
+        for (tag, value) in values {
+            let previous = self.readings.insert(
+                tag.clone(),
+                Reading {
+                    timestamp: now,
+                    value: *value,
+                },
+            );
+            for rule in rules.iter().filter(|rule| &rule.tag == tag) {
+                let active = self.is_active(&rule.name);
+                if let Some(active) = rule.condition.on_value(active, *value, previous, now) {
+                    self.update(rule, active, Some(*value), now);
+                }
+            }
+        }
+    }
+
+    /// Raises stale data alarms, only called while a connection is configured.
+    pub fn check_stale(&mut self, rules: &[AlarmRule], now: i64) {
+        let since = *self.watching_since.get_or_insert(now);
+        for rule in rules {
+            let AlarmCondition::Stale { seconds } = rule.condition else {
+                continue;
+            };
+            let last = self.readings.get(&rule.tag).map_or(since, |r| r.timestamp);
+            if now - last >= seconds as i64 * 1000 {
+                self.update(rule, true, None, now);
+            }
+        }
+    }
+
+    /// Restarts stale data and rate of change detection, e.g. without a connection.
+    pub fn stop_watching(&mut self) {
+        self.watching_since = None;
+        self.readings.clear();
+    }
+
+    /// Acknowledges one alarm, or all of them without a name.
+    pub fn acknowledge(&mut self, name: Option<&str>, now: i64) -> usize {
+        let mut acknowledged = Vec::new();
+        for alarm in self.active.iter_mut() {
+            if alarm.acknowledged.is_none() && name.is_none_or(|name| alarm.name == name) {
+                alarm.acknowledged = Some(now);
+                acknowledged.push(alarm.clone());
+            }
+        }
+        for alarm in &acknowledged {
+            self.log(alarm, AlarmEventKind::Acknowledged, now);
+        }
+        self.active
+            .retain(|alarm| alarm.cleared.is_none() || alarm.acknowledged.is_none());
+        acknowledged.len()
+    }
+
+    /// Drops alarms whose rule was removed or changed.
+    pub fn retain_rules(&mut self, rules: &[AlarmRule]) {
+        self.active.retain(|alarm| {
+            rules
+                .iter()
+                .any(|rule| rule.name == alarm.name && rule.tag == alarm.tag)
+        });
+    }
+
+    /// Active alarms and how many of them wait for an acknowledgement.
+    pub fn counts(&self) -> (usize, usize) {
+        let active = self.active.iter().filter(|a| a.cleared.is_none()).count();
+        let unacknowledged = self
+            .active
+            .iter()
+            .filter(|a| a.acknowledged.is_none())
+            .count();
+        (active, unacknowledged)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AlarmForm {
+    pub name: String,
+    pub tag: String,
+    pub condition: String,
+    #[serde(default)]
+    pub limit: f64,
+    #[serde(default)]
+    pub deadband: f64,
+    /// Empty for coils and whole values.
+    #[serde(default)]
+    pub bit: String,
+    #[serde(default)]
+    pub seconds: u64,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl TryFrom<AlarmForm> for AlarmRule {
+    type Error = String;
+
+    fn try_from(form: AlarmForm) -> Result<Self, String> {
+        let name = form.name.trim().to_string();
+        let tag = form.tag.trim().to_string();
+        if name.is_empty() || tag.is_empty() {
+            return Err("An alarm needs a name and a tag.".to_string());
+        }
+        let bit = match form.bit.trim() {
+            "" => None,
+            bit => match bit.parse::<u8>() {
+                Ok(bit) if bit < 16 => Some(bit),
+                _ => return Err("The bit must be between 0 and 15.".to_string()),
+            },
+        };
+        let (limit, deadband) = (form.limit, form.deadband.abs());
+        let condition = match form.condition.as_str() {
+            "high" => AlarmCondition::High { limit, deadband },
+            "low" => AlarmCondition::Low { limit, deadband },
+            "bit_on" => AlarmCondition::BitOn { bit },
+            "bit_off" => AlarmCondition::BitOff { bit },
+            "rate_of_change" => AlarmCondition::RateOfChange { limit: limit.abs() },
+            "stale" if form.seconds > 0 => AlarmCondition::Stale {
+                seconds: form.seconds,
+            },
+            "stale" => return Err("Stale data needs a time in seconds.".to_string()),
+            other => return Err(format!("Unknown condition: {}", other)),
+        };
+        Ok(AlarmRule {
+            name,
+            tag,
+            condition,
+            message: form.message.trim().to_string(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AlarmNameForm {
+    /// Every alarm when not set.
+    pub name: Option<String>,
+}
+
+/// Adds the rule, or replaces the one with the same name.
+pub fn set_alarm_rule(state: &mut ModbusState, rule: AlarmRule) {
+    let rules = &mut state.project.alarms;
+    match rules.iter_mut().find(|r| r.name == rule.name) {
+        Some(existing) => *existing = rule,
+        None => rules.push(rule),
+    }
+    state.alarms.retain_rules(&state.project.alarms);
+}
+
+pub fn remove_alarm_rule(state: &mut ModbusState, name: &str) -> bool {
+    let count = state.project.alarms.len();
+    state.project.alarms.retain(|rule| rule.name != name);
+    state.alarms.retain_rules(&state.project.alarms);
+    state.project.alarms.len() != count
+}
+
+/// Hours, minutes and seconds in UTC.
+fn time_of_day(timestamp: i64) -> String {
+    let seconds = timestamp.div_euclid(1000).rem_euclid(24 * 60 * 60);
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn value_cell(value: Option<f64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn alarm_tables_fragment(alarms: &Alarms) -> Markup {
+    html! {
+        #alarm_tables {
+            div hx-get="/alarm_tables" hx-trigger="load delay:1s" hx-target="#alarm_tables" hx-swap="outerHTML" {}
+            fieldset {
+                legend { "Active Alarms" }
+                div class="sunken-panel" style=(format!("height: 160px; width: {}px", TABLE_WIDTH)) {
+                    table class="interactive" {
+                        thead {
+                            tr {
+                                th { "Raised (UTC)" }
+                                th { "Alarm" }
+                                th { "Tag" }
+                                th { "Value" }
+                                th { "Message" }
+                                th { "State" }
+                                th { "" }
+                            }
+                        }
+                        tbody {
+                            @for alarm in &alarms.active {
+                                tr {
+                                    td { (time_of_day(alarm.raised)) }
+                                    td { (alarm.name) }
+                                    td { (alarm.tag) }
+                                    td { (value_cell(alarm.value)) }
+                                    td { (alarm.message) }
+                                    td {
+                                        @match (alarm.cleared, alarm.acknowledged) {
+                                            (Some(_), _) => "Cleared",
+                                            (None, Some(_)) => "Acknowledged",
+                                            (None, None) => "Active",
+                                        }
+                                    }
+                                    td {
+                                        @if alarm.acknowledged.is_none() {
+                                            button hx-post="/acknowledge_alarm" hx-vals=(format!(r#"{{"name": "{}"}}"#, alarm.name.replace('"', "\\\""))) hx-target="#alarm_tables" hx-swap="outerHTML" { "Acknowledge" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                div class="field-row" {
+                    button hx-post="/acknowledge_alarm" hx-target="#alarm_tables" hx-swap="outerHTML" { "Acknowledge All" }
+                }
+            }
+            fieldset {
+                legend { "History" }
+                div class="sunken-panel" style=(format!("height: 200px; width: {}px", TABLE_WIDTH)) {
+                    table class="interactive" {
+                        thead {
+                            tr {
+                                th { "Time (UTC)" }
+                                th { "Alarm" }
+                                th { "Tag" }
+                                th { "Event" }
+                                th { "Value" }
+                            }
+                        }
+                        tbody {
+                            @for event in alarms.history.iter().rev() {
+                                tr {
+                                    td { (time_of_day(event.timestamp)) }
+                                    td { (event.name) }
+                                    td { (event.tag) }
+                                    td { (format!("{:?}", event.kind)) }
+                                    td { (value_cell(event.value)) }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn alarm_rules_fragment(rules: &[AlarmRule], status: &str) -> Markup {
+    html! {
+        #alarm_rules {
+            table class="interactive" {
+                thead {
+                    tr {
+                        th { "Alarm" }
+                        th { "Tag" }
+                        th { "Condition" }
+                        th { "Message" }
+                        th { "" }
+                    }
+                }
+                tbody {
+                    @for rule in rules {
+                        tr {
+                            td { (rule.name) }
+                            td { (rule.tag) }
+                            td { (rule.condition) }
+                            td { (rule.message) }
+                            td {
+                                button hx-post="/delete_alarm" hx-vals=(format!(r#"{{"name": "{}"}}"#, rule.name.replace('"', "\\\""))) hx-target="#alarm_rules" hx-swap="outerHTML" { "Delete" }
+                            }
+                        }
+                    }
+                }
+            }
+            p { (status) }
+        }
+    }
+}
+
+pub async fn alarm_tables(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    alarm_tables_fragment(&mtx.lock().await.alarms)
+}
+
+pub async fn acknowledge_alarm(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<AlarmNameForm>,
+) -> Markup {
+    let mut mtx = mtx.lock().await;
+    mtx.alarms
+        .acknowledge(form_input.name.as_deref(), now_millis());
+    alarm_tables_fragment(&mtx.alarms)
+}
+
+pub async fn add_alarm(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<AlarmForm>,
+) -> Markup {
+    let mut mtx = mtx.lock().await;
+    let status = match AlarmRule::try_from(form_input) {
+        Ok(rule) => {
+            let status = format!("Saved {}, save the project to keep it.", rule.name);
+            set_alarm_rule(&mut mtx, rule);
+            status
+        }
+        Err(e) => e,
+    };
+    alarm_rules_fragment(&mtx.project.alarms, &status)
+}
+
+pub async fn delete_alarm(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<AlarmNameForm>,
+) -> Markup {
+    let mut mtx = mtx.lock().await;
+    let name = form_input.name.unwrap_or_default();
+    let status = if remove_alarm_rule(&mut mtx, &name) {
+        format!("Deleted {}.", name)
+    } else {
+        format!("No alarm named {}.", name)
+    };
+    alarm_rules_fragment(&mtx.project.alarms, &status)
+}
+
+pub fn alarms_body(state: &ModbusState) -> Markup {
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Alarms" }
+                    }
+                    div class="window-body" {
+                        (alarm_tables_fragment(&state.alarms))
+                        fieldset {
+                            legend { "Conditions" }
+                            (alarm_rules_fragment(&state.project.alarms, ""))
+                            form hx-post="/add_alarm" hx-target="#alarm_rules" hx-swap="outerHTML" {
+                                div class="field-row-stacked" style="width: 200px" {
+                                    label for="name" { "Name: " }
+                                    input type="text" id="name" name="name" {}
+                                    label for="tag" { "Tag: " }
+                                    input type="text" id="tag" name="tag" {}
+                                    label for="condition" { "Condition: " }
+                                    select id="condition" name="condition" {
+                                        option value="high" { "High limit" }
+                                        option value="low" { "Low limit" }
+                                        option value="bit_on" { "Bit on" }
+                                        option value="bit_off" { "Bit off" }
+                                        option value="rate_of_change" { "Rate of change" }
+                                        option value="stale" { "Stale data" }
+                                    }
+                                    label for="limit" { "Limit: (per second for rate of change)" }
+                                    input type="number" step="any" id="limit" name="limit" value="0" {}
+                                    label for="deadband" { "Deadband: " }
+                                    input type="number" step="any" id="deadband" name="deadband" value="0" {}
+                                    label for="bit" { "Bit: (empty for coils)" }
+                                    input type="text" id="bit" name="bit" {}
+                                    label for="seconds" { "No good read for: (s)" }
+                                    input type="number" id="seconds" name="seconds" value="10" {}
+                                    label for="message" { "Message: " }
+                                    input type="text" id="message" name="message" {}
+                                    button { "Save" }
+                                }
+                            }
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, condition: AlarmCondition) -> AlarmRule {
+        AlarmRule {
+            name: name.to_string(),
+            tag: "Level".to_string(),
+            condition,
+            message: String::new(),
+        }
+    }
+
+    fn level(alarms: &mut Alarms, rules: &[AlarmRule], value: f64, now: i64) {
+        alarms.evaluate(rules, &[("Level".to_string(), value)], now);
+    }
+
+    #[test]
+    fn deadband_and_acknowledge() {
+        let rules = [rule(
+            "High level",
+            AlarmCondition::High {
+                limit: 80.0,
+                deadband: 5.0,
+            },
+        )];
+        let mut alarms = Alarms::default();
+        level(&mut alarms, &rules, 81.0, 1000);
+        assert_eq!(alarms.counts(), (1, 1));
+        // Inside the deadband the alarm stays active.
+        level(&mut alarms, &rules, 77.0, 2000);
+        assert_eq!(alarms.counts(), (1, 1));
+        level(&mut alarms, &rules, 74.0, 3000);
+        // Cleared alarms stay listed until acknowledged.
+        assert_eq!(alarms.counts(), (0, 1));
+        assert_eq!(alarms.active[0].cleared, Some(3000));
+        assert_eq!(alarms.acknowledge(None, 4000), 1);
+        assert!(alarms.active.is_empty());
+
+        level(&mut alarms, &rules, 90.0, 5000);
+        alarms.acknowledge(Some("High level"), 6000);
+        assert_eq!(alarms.counts(), (1, 0));
+        let kinds: Vec<AlarmEventKind> = alarms.history.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                AlarmEventKind::Raised,
+                AlarmEventKind::Cleared,
+                AlarmEventKind::Acknowledged,
+                AlarmEventKind::Raised,
+                AlarmEventKind::Acknowledged
+            ]
+        );
+    }
+
+    #[test]
+    fn bits_rate_of_change_and_stale() {
+        let rules = [
+            rule("Fault bit", AlarmCondition::BitOn { bit: Some(3) }),
+            rule("Fast change", AlarmCondition::RateOfChange { limit: 2.0 }),
+            rule("No data", AlarmCondition::Stale { seconds: 5 }),
+        ];
+        let mut alarms = Alarms::default();
+        alarms.check_stale(&rules, 0);
+        level(&mut alarms, &rules, 0b0100 as f64, 1000);
+        assert!(alarms.active.is_empty());
+        // 4 per second and bit 3 set.
+        level(&mut alarms, &rules, 0b1000 as f64, 2000);
+        let names: Vec<&str> = alarms.active.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["Fault bit", "Fast change"]);
+
+        alarms.check_stale(&rules, 6000);
+        assert!(!alarms.is_active("No data"));
+        alarms.check_stale(&rules, 7000);
+        assert!(alarms.is_active("No data"));
+        // A good read clears stale data.
+        level(&mut alarms, &rules, 0b1000 as f64, 8000);
+        assert!(!alarms.is_active("No data"));
+        assert!(!alarms.is_active("Fast change"));
+        assert!(alarms.is_active("Fault bit"));
+    }
+}
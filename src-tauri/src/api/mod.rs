@@ -0,0 +1,1060 @@
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::alarms::{ActiveAlarm, AlarmCondition, AlarmEvent, AlarmEventKind, AlarmRule};
+use crate::audit::{client_origin, AuditEntry};
+use crate::auth::CurrentUser;
+use crate::historian::{
+    self, now_millis, Bucket, HistoryQuery, HistoryResponse, Sample, DEFAULT_QUERY_RANGE,
+};
+use crate::modbus::{
+    connect, connect_modbus_tcp, disconnect, function_code_from, read_block, write_modbus,
+    write_value, ConnectionSettings, DataType, LinkState, LinkTransition, ModbusState,
+    ModbusTcpForm, ModbusWriteForm, PollData, PollSnapshot, ProtocolOpts, ReadError, TcpFraming,
+    TlsPeer, TlsSettings, WriteRequest,
+};
+use crate::mqtt::{MqttSettings, PayloadFormat, PublishMode};
+use crate::notify::{Delivery, NotificationEvent, NotificationSink, SinkTarget};
+use crate::stats::{statistics_snapshot, ConnectionStats, Counters, LatencyStats};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "MPTT API",
+        description = "Modbus polling and writing over JSON."
+    ),
+    paths(
+        list_connections,
+        create_connection,
+        delete_connection,
+        read,
+        write,
+        get_polling,
+        set_polling,
+        status,
+        get_statistics,
+        reset_statistics,
+        list_alarms,
+        acknowledge_alarms,
+        alarm_history,
+        get_alarm_rules,
+        set_alarm_rules,
+        get_notifications,
+        set_notifications,
+        notification_log,
+        get_mqtt,
+        set_mqtt,
+        get_write_lock,
+        set_write_lock,
+        audit_log,
+        history,
+        crate::modbus::connect_modbus_tcp,
+        crate::modbus::write_modbus
+    ),
+    components(schemas(
+        ApiError,
+        ConnectionInfo,
+        ConnectionSettings,
+        DataType,
+        PollData,
+        PollSnapshot,
+        PollingOptions,
+        ReadRequest,
+        ReadResponse,
+        StatusResponse,
+        WriteRequest,
+        WriteResponse,
+        WriteLock,
+        AuditEntry,
+        TlsSettings,
+        TlsPeer,
+        LinkState,
+        LinkTransition,
+        ConnectionStats,
+        Counters,
+        LatencyStats,
+        ActiveAlarm,
+        AlarmCondition,
+        AlarmEvent,
+        AlarmEventKind,
+        AlarmRule,
+        AcknowledgeRequest,
+        AcknowledgeResponse,
+        NotificationEvent,
+        NotificationSink,
+        SinkTarget,
+        Delivery,
+        MqttSettings,
+        PayloadFormat,
+        PublishMode,
+        HistoryResponse,
+        Sample,
+        Bucket,
+        ModbusTcpForm,
+        TcpFraming,
+        ModbusWriteForm
+    ))
+)]
+pub struct ApiDoc;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ApiError {
+    pub error: String,
+}
+
+pub type ApiResult<T> = Result<Json<T>, (StatusCode, Json<ApiError>)>;
+
+fn api_error<T>(status: StatusCode, error: impl Into<String>) -> ApiResult<T> {
+    Err((
+        status,
+        Json(ApiError {
+            error: error.into(),
+        }),
+    ))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ConnectionInfo {
+    pub connected: bool,
+    pub settings: ConnectionSettings,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReadRequest {
+    /// 1 (coils), 3 (holding registers) or 4 (input registers).
+    pub function: u8,
+    pub register: u16,
+    pub count: u16,
+    #[serde(default)]
+    pub data_type: DataType,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReadResponse {
+    pub function: u8,
+    pub register: u16,
+    pub data: PollData,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WriteResponse {
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WriteLock {
+    /// Writes to the active connection are rejected while set.
+    pub read_only: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PollingOptions {
+    pub function: u8,
+    pub register: u16,
+    pub count: u16,
+    #[serde(default)]
+    pub data_type: DataType,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StatusResponse {
+    pub connected: bool,
+    pub connection: Option<ConnectionSettings>,
+    /// Negotiated certificate details of a TLS connection.
+    pub tls: Option<TlsPeer>,
+    pub link: LinkState,
+    /// Writes to the active connection are rejected.
+    pub read_only: bool,
+    /// Latest connection state changes, oldest first.
+    pub link_log: Vec<LinkTransition>,
+    pub scan_time_micros: Option<u64>,
+    pub polling: PollingOptions,
+    pub last_poll: Option<PollSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AcknowledgeRequest {
+    /// Every alarm when not set.
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AcknowledgeResponse {
+    pub acknowledged: usize,
+}
+
+/// Versioned JSON API mirroring the HTMX endpoints.
+pub fn api_router() -> Router<Arc<Mutex<ModbusState>>> {
+    Router::new()
+        .route(
+            "/api/v1/connections",
+            get(list_connections)
+                .post(create_connection)
+                .delete(delete_connection),
+        )
+        .route("/api/v1/read", post(read))
+        .route("/api/v1/write", post(write))
+        .route(
+            "/api/v1/write_lock",
+            get(get_write_lock).put(set_write_lock),
+        )
+        .route("/api/v1/polling", get(get_polling).put(set_polling))
+        .route("/api/v1/status", get(status))
+        .route(
+            "/api/v1/statistics",
+            get(get_statistics).delete(reset_statistics),
+        )
+        .route("/api/v1/alarms", get(list_alarms))
+        .route("/api/v1/alarms/acknowledge", post(acknowledge_alarms))
+        .route("/api/v1/alarms/history", get(alarm_history))
+        .route(
+            "/api/v1/alarms/rules",
+            get(get_alarm_rules).put(set_alarm_rules),
+        )
+        .route(
+            "/api/v1/notifications",
+            get(get_notifications).put(set_notifications),
+        )
+        .route("/api/v1/notifications/log", get(notification_log))
+        .route("/api/v1/mqtt", get(get_mqtt).put(set_mqtt))
+        .route("/api/v1/audit", get(audit_log))
+        .route("/api/v1/history", get(history))
+        .route("/api/openapi.json", get(openapi))
+        // The HTMX forms the JSON API builds on, documented with it.
+        .route("/connect_modbus_tcp", post(connect_modbus_tcp))
+        .route("/write_modbus", post(write_modbus))
+}
+
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// MPTT holds a single connection, so the list has at most one entry.
+#[utoipa::path(
+    get,
+    path = "/api/v1/connections",
+    responses((status = 200, description = "Active connection, if any", body = [ConnectionInfo]))
+)]
+pub async fn list_connections(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+) -> Json<Vec<ConnectionInfo>> {
+    let mtx = mtx.lock().await;
+    let connections = mtx
+        .connection
+        .iter()
+        .map(|settings| ConnectionInfo {
+            connected: mtx.context.is_some(),
+            settings: settings.clone(),
+        })
+        .collect();
+    Json(connections)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/connections",
+    request_body = ConnectionSettings,
+    responses(
+        (status = 200, description = "Connected", body = ConnectionInfo),
+        (status = 502, description = "Could not connect", body = ApiError)
+    )
+)]
+pub async fn create_connection(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(settings): Json<ConnectionSettings>,
+) -> ApiResult<ConnectionInfo> {
+    let mut mtx = mtx.lock().await;
+    match connect(&mut mtx, settings.clone()).await {
+        Ok(()) => Ok(Json(ConnectionInfo {
+            connected: true,
+            settings,
+        })),
+        Err(e) => api_error(StatusCode::BAD_GATEWAY, e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/connections",
+    responses(
+        (status = 204, description = "Disconnected"),
+        (status = 404, description = "There was no connection")
+    )
+)]
+pub async fn delete_connection(State(mtx): State<Arc<Mutex<ModbusState>>>) -> StatusCode {
+    let mut mtx = mtx.lock().await;
+    if disconnect(&mut mtx).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/read",
+    request_body = ReadRequest,
+    responses(
+        (status = 200, description = "Values read", body = ReadResponse),
+        (status = 400, description = "Unsupported function code", body = ApiError),
+        (status = 409, description = "There is no connection", body = ApiError),
+        (status = 422, description = "Modbus exception", body = ApiError),
+        (status = 502, description = "Transport error", body = ApiError)
+    )
+)]
+pub async fn read(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(request): Json<ReadRequest>,
+) -> ApiResult<ReadResponse> {
+    let Some(function_code) = function_code_from(request.function) else {
+        return api_error(StatusCode::BAD_REQUEST, "Unsupported function code.");
+    };
+    let mut mtx = mtx.lock().await;
+    let Some(ctx) = mtx.context.as_mut() else {
+        return api_error(StatusCode::CONFLICT, "There is no connection.");
+    };
+    let float32 = request.data_type == DataType::F32;
+    match read_block(ctx, function_code, request.register, request.count, float32).await {
+        Ok(data) => Ok(Json(ReadResponse {
+            function: request.function,
+            register: request.register,
+            data,
+        })),
+        Err(ReadError::Exception(e)) => api_error(StatusCode::UNPROCESSABLE_ENTITY, e),
+        Err(ReadError::Transport(e)) => api_error(StatusCode::BAD_GATEWAY, e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/write",
+    request_body = WriteRequest,
+    responses(
+        (status = 200, description = "Value written", body = WriteResponse),
+        (status = 409, description = "There is no connection", body = ApiError),
+        (status = 422, description = "Rejected, failed or unverified write", body = ApiError),
+        (status = 423, description = "Writes are locked for this connection", body = ApiError)
+    )
+)]
+pub async fn write(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    info: Option<ConnectInfo<SocketAddr>>,
+    user: Option<Extension<CurrentUser>>,
+    Json(request): Json<WriteRequest>,
+) -> ApiResult<WriteResponse> {
+    let mut mtx = mtx.lock().await;
+    // Rejected writes still go through `write_value` so they are audited.
+    let status = if mtx.context.is_none() {
+        StatusCode::CONFLICT
+    } else if mtx.writes_locked() {
+        StatusCode::LOCKED
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    let origin = client_origin(info, user.as_deref());
+    match write_value(&mut mtx, &request, &origin).await {
+        Ok(message) => Ok(Json(WriteResponse { message })),
+        Err(e) => api_error(status, e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/write_lock",
+    responses((status = 200, description = "Whether writes are locked", body = WriteLock))
+)]
+pub async fn get_write_lock(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<WriteLock> {
+    Json(WriteLock {
+        read_only: mtx.lock().await.writes_locked(),
+    })
+}
+
+/// Kept with the connection settings, so it is saved with the project.
+#[utoipa::path(
+    put,
+    path = "/api/v1/write_lock",
+    request_body = WriteLock,
+    responses(
+        (status = 200, description = "Lock updated", body = WriteLock),
+        (status = 409, description = "There is no connection", body = ApiError)
+    )
+)]
+pub async fn set_write_lock(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(lock): Json<WriteLock>,
+) -> ApiResult<WriteLock> {
+    if !mtx.lock().await.lock_writes(lock.read_only) {
+        return api_error(StatusCode::CONFLICT, "There is no connection.");
+    }
+    Ok(Json(lock))
+}
+
+fn polling_options(options: &ProtocolOpts) -> PollingOptions {
+    PollingOptions {
+        function: options.function_code.value(),
+        register: options.start_register,
+        count: options.count,
+        data_type: if options.float32 {
+            DataType::F32
+        } else {
+            DataType::Int16
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/polling",
+    responses((status = 200, description = "Block read by the poller", body = PollingOptions))
+)]
+pub async fn get_polling(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<PollingOptions> {
+    Json(polling_options(&mtx.lock().await.protocol_options))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/polling",
+    request_body = PollingOptions,
+    responses(
+        (status = 200, description = "Polling options updated", body = PollingOptions),
+        (status = 400, description = "Unsupported function code", body = ApiError)
+    )
+)]
+pub async fn set_polling(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(options): Json<PollingOptions>,
+) -> ApiResult<PollingOptions> {
+    let Some(function_code) = function_code_from(options.function) else {
+        return api_error(StatusCode::BAD_REQUEST, "Unsupported function code.");
+    };
+    let mut mtx = mtx.lock().await;
+    mtx.protocol_options = ProtocolOpts {
+        function_code,
+        start_register: options.register,
+        count: options.count,
+        float32: options.data_type == DataType::F32,
+    };
+    Ok(Json(polling_options(&mtx.protocol_options)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/status",
+    responses((status = 200, description = "Connection, scan time and last values", body = StatusResponse))
+)]
+pub async fn status(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<StatusResponse> {
+    let mtx = mtx.lock().await;
+    Json(StatusResponse {
+        connected: mtx.context.is_some(),
+        connection: mtx.connection.clone(),
+        tls: mtx.tls_peer.clone(),
+        link: mtx.link.state.clone(),
+        read_only: mtx.writes_locked(),
+        link_log: mtx.link.log.iter().cloned().collect(),
+        scan_time_micros: mtx.poll_time.map(|time| time.as_micros() as u64),
+        polling: polling_options(&mtx.protocol_options),
+        last_poll: mtx.last_poll.clone(),
+    })
+}
+
+/// Request counters and latency per connection and unit id since the last reset.
+#[utoipa::path(
+    get,
+    path = "/api/v1/statistics",
+    responses((status = 200, description = "Statistics per connection", body = [ConnectionStats]))
+)]
+pub async fn get_statistics(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+) -> Json<Vec<ConnectionStats>> {
+    Json(statistics_snapshot(&*mtx.lock().await))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/statistics",
+    responses((status = 204, description = "Statistics cleared"))
+)]
+pub async fn reset_statistics(State(mtx): State<Arc<Mutex<ModbusState>>>) -> StatusCode {
+    let mtx = mtx.lock().await;
+    if let Ok(mut stats) = mtx.stats.lock() {
+        stats.reset();
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// Raised alarms, including cleared ones that still wait for an acknowledgement.
+#[utoipa::path(
+    get,
+    path = "/api/v1/alarms",
+    responses((status = 200, description = "Active alarms", body = [ActiveAlarm]))
+)]
+pub async fn list_alarms(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<Vec<ActiveAlarm>> {
+    Json(mtx.lock().await.alarms.active.clone())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/alarms/acknowledge",
+    request_body = AcknowledgeRequest,
+    responses((status = 200, description = "Number of alarms acknowledged", body = AcknowledgeResponse))
+)]
+pub async fn acknowledge_alarms(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(request): Json<AcknowledgeRequest>,
+) -> Json<AcknowledgeResponse> {
+    let mut mtx = mtx.lock().await;
+    let acknowledged = mtx
+        .alarms
+        .acknowledge(request.name.as_deref(), now_millis());
+    Json(AcknowledgeResponse { acknowledged })
+}
+
+/// Latest raised, cleared and acknowledged events, oldest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/alarms/history",
+    responses((status = 200, description = "Alarm events", body = [AlarmEvent]))
+)]
+pub async fn alarm_history(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<Vec<AlarmEvent>> {
+    Json(mtx.lock().await.alarms.history.iter().cloned().collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/alarms/rules",
+    responses((status = 200, description = "Alarm conditions of the project", body = [AlarmRule]))
+)]
+pub async fn get_alarm_rules(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<Vec<AlarmRule>> {
+    Json(mtx.lock().await.project.alarms.clone())
+}
+
+/// Replaces the alarm conditions, they are saved with the project.
+#[utoipa::path(
+    put,
+    path = "/api/v1/alarms/rules",
+    request_body = [AlarmRule],
+    responses(
+        (status = 200, description = "Conditions replaced", body = [AlarmRule]),
+        (status = 400, description = "Missing or duplicate name", body = ApiError)
+    )
+)]
+pub async fn set_alarm_rules(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(rules): Json<Vec<AlarmRule>>,
+) -> ApiResult<Vec<AlarmRule>> {
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.name.is_empty() || rule.tag.is_empty() {
+            return api_error(StatusCode::BAD_REQUEST, "An alarm needs a name and a tag.");
+        }
+        if rules[..i].iter().any(|other| other.name == rule.name) {
+            return api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Duplicate alarm name: {}", rule.name),
+            );
+        }
+    }
+    let mut mtx = mtx.lock().await;
+    let mtx = &mut *mtx;
+    mtx.project.alarms = rules;
+    mtx.alarms.retain_rules(&mtx.project.alarms);
+    Ok(Json(mtx.project.alarms.clone()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/notifications",
+    responses((status = 200, description = "Notification sinks of the project", body = [NotificationSink]))
+)]
+pub async fn get_notifications(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+) -> Json<Vec<NotificationSink>> {
+    Json(mtx.lock().await.project.notifications.clone())
+}
+
+/// Replaces the notification sinks, they are saved with the project.
+#[utoipa::path(
+    put,
+    path = "/api/v1/notifications",
+    request_body = [NotificationSink],
+    responses(
+        (status = 200, description = "Sinks replaced", body = [NotificationSink]),
+        (status = 400, description = "Missing or duplicate name", body = ApiError)
+    )
+)]
+pub async fn set_notifications(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(sinks): Json<Vec<NotificationSink>>,
+) -> ApiResult<Vec<NotificationSink>> {
+    for (i, sink) in sinks.iter().enumerate() {
+        if sink.name.is_empty() {
+            return api_error(StatusCode::BAD_REQUEST, "A notification needs a name.");
+        }
+        if sinks[..i].iter().any(|other| other.name == sink.name) {
+            return api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Duplicate notification name: {}", sink.name),
+            );
+        }
+    }
+    let mut mtx = mtx.lock().await;
+    mtx.project.notifications = sinks;
+    Ok(Json(mtx.project.notifications.clone()))
+}
+
+/// Latest deliveries, oldest first, failures keep their last error.
+#[utoipa::path(
+    get,
+    path = "/api/v1/notifications/log",
+    responses((status = 200, description = "Delivery log", body = [Delivery]))
+)]
+pub async fn notification_log(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<Vec<Delivery>> {
+    let log = mtx.lock().await.deliveries.clone();
+    let deliveries = log
+        .lock()
+        .map(|log| log.iter().cloned().collect())
+        .unwrap_or_default();
+    Json(deliveries)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/mqtt",
+    responses((status = 200, description = "MQTT bridge settings of the project", body = MqttSettings))
+)]
+pub async fn get_mqtt(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Json<MqttSettings> {
+    Json(mtx.lock().await.project.mqtt.redacted())
+}
+
+/// The bridge restarts with new settings within a second, `enabled: false` stops it.
+#[utoipa::path(
+    put,
+    path = "/api/v1/mqtt",
+    request_body = MqttSettings,
+    responses(
+        (status = 200, description = "Settings applied", body = MqttSettings),
+        (status = 400, description = "Invalid settings", body = ApiError)
+    )
+)]
+pub async fn set_mqtt(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Json(mut settings): Json<MqttSettings>,
+) -> ApiResult<MqttSettings> {
+    if let Err(e) = settings.validate() {
+        return api_error(StatusCode::BAD_REQUEST, e);
+    }
+    let mut mtx = mtx.lock().await;
+    settings.keep_password(&mtx.project.mqtt);
+    mtx.project.mqtt = settings;
+    Ok(Json(mtx.project.mqtt.redacted()))
+}
+
+/// Every write attempt, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit",
+    responses(
+        (status = 200, description = "Audit log entries", body = [AuditEntry]),
+        (status = 503, description = "The audit log is not available", body = ApiError)
+    )
+)]
+pub async fn audit_log(State(mtx): State<Arc<Mutex<ModbusState>>>) -> ApiResult<Vec<AuditEntry>> {
+    let mtx = mtx.lock().await;
+    let Some(audit) = mtx.audit.as_ref() else {
+        return api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The audit log is not available.",
+        );
+    };
+    match audit.entries(None) {
+        Ok(entries) => Ok(Json(entries)),
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Raw samples of a tag, or min/max/avg buckets when `bucket` is given.
+#[utoipa::path(
+    get,
+    path = "/api/v1/history",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Recorded values of the range", body = HistoryResponse),
+        (status = 400, description = "Bad range", body = ApiError),
+        (status = 503, description = "The historian is not available", body = ApiError)
+    )
+)]
+pub async fn history(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Query(query): Query<HistoryQuery>,
+) -> ApiResult<HistoryResponse> {
+    let to = query.to.unwrap_or_else(now_millis);
+    let from = query.from.unwrap_or(to - DEFAULT_QUERY_RANGE);
+    if from > to {
+        return api_error(StatusCode::BAD_REQUEST, "`from` must not be after `to`.");
+    }
+    if query.bucket.is_some_and(|bucket| bucket <= 0) {
+        return api_error(
+            StatusCode::BAD_REQUEST,
+            "`bucket` must be a positive number of milliseconds.",
+        );
+    }
+    let Some(historian) = mtx.lock().await.historian.clone() else {
+        return api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The historian is not available.",
+        );
+    };
+
+    let tag = query.tag.clone();
+    let result = historian::query(&historian, move |historian| match query.bucket {
+        Some(bucket) => historian
+            .buckets(&tag, from, to, bucket)
+            .map(|buckets| (None, Some(buckets))),
+        None => historian
+            .samples(&tag, from, to)
+            .map(|samples| (Some(samples), None)),
+    })
+    .await;
+    match result {
+        Ok((samples, buckets)) => Ok(Json(HistoryResponse {
+            tag: query.tag,
+            from,
+            to,
+            samples,
+            buckets,
+        })),
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus;
+    use axum::body::Body;
+    use axum::extract::Form;
+    use axum::http::{Method, Request};
+    use maud::Markup;
+    use std::future::Future;
+    use tower::ServiceExt;
+    use utoipa::openapi::PathItemType;
+
+    const METHODS: [Method; 5] = [
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::DELETE,
+        Method::PATCH,
+    ];
+
+    fn test_router() -> Router {
+        let state = ModbusState::new(ProtocolOpts::default(), None);
+        // Unknown paths answer 418 so they can't be mistaken for a handler's 404.
+        api_router()
+            .fallback(|| async { StatusCode::IM_A_TEAPOT })
+            .with_state(Arc::new(Mutex::new(state)))
+    }
+
+    fn method(item_type: &PathItemType) -> Method {
+        match item_type {
+            PathItemType::Get => Method::GET,
+            PathItemType::Post => Method::POST,
+            PathItemType::Put => Method::PUT,
+            PathItemType::Delete => Method::DELETE,
+            PathItemType::Patch => Method::PATCH,
+            _ => panic!("unexpected operation type"),
+        }
+    }
+
+    async fn call(method: Method, path: &str) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header("content-type", "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        test_router().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn spec_matches_handlers() {
+        let spec = ApiDoc::openapi();
+        assert!(!spec.paths.paths.is_empty());
+        for (path, item) in &spec.paths.paths {
+            let documented: Vec<Method> = item.operations.keys().map(method).collect();
+            for method in METHODS {
+                let status = call(method.clone(), path).await;
+                assert_ne!(status, StatusCode::IM_A_TEAPOT, "{} is not routed", path);
+                if documented.contains(&method) {
+                    assert_ne!(
+                        status,
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{} {} is documented but not routed",
+                        method,
+                        path
+                    );
+                } else {
+                    assert_eq!(
+                        status,
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{} {} is routed but not documented",
+                        method,
+                        path
+                    );
+                }
+            }
+        }
+    }
+
+    /// `Name`, or `[Name]` for a `Vec`, as the spec refers to a body type.
+    fn schema_name<T>() -> String {
+        let last = |name: &str| name.rsplit("::").next().unwrap().to_string();
+        let name = std::any::type_name::<T>();
+        match name.strip_prefix("alloc::vec::Vec<") {
+            Some(item) => format!("[{}]", last(item.trim_end_matches('>'))),
+            None => last(name),
+        }
+    }
+
+    /// An extractor, `Some` content type and body type for the one taking the body.
+    trait Arg {
+        fn body() -> Option<(String, String)> {
+            None
+        }
+    }
+    impl<T> Arg for Json<T> {
+        fn body() -> Option<(String, String)> {
+            Some(("application/json".to_string(), schema_name::<T>()))
+        }
+    }
+    impl<T> Arg for Form<T> {
+        fn body() -> Option<(String, String)> {
+            Some((
+                "application/x-www-form-urlencoded".to_string(),
+                schema_name::<T>(),
+            ))
+        }
+    }
+    impl<T> Arg for State<T> {}
+    impl<T> Arg for Query<T> {}
+    // `ConnectInfo` and `Extension` are only taken as options.
+    impl<T> Arg for Option<T> {}
+
+    /// What a handler answers, `Some` body type for JSON.
+    trait Reply {
+        fn body() -> Option<String>;
+    }
+    impl<T> Reply for Json<T> {
+        fn body() -> Option<String> {
+            Some(schema_name::<T>())
+        }
+    }
+    impl<T, E> Reply for Result<Json<T>, E> {
+        fn body() -> Option<String> {
+            Some(schema_name::<T>())
+        }
+    }
+    impl Reply for StatusCode {
+        fn body() -> Option<String> {
+            None
+        }
+    }
+    impl Reply for Markup {
+        fn body() -> Option<String> {
+            None
+        }
+    }
+
+    /// The body types of a handler, read from its signature.
+    trait Signature<Args> {
+        fn request() -> Option<(String, String)>;
+        fn response() -> Option<String>;
+    }
+    macro_rules! signature {
+        ($($arg:ident),+) => {
+            impl<H, F, $($arg: Arg),+> Signature<($($arg,)+)> for H
+            where
+                H: Fn($($arg),+) -> F,
+                F: Future,
+                F::Output: Reply,
+            {
+                fn request() -> Option<(String, String)> {
+                    None$(.or_else($arg::body))+
+                }
+                fn response() -> Option<String> {
+                    F::Output::body()
+                }
+            }
+        };
+    }
+    signature!(A);
+    signature!(A, B);
+    signature!(A, B, C);
+    signature!(A, B, C, D);
+
+    /// `Name` or `[Name]` for a schema referring to a component.
+    fn schema_ref(schema: &serde_json::Value) -> Option<String> {
+        let name = |schema: &serde_json::Value| {
+            schema["$ref"]
+                .as_str()
+                .and_then(|reference| reference.strip_prefix("#/components/schemas/"))
+                .map(str::to_string)
+        };
+        match schema["type"].as_str() {
+            Some("array") => name(&schema["items"]).map(|name| format!("[{}]", name)),
+            _ => name(schema),
+        }
+    }
+
+    /// Compares the bodies `P` documents with the ones the handler takes and answers.
+    fn check<P: utoipa::Path, Args, H: Signature<Args>>(_: H) -> (String, String) {
+        let path = P::path();
+        let item = serde_json::to_value(P::path_item(None)).unwrap();
+        let (method, operation) = item.as_object().unwrap().iter().next().unwrap();
+        let request = operation["requestBody"]["content"]
+            .as_object()
+            .and_then(|content| content.iter().next())
+            .map(|(content_type, body)| (content_type.clone(), schema_ref(&body["schema"])));
+        let expected = H::request().map(|(content_type, name)| (content_type, Some(name)));
+        assert_eq!(request, expected, "{} {} request", method, path);
+        let response =
+            schema_ref(&operation["responses"]["200"]["content"]["application/json"]["schema"]);
+        assert_eq!(response, H::response(), "{} {} response", method, path);
+        (method.clone(), path)
+    }
+
+    #[test]
+    fn spec_schemas_match_handlers() {
+        let handlers = [
+            check::<__path_list_connections, _, _>(list_connections),
+            check::<__path_create_connection, _, _>(create_connection),
+            check::<__path_delete_connection, _, _>(delete_connection),
+            check::<__path_read, _, _>(read),
+            check::<__path_write, _, _>(write),
+            check::<__path_get_write_lock, _, _>(get_write_lock),
+            check::<__path_set_write_lock, _, _>(set_write_lock),
+            check::<__path_get_polling, _, _>(get_polling),
+            check::<__path_set_polling, _, _>(set_polling),
+            check::<__path_status, _, _>(status),
+            check::<__path_get_statistics, _, _>(get_statistics),
+            check::<__path_reset_statistics, _, _>(reset_statistics),
+            check::<__path_list_alarms, _, _>(list_alarms),
+            check::<__path_acknowledge_alarms, _, _>(acknowledge_alarms),
+            check::<__path_alarm_history, _, _>(alarm_history),
+            check::<__path_get_alarm_rules, _, _>(get_alarm_rules),
+            check::<__path_set_alarm_rules, _, _>(set_alarm_rules),
+            check::<__path_get_notifications, _, _>(get_notifications),
+            check::<__path_set_notifications, _, _>(set_notifications),
+            check::<__path_notification_log, _, _>(notification_log),
+            check::<__path_get_mqtt, _, _>(get_mqtt),
+            check::<__path_set_mqtt, _, _>(set_mqtt),
+            check::<__path_audit_log, _, _>(audit_log),
+            check::<__path_history, _, _>(history),
+            check::<modbus::__path_connect_modbus_tcp, _, _>(connect_modbus_tcp),
+            check::<modbus::__path_write_modbus, _, _>(write_modbus),
+        ];
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut documented = 0;
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for method in item.as_object().unwrap().keys() {
+                documented += 1;
+                assert!(
+                    handlers.contains(&(method.clone(), path.clone())),
+                    "{} {} is not checked against its handler",
+                    method,
+                    path
+                );
+            }
+        }
+        assert_eq!(documented, handlers.len());
+
+        // Every component is used by a path, directly or through another component,
+        // except the webhook body, which MPTT sends rather than answers.
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        let mut used: Vec<String> = Vec::new();
+        let webhook = format!("#/components/schemas/{}\"", NotificationEvent::schema().0);
+        let mut pending = vec![spec["paths"].to_string(), webhook];
+        while let Some(json) = pending.pop() {
+            for name in json.split("#/components/schemas/").skip(1) {
+                let name = name.split('"').next().unwrap().to_string();
+                if !used.contains(&name) {
+                    pending.push(schemas[&name].to_string());
+                    used.push(name);
+                }
+            }
+        }
+        for name in schemas.keys() {
+            assert!(used.contains(name), "{} is not used by any path", name);
+        }
+    }
+
+    fn query(from: Option<i64>, to: Option<i64>, bucket: Option<i64>) -> Query<HistoryQuery> {
+        Query(HistoryQuery {
+            tag: "HR1".to_string(),
+            from,
+            to,
+            bucket,
+        })
+    }
+
+    #[tokio::test]
+    async fn answers_range_queries() {
+        let mut historian =
+            historian::Historian::open(":memory:", historian::RetentionPolicy::default()).unwrap();
+        for (timestamp, value) in [(1000, 1.0), (2000, 2.0), (3000, 3.0)] {
+            historian
+                .record(timestamp, &[("HR1".to_string(), value)])
+                .unwrap();
+        }
+        let state = ModbusState::new(ProtocolOpts::default(), Some(historian));
+        let mtx = Arc::new(Mutex::new(state));
+
+        let response = history(State(mtx.clone()), query(Some(1500), Some(3000), None))
+            .await
+            .ok()
+            .unwrap();
+        let samples = response.samples.as_ref().unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(response.buckets.is_none());
+
+        let response = history(State(mtx.clone()), query(Some(0), Some(4000), Some(2000)))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(response.buckets.as_ref().unwrap().len(), 2);
+        assert!(response.samples.is_none());
+
+        // Without a range the last hour is returned.
+        let response = history(State(mtx.clone()), query(None, None, None))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(response.from, response.to - DEFAULT_QUERY_RANGE);
+        assert!(response.samples.as_ref().unwrap().is_empty());
+
+        let error = history(State(mtx.clone()), query(Some(2000), Some(1000), None)).await;
+        assert_eq!(error.err().unwrap().0, StatusCode::BAD_REQUEST);
+        let error = history(State(mtx.clone()), query(None, None, Some(0))).await;
+        assert_eq!(error.err().unwrap().0, StatusCode::BAD_REQUEST);
+
+        mtx.lock().await.historian = None;
+        let error = history(State(mtx), query(None, None, None)).await;
+        assert_eq!(error.err().unwrap().0, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn spec_is_served() {
+        let request = Request::builder()
+            .uri("/api/openapi.json")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
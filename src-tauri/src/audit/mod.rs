@@ -0,0 +1,422 @@
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use maud::{html, Markup};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, MutexGuard, PoisonError};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::auth::CurrentUser;
+use crate::modbus::{modbus_status_bar, ModbusState, MARGIN, WINDOW_WIDTH};
+
+pub const AUDIT_DB: &str = "./audit.sqlite";
+const PAGE_ENTRIES: usize = 200;
+
+/// One write attempt, whatever its outcome.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: i64,
+    pub connection: String,
+    pub unit_id: Option<u8>,
+    pub function: u8,
+    pub register: u16,
+    /// Last polled value of the target, when the poll covered it.
+    pub old_value: Option<f64>,
+    pub new_value: f64,
+    pub success: bool,
+    /// Confirmation message or the exception.
+    pub result: String,
+    /// `user@ip` of the HTTP client, or `mqtt`/`cli` for writes that don't come over HTTP.
+    pub origin: String,
+}
+
+/// SQLite store of write attempts, triggers reject any update or delete.
+pub struct AuditLog {
+    conn: std::sync::Mutex<Connection>,
+}
+
+/// The transaction `AuditLog::begin` opened, rolled back when dropped without `commit`
+/// so a cancelled or panicking write doesn't keep the log locked.
+pub struct PendingEntry {
+    audit: Arc<AuditLog>,
+    open: bool,
+}
+
+impl PendingEntry {
+    /// Appends the entry and ends the transaction.
+    pub fn commit(mut self, entry: &AuditEntry) -> rusqlite::Result<()> {
+        self.open = false;
+        let conn = self.audit.conn();
+        let result = insert(&conn, entry).and_then(|_| conn.execute_batch("COMMIT"));
+        if result.is_err() {
+            let _ = conn.execute_batch("ROLLBACK");
+        }
+        result
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        if self.open {
+            let _ = self.audit.conn().execute_batch("ROLLBACK");
+        }
+    }
+}
+
+fn insert(conn: &Connection, entry: &AuditEntry) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO writes (timestamp, connection, unit_id, function, register,
+             old_value, new_value, success, result, origin)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    )?
+    .execute(params![
+        entry.timestamp,
+        entry.connection,
+        entry.unit_id,
+        entry.function,
+        entry.register,
+        entry.old_value,
+        entry.new_value,
+        entry.success,
+        entry.result,
+        entry.origin
+    ])?;
+    Ok(())
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS writes (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 timestamp INTEGER NOT NULL,
+                 connection TEXT NOT NULL,
+                 unit_id INTEGER,
+                 function INTEGER NOT NULL,
+                 register INTEGER NOT NULL,
+                 old_value REAL,
+                 new_value REAL NOT NULL,
+                 success INTEGER NOT NULL,
+                 result TEXT NOT NULL,
+                 origin TEXT NOT NULL
+             );
+             CREATE TRIGGER IF NOT EXISTS writes_no_update BEFORE UPDATE ON writes
+             BEGIN SELECT RAISE(ABORT, 'The audit log is append-only.'); END;
+             CREATE TRIGGER IF NOT EXISTS writes_no_delete BEFORE DELETE ON writes
+             BEGIN SELECT RAISE(ABORT, 'The audit log is append-only.'); END;",
+        )?;
+        Ok(AuditLog {
+            conn: std::sync::Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> rusqlite::Result<()> {
+        insert(&self.conn(), entry)
+    }
+
+    /// Takes the write lock before a write goes out, so its entry can't be refused for a busy log.
+    pub fn begin(self: &Arc<Self>) -> rusqlite::Result<PendingEntry> {
+        self.conn().execute_batch("BEGIN IMMEDIATE")?;
+        Ok(PendingEntry {
+            audit: self.clone(),
+            open: true,
+        })
+    }
+
+    /// Newest first, every entry when `limit` is `None`.
+    pub fn entries(&self, limit: Option<usize>) -> rusqlite::Result<Vec<AuditEntry>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT timestamp, connection, unit_id, function, register,
+                 old_value, new_value, success, result, origin
+             FROM writes ORDER BY id DESC LIMIT ?1",
+        )?;
+        let limit = limit.map(|limit| limit as i64).unwrap_or(-1);
+        let rows = stmt.query_map(params![limit], |row| {
+            Ok(AuditEntry {
+                timestamp: row.get(0)?,
+                connection: row.get(1)?,
+                unit_id: row.get(2)?,
+                function: row.get(3)?,
+                register: row.get(4)?,
+                old_value: row.get(5)?,
+                new_value: row.get(6)?,
+                success: row.get(7)?,
+                result: row.get(8)?,
+                origin: row.get(9)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+/// User and IP of the HTTP client, the IP is known when the server was started with connect info.
+pub fn client_origin(info: Option<ConnectInfo<SocketAddr>>, user: Option<&CurrentUser>) -> String {
+    let address = match info {
+        Some(ConnectInfo(address)) => address.ip().to_string(),
+        None => "unknown".to_string(),
+    };
+    match user {
+        Some(user) => format!("{}@{}", user.name, address),
+        None => address,
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn utc_datetime(timestamp: i64) -> String {
+    let seconds = timestamp.div_euclid(1000);
+    let (days, time) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    // Days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn value_cell(value: Option<f64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn unit_cell(unit_id: Option<u8>) -> String {
+    unit_id
+        .map(|unit_id| unit_id.to_string())
+        .unwrap_or_default()
+}
+
+pub fn audit_csv(entries: &[AuditEntry]) -> Result<String, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let header = [
+        "time (UTC)",
+        "timestamp",
+        "connection",
+        "unit_id",
+        "function",
+        "register",
+        "old_value",
+        "new_value",
+        "success",
+        "result",
+        "origin",
+    ];
+    writer.write_record(header).map_err(|e| e.to_string())?;
+    for entry in entries {
+        writer
+            .write_record([
+                utc_datetime(entry.timestamp),
+                entry.timestamp.to_string(),
+                entry.connection.clone(),
+                unit_cell(entry.unit_id),
+                entry.function.to_string(),
+                entry.register.to_string(),
+                value_cell(entry.old_value),
+                entry.new_value.to_string(),
+                entry.success.to_string(),
+                entry.result.clone(),
+                entry.origin.clone(),
+            ])
+            .map_err(|e| e.to_string())?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+/// The whole log as CSV, oldest first.
+pub async fn export_audit(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Response {
+    let mtx = mtx.lock().await;
+    let Some(audit) = mtx.audit.as_ref() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The audit log is not available.",
+        )
+            .into_response();
+    };
+    let result = audit
+        .entries(None)
+        .map_err(|e| e.to_string())
+        .and_then(|mut entries| {
+            entries.reverse();
+            audit_csv(&entries)
+        });
+    match result {
+        Ok(text) => (
+            [
+                (header::CONTENT_TYPE, "text/csv"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"audit.csv\"",
+                ),
+            ],
+            text,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+pub async fn audit_table(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    audit_fragment(&mtx)
+}
+
+fn audit_fragment(state: &ModbusState) -> Markup {
+    let entries = match state
+        .audit
+        .as_ref()
+        .map(|audit| audit.entries(Some(PAGE_ENTRIES)))
+    {
+        Some(Ok(entries)) => Ok(entries),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Err("The audit log is not available.".to_string()),
+    };
+    html! {
+        #audit_table {
+            @match entries {
+                Ok(entries) => {
+                    table class="interactive" {
+                        thead {
+                            tr {
+                                th { "Time (UTC)" }
+                                th { "Connection" }
+                                th { "Unit" }
+                                th { "FC" }
+                                th { "Address" }
+                                th { "Old" }
+                                th { "New" }
+                                th { "Result" }
+                                th { "Client" }
+                            }
+                        }
+                        tbody {
+                            @for entry in &entries {
+                                tr {
+                                    td { (utc_datetime(entry.timestamp)) }
+                                    td { (entry.connection) }
+                                    td { (unit_cell(entry.unit_id)) }
+                                    td { (entry.function) }
+                                    td { (entry.register) }
+                                    td { (value_cell(entry.old_value)) }
+                                    td { (entry.new_value) }
+                                    td style=(if entry.success { "" } else { "color: red" }) { (entry.result) }
+                                    td { (entry.origin) }
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => p { (e) }
+            }
+        }
+    }
+}
+
+pub fn audit_body(state: &ModbusState) -> Markup {
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH * 2)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Write Audit Log" }
+                    }
+                    div class="window-body" {
+                        p { (format!("Every write attempt, newest first. The page shows the last {}, the export has all of them.", PAGE_ENTRIES)) }
+                        div class="field-row" {
+                            button hx-get="/audit_table" hx-target="#audit_table" hx-swap="outerHTML" { "Refresh" }
+                            a href="/export_audit" download="audit.csv" { button type="button" { "Export CSV" } }
+                        }
+                        div class="sunken-panel" style=(format!("height: 400px; width: {}px", WINDOW_WIDTH * 2 - 20)) {
+                            (audit_fragment(state))
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: i64, success: bool) -> AuditEntry {
+        AuditEntry {
+            timestamp,
+            connection: "TCP 10.0.0.5:502".to_string(),
+            unit_id: Some(255),
+            function: 6,
+            register: 40,
+            old_value: None,
+            new_value: 1500.0,
+            success,
+            result: if success {
+                "Wrote: 1500 to H-Register: 40".to_string()
+            } else {
+                "IllegalDataValue".to_string()
+            },
+            origin: "ops@192.168.1.20".to_string(),
+        }
+    }
+
+    #[test]
+    fn appends_and_refuses_changes() {
+        let audit = AuditLog::open(":memory:").unwrap();
+        audit.append(&entry(1_000, true)).unwrap();
+        audit.append(&entry(2_000, false)).unwrap();
+        let entries = audit.entries(None).unwrap();
+        assert_eq!(entries, vec![entry(2_000, false), entry(1_000, true)]);
+        assert_eq!(audit.entries(Some(1)).unwrap().len(), 1);
+
+        assert!(audit.conn().execute("DELETE FROM writes", []).is_err());
+        assert!(audit
+            .conn()
+            .execute("UPDATE writes SET new_value = 0", [])
+            .is_err());
+        assert_eq!(audit.entries(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rolls_back_an_entry_that_never_came() {
+        let audit = Arc::new(AuditLog::open(":memory:").unwrap());
+        // As when the write's future is dropped mid-request.
+        drop(audit.begin().unwrap());
+        audit.begin().unwrap().commit(&entry(1_000, true)).unwrap();
+        assert_eq!(audit.entries(None).unwrap(), vec![entry(1_000, true)]);
+    }
+
+    #[test]
+    fn exports_csv_with_utc_times() {
+        assert_eq!(utc_datetime(1_700_000_000_000), "2023-11-14 22:13:20");
+        assert_eq!(utc_datetime(951_782_400_000), "2000-02-29 00:00:00");
+        let csv = audit_csv(&[entry(1_700_000_000_000, false)]).unwrap();
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("time (UTC),timestamp,"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "2023-11-14 22:13:20,1700000000000,TCP 10.0.0.5:502,255,6,40,,1500,false,IllegalDataValue,ops@192.168.1.20"
+        );
+    }
+}
@@ -0,0 +1,736 @@
+use axum::extract::{ConnectInfo, Form, Request, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::ValueEnum;
+use maud::{html, Markup};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api::ApiError;
+use crate::modbus::{MARGIN, WINDOW_WIDTH};
+
+pub const USERS_FILE: &str = "./users.toml";
+const SESSION_COOKIE: &str = "mptt_session";
+/// Readable by the page script, which echoes it in the `X-CSRF-Token` header.
+const CSRF_COOKIE: &str = "mptt_csrf";
+const CSRF_HEADER: &str = "x-csrf-token";
+const SESSION_IDLE: Duration = Duration::from_secs(8 * 60 * 60);
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const HASH_LEN: usize = 32;
+
+/// Each role can do everything the previous one can.
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Pages, values, history and the API reads.
+    Viewer,
+    /// Writes, connecting, polling options and acknowledging alarms.
+    Operator,
+    /// Project, tags, alarm rules, notifications, the gateway and the MQTT bridge.
+    Engineer,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Engineer => write!(f, "engineer"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// `pbkdf2-sha256$<iterations>$<salt>$<hash>`, salt and hash in hex.
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UserFile {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+/// The logged in user, added to the request by `require_login`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CurrentUser {
+    pub name: String,
+    pub role: Role,
+}
+
+struct Session {
+    user: CurrentUser,
+    csrf: String,
+    last_seen: Instant,
+}
+
+/// Users from the local user file and the open sessions.
+pub struct Auth {
+    path: PathBuf,
+    users: Mutex<Vec<User>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    rng: SystemRandom,
+    secure_cookies: bool,
+}
+
+impl Auth {
+    /// Loads the user file at `path`, a missing file means there are no users yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let users = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                toml::from_str::<UserFile>(&text)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?
+                    .users
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        Ok(Auth {
+            path,
+            users: Mutex::new(users),
+            sessions: Mutex::new(HashMap::new()),
+            rng: SystemRandom::new(),
+            secure_cookies: false,
+        })
+    }
+
+    /// Marks the session cookies `Secure`, for a server on HTTPS.
+    pub fn secure_cookies(mut self, secure: bool) -> Self {
+        self.secure_cookies = secure;
+        self
+    }
+
+    pub fn users(&self) -> Vec<User> {
+        self.users
+            .lock()
+            .map(|users| users.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn has_users(&self) -> bool {
+        self.users.lock().is_ok_and(|users| !users.is_empty())
+    }
+
+    /// Adds the user or replaces its role and password, then saves the user file.
+    pub fn set_user(&self, name: &str, role: Role, password: &str) -> Result<(), String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("The user name is empty.".to_string());
+        }
+        if password.is_empty() {
+            return Err("The password is empty.".to_string());
+        }
+        let user = User {
+            name: name.to_string(),
+            role,
+            password: self.hash_password(password)?,
+        };
+        let mut users = self.users.lock().map_err(|e| e.to_string())?;
+        match users.iter_mut().find(|u| u.name == user.name) {
+            Some(existing) => *existing = user,
+            None => users.push(user),
+        }
+        self.save(&users)
+    }
+
+    pub fn remove_user(&self, name: &str) -> Result<(), String> {
+        let mut users = self.users.lock().map_err(|e| e.to_string())?;
+        let count = users.len();
+        users.retain(|u| u.name != name);
+        if users.len() == count {
+            return Err(format!("There is no user {}.", name));
+        }
+        self.save(&users)?;
+        drop(users);
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.retain(|_, session| session.user.name != name);
+        }
+        Ok(())
+    }
+
+    fn save(&self, users: &[User]) -> Result<(), String> {
+        let file = UserFile {
+            users: users.to_vec(),
+        };
+        let text = toml::to_string_pretty(&file).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, text).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+
+    /// The user, if the name and password match.
+    pub fn check_password(&self, name: &str, password: &str) -> Option<CurrentUser> {
+        let users = self.users.lock().ok()?;
+        let user = users.iter().find(|u| u.name == name)?;
+        verify_password(password, &user.password).then(|| CurrentUser {
+            name: user.name.clone(),
+            role: user.role,
+        })
+    }
+
+    fn hash_password(&self, password: &str) -> Result<String, String> {
+        let salt = self.random_hex(16)?;
+        let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap_or(NonZeroU32::MIN);
+        let mut hash = [0u8; HASH_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            salt.as_bytes(),
+            password.as_bytes(),
+            &mut hash,
+        );
+        Ok(format!(
+            "pbkdf2-sha256${}${}${}",
+            iterations,
+            salt,
+            to_hex(&hash)
+        ))
+    }
+
+    fn random_hex(&self, len: usize) -> Result<String, String> {
+        let mut bytes = vec![0u8; len];
+        self.rng
+            .fill(&mut bytes)
+            .map_err(|_| "No random numbers available.".to_string())?;
+        Ok(to_hex(&bytes))
+    }
+
+    /// Opens a session, returns the session and CSRF tokens.
+    pub fn start_session(&self, user: CurrentUser) -> Result<(String, String), String> {
+        let token = self.random_hex(32)?;
+        let csrf = self.random_hex(32)?;
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        sessions.retain(|_, session| session.last_seen.elapsed() < SESSION_IDLE);
+        sessions.insert(
+            token.clone(),
+            Session {
+                user,
+                csrf: csrf.clone(),
+                last_seen: Instant::now(),
+            },
+        );
+        Ok((token, csrf))
+    }
+
+    pub fn end_session(&self, headers: &HeaderMap) {
+        if let (Some(token), Ok(mut sessions)) =
+            (cookie(headers, SESSION_COOKIE), self.sessions.lock())
+        {
+            sessions.remove(token);
+        }
+    }
+
+    /// The session user and its CSRF token, refreshing the idle timeout.
+    fn session(&self, headers: &HeaderMap) -> Option<(CurrentUser, String)> {
+        let token = cookie(headers, SESSION_COOKIE)?;
+        let mut sessions = self.sessions.lock().ok()?;
+        let session = sessions.get_mut(token)?;
+        if session.last_seen.elapsed() >= SESSION_IDLE {
+            sessions.remove(token);
+            return None;
+        }
+        session.last_seen = Instant::now();
+        Some((session.user.clone(), session.csrf.clone()))
+    }
+
+    /// HTTP Basic credentials, for scripts calling the API.
+    fn basic_user(&self, headers: &HeaderMap) -> Option<CurrentUser> {
+        let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+        let encoded = value.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        self.check_password(name, password)
+    }
+}
+
+fn verify_password(password: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Some(iterations), Some(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        from_hex(hash),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt.as_bytes(),
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much matched.
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Requests that change something, with a GET for a few older buttons.
+fn changes_state(method: &Method, path: &str) -> bool {
+    const CHANGING_GETS: [&str; 3] = ["/disconnect_modbus", "/stop_gateway", "/stop_mqtt"];
+    !matches!(*method, Method::GET | Method::HEAD) || CHANGING_GETS.contains(&path)
+}
+
+/// Role needed for a request, a change that isn't listed needs an engineer.
+pub fn required_role(method: &Method, path: &str) -> Role {
+    const VIEWER_ACTIONS: [&str; 2] = ["/api/v1/read", "/logout"];
+    const OPERATOR_ACTIONS: [&str; 12] = [
+        "/write_modbus",
+        "/connect_modbus_tcp",
+        "/connect_modbus_serial",
+        "/disconnect_modbus",
+        "/update_modbus",
+        "/acknowledge_alarm",
+        "/reset_statistics",
+        "/api/v1/write",
+        "/api/v1/connections",
+        "/api/v1/polling",
+        "/api/v1/statistics",
+        "/api/v1/alarms/acknowledge",
+    ];
+    if !changes_state(method, path) || VIEWER_ACTIONS.contains(&path) {
+        Role::Viewer
+    } else if OPERATOR_ACTIONS.contains(&path) {
+        Role::Operator
+    } else {
+        Role::Engineer
+    }
+}
+
+/// Only engineers lift a read-only lock, anyone may set one.
+pub fn may_unlock_writes(user: Option<&CurrentUser>) -> bool {
+    user.is_none_or(|user| user.role >= Role::Engineer)
+}
+
+fn is_public(path: &str) -> bool {
+    path == "/login" || path == "/setup" || path.starts_with("/assets/")
+}
+
+fn is_htmx(request: &Request) -> bool {
+    request.headers().contains_key("hx-request")
+}
+
+fn is_json(request: &Request) -> bool {
+    request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_lowercase().starts_with("application/json"))
+}
+
+/// Checks the session (or Basic credentials on the API), the CSRF token and the role.
+pub async fn require_login(
+    State(auth): State<Arc<Auth>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    if is_public(&path) {
+        return next.run(request).await;
+    }
+    let api = path.starts_with("/api/");
+    let (user, csrf) = match auth.session(request.headers()) {
+        Some((user, csrf)) => (user, Some(csrf)),
+        None => match auth.basic_user(request.headers()).filter(|_| api) {
+            Some(user) => (user, None),
+            None if api => {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Basic realm=\"MPTT\"")],
+                    Json(ApiError {
+                        error: "Log in or send Basic credentials.".to_string(),
+                    }),
+                )
+                    .into_response();
+            }
+            None if is_htmx(&request) => {
+                return (StatusCode::UNAUTHORIZED, [("hx-redirect", "/login")]).into_response();
+            }
+            None => {
+                return (StatusCode::SEE_OTHER, [(header::LOCATION, "/login")]).into_response();
+            }
+        },
+    };
+    let method = request.method().clone();
+    if let (Some(csrf), true) = (&csrf, changes_state(&method, &path)) {
+        let sent = request
+            .headers()
+            .get(CSRF_HEADER)
+            .map(|value| value.as_bytes())
+            .unwrap_or_default();
+        if !same_token(sent, csrf.as_bytes()) {
+            return (
+                StatusCode::FORBIDDEN,
+                "Missing or wrong CSRF token, reload the page.",
+            )
+                .into_response();
+        }
+    }
+    // Browsers cache Basic credentials after the challenge and resend them on their own,
+    // but another site's form can't post JSON, so API posts without a session must be JSON.
+    if csrf.is_none() && method == Method::POST && !is_json(&request) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(ApiError {
+                error: "Send the request as application/json.".to_string(),
+            }),
+        )
+            .into_response();
+    }
+    let role = required_role(&method, &path);
+    if user.role < role {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("{} is a {}, this needs an {}.", user.name, user.role, role),
+        )
+            .into_response();
+    }
+    request.extensions_mut().insert(user);
+    next.run(request).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginForm {
+    pub name: String,
+    pub password: String,
+}
+
+fn session_response(auth: &Auth, user: CurrentUser) -> Response {
+    let secure = if auth.secure_cookies { "; Secure" } else { "" };
+    match auth.start_session(user) {
+        Ok((token, csrf)) => (
+            [
+                (
+                    header::SET_COOKIE,
+                    format!(
+                        "{}={}; Path=/; HttpOnly; SameSite=Strict{}",
+                        SESSION_COOKIE, token, secure
+                    ),
+                ),
+                (
+                    header::SET_COOKIE,
+                    format!(
+                        "{}={}; Path=/; SameSite=Strict{}",
+                        CSRF_COOKIE, csrf, secure
+                    ),
+                ),
+                (
+                    header::HeaderName::from_static("hx-redirect"),
+                    "/".to_string(),
+                ),
+            ],
+            login_status("Logged in."),
+        )
+            .into_response(),
+        Err(e) => login_status(&e).into_response(),
+    }
+}
+
+pub async fn login(
+    Extension(auth): Extension<Arc<Auth>>,
+    Form(form_input): Form<LoginForm>,
+) -> Response {
+    match auth.check_password(form_input.name.trim(), &form_input.password) {
+        Some(user) => session_response(&auth, user),
+        None => login_status("Wrong user name or password.").into_response(),
+    }
+}
+
+/// Creates the first account, an engineer, from the computer MPTT runs on.
+pub async fn setup(
+    Extension(auth): Extension<Arc<Auth>>,
+    info: Option<ConnectInfo<SocketAddr>>,
+    Form(form_input): Form<LoginForm>,
+) -> Response {
+    if auth.has_users() {
+        return login_status("There already are users, log in instead.").into_response();
+    }
+    if !info.is_some_and(|ConnectInfo(address)| address.ip().is_loopback()) {
+        return login_status("The first account can only be created on this computer.")
+            .into_response();
+    }
+    let name = form_input.name.trim();
+    if let Err(e) = auth.set_user(name, Role::Engineer, &form_input.password) {
+        return login_status(&e).into_response();
+    }
+    let user = CurrentUser {
+        name: name.to_string(),
+        role: Role::Engineer,
+    };
+    session_response(&auth, user)
+}
+
+pub async fn logout(Extension(auth): Extension<Arc<Auth>>, headers: HeaderMap) -> Response {
+    auth.end_session(&headers);
+    (
+        [
+            (
+                header::SET_COOKIE,
+                format!("{}=; Path=/; Max-Age=0", SESSION_COOKIE),
+            ),
+            (
+                header::SET_COOKIE,
+                format!("{}=; Path=/; Max-Age=0", CSRF_COOKIE),
+            ),
+            (
+                header::HeaderName::from_static("hx-redirect"),
+                "/login".to_string(),
+            ),
+        ],
+        "",
+    )
+        .into_response()
+}
+
+fn login_status(message: &str) -> Markup {
+    html! {
+        #login_status {
+            p { (message) }
+        }
+    }
+}
+
+pub fn login_body(auth: &Auth) -> Markup {
+    let first_user = !auth.has_users();
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { @if first_user { "Create the First Account" } @else { "Log In" } }
+                    }
+                    div class="window-body" {
+                        @if first_user {
+                            p { "There are no users yet. Create an engineer account from this computer, or add users with `mptt user add`." }
+                        }
+                        form hx-post=(if first_user { "/setup" } else { "/login" }) hx-target="#login_status" hx-swap="outerHTML" {
+                            div class="field-row-stacked" style="width: 200px" {
+                                label for="name" { "User: " }
+                                input type="text" id="name" name="name" autocomplete="username" {}
+                                label for="password" { "Password: " }
+                                input type="password" id="password" name="password" autocomplete=(if first_user { "new-password" } else { "current-password" }) {}
+                            }
+                            div class="field-row" {
+                                button { @if first_user { "Create" } @else { "Log In" } }
+                            }
+                        }
+                        (login_status(""))
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn temp_auth(name: &str) -> Arc<Auth> {
+        let path = std::env::temp_dir().join(format!("mptt-{}-{}.toml", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Arc::new(Auth::load(path).unwrap())
+    }
+
+    #[test]
+    fn passwords_and_roles() {
+        let auth = temp_auth("passwords");
+        assert!(!auth.has_users());
+        auth.set_user("ops", Role::Operator, "s3cret").unwrap();
+        assert_eq!(
+            auth.check_password("ops", "s3cret").map(|user| user.role),
+            Some(Role::Operator)
+        );
+        assert_eq!(auth.check_password("ops", "secret"), None);
+        // The file keeps the hash only and loads back.
+        let reloaded = Auth::load(&auth.path).unwrap();
+        assert!(!std::fs::read_to_string(&auth.path)
+            .unwrap()
+            .contains("s3cret"));
+        assert!(reloaded.check_password("ops", "s3cret").is_some());
+        std::fs::remove_file(&auth.path).unwrap();
+
+        assert_eq!(required_role(&Method::GET, "/trend"), Role::Viewer);
+        assert_eq!(required_role(&Method::POST, "/api/v1/read"), Role::Viewer);
+        assert_eq!(
+            required_role(&Method::GET, "/disconnect_modbus"),
+            Role::Operator
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/write_modbus"),
+            Role::Operator
+        );
+        assert_eq!(
+            required_role(&Method::PUT, "/api/v1/write_lock"),
+            Role::Engineer
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/save_project"),
+            Role::Engineer
+        );
+        assert_eq!(required_role(&Method::GET, "/stop_mqtt"), Role::Engineer);
+    }
+
+    #[tokio::test]
+    async fn sessions_need_csrf_and_the_role() {
+        let auth = temp_auth("sessions");
+        auth.set_user("view", Role::Viewer, "pw").unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "page" }))
+            .route("/write_modbus", post(|| async { "written" }))
+            .route("/api/v1/write", post(|| async { "written" }))
+            .layer(from_fn_with_state(auth.clone(), require_login));
+        let call = |method: Method, path: &str, headers: Vec<(&str, String)>| {
+            let mut request = Request::builder().method(method).uri(path);
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            let app = app.clone();
+            let request = request.body(Body::empty()).unwrap();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(call(Method::GET, "/", vec![]).await, StatusCode::SEE_OTHER);
+        assert_eq!(
+            call(Method::POST, "/api/v1/write", vec![]).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let user = auth.check_password("view", "pw").unwrap();
+        let (token, csrf) = auth.start_session(user).unwrap();
+        let session = format!("{}={}", SESSION_COOKIE, token);
+        assert_eq!(
+            call(Method::GET, "/", vec![("cookie", session.clone())]).await,
+            StatusCode::OK
+        );
+        // A viewer with a valid token still can't write.
+        assert_eq!(
+            call(
+                Method::POST,
+                "/write_modbus",
+                vec![("cookie", session.clone()), (CSRF_HEADER, csrf.clone())]
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("view:pw"));
+        assert_eq!(
+            call(
+                Method::POST,
+                "/api/v1/write",
+                vec![
+                    ("authorization", basic),
+                    ("content-type", "application/json".to_string())
+                ]
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        auth.set_user("view", Role::Operator, "pw").unwrap();
+        let user = auth.check_password("view", "pw").unwrap();
+        let (token, csrf2) = auth.start_session(user).unwrap();
+        let session = format!("{}={}", SESSION_COOKIE, token);
+        assert_ne!(csrf, csrf2);
+        assert_eq!(
+            call(
+                Method::POST,
+                "/write_modbus",
+                vec![("cookie", session.clone())]
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call(
+                Method::POST,
+                "/write_modbus",
+                vec![("cookie", session), (CSRF_HEADER, csrf2)]
+            )
+            .await,
+            StatusCode::OK
+        );
+        // A form another site posts with the cached credentials isn't JSON.
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("view:pw"));
+        assert_eq!(
+            call(
+                Method::POST,
+                "/api/v1/write",
+                vec![
+                    ("authorization", basic.clone()),
+                    (
+                        "content-type",
+                        "application/x-www-form-urlencoded".to_string()
+                    )
+                ]
+            )
+            .await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            call(
+                Method::POST,
+                "/api/v1/write",
+                vec![
+                    ("authorization", basic),
+                    ("content-type", "application/json".to_string())
+                ]
+            )
+            .await,
+            StatusCode::OK
+        );
+        std::fs::remove_file(&auth.path).unwrap();
+    }
+}
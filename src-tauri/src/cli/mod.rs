@@ -0,0 +1,650 @@
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tokio_modbus::prelude::*;
+use tokio_modbus::FunctionCode;
+
+use crate::audit::{AuditLog, AUDIT_DB};
+use crate::auth::{Auth, Role, USERS_FILE};
+use crate::historian::now_millis;
+use crate::modbus::{
+    connect, function_code_from, read_block, write_value, ConnectionSettings, DataType,
+    ModbusState, PollData, PollSnapshot, ProtocolOpts, ReadError, TlsSettings, WriteRequest,
+};
+use crate::notify::{NotificationEvent, Notifier};
+use crate::server::{ServerSettings, CONFIG_FILE};
+
+/// Without a subcommand MPTT starts the tray application.
+#[derive(Parser)]
+#[command(name = "mptt", version, about = "Modbus polling and testing tool")]
+pub struct Cli {
+    /// Project file loaded when the web UI starts.
+    #[arg(long)]
+    pub project: Option<PathBuf>,
+    /// Address the web UI listens on, 127.0.0.1 unless set in mptt.toml.
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Port of the web UI, 3000 unless set in mptt.toml.
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Serve the web UI over HTTPS.
+    #[arg(long)]
+    pub https: bool,
+    /// PEM certificate chain for HTTPS instead of the self-signed one.
+    #[arg(long, requires = "https_key")]
+    pub https_cert: Option<String>,
+    #[arg(long, requires = "https_cert")]
+    pub https_key: Option<String>,
+    /// Days of polled values kept in the historian, 7 unless set in mptt.toml.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub history_days: Option<u64>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Read a block of registers or coils once.
+    Read {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        #[command(flatten)]
+        block: BlockArgs,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Write a single coil or register.
+    Write {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        /// 5 (coil), 6 (holding register) or 16 (two registers, with `--data-type f32`).
+        #[arg(long, default_value_t = 6)]
+        function: u8,
+        #[arg(long)]
+        register: u16,
+        #[arg(long, value_enum, default_value_t = DataType::Int16)]
+        data_type: DataType,
+        /// Read the value back and fail if it differs.
+        #[arg(long)]
+        verify: bool,
+        value: f64,
+    },
+    /// Read a block repeatedly until interrupted.
+    Poll {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        #[command(flatten)]
+        block: BlockArgs,
+        /// Seconds between two polls.
+        #[arg(long, default_value_t = 1.0)]
+        interval: f64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Probe a range of unit ids and report which ones answer.
+    Scan {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+        #[arg(long, default_value_t = 1)]
+        first: u8,
+        #[arg(long, default_value_t = 247)]
+        last: u8,
+        /// Holding register read from every unit id.
+        #[arg(long, default_value_t = 0)]
+        register: u16,
+        /// Milliseconds to wait for each unit id.
+        #[arg(long, default_value_t = 500)]
+        timeout: u64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Run the web UI only, without the tray.
+    Serve,
+    /// Manage the accounts of the web UI.
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserAction {
+    /// Add a user or change its role and password, the password is read from stdin.
+    Add {
+        name: String,
+        #[arg(long, value_enum, default_value_t = Role::Viewer)]
+        role: Role,
+    },
+    /// Remove a user and end its sessions.
+    Remove { name: String },
+    /// Print every user and its role.
+    List,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("transport").required(true).args(["tcp", "udp", "serial"])))]
+pub struct ConnectionArgs {
+    /// Modbus TCP slave address.
+    #[arg(long)]
+    pub tcp: Option<String>,
+    #[arg(long, default_value_t = 502)]
+    pub port: usize,
+    /// Send RTU frames over the TCP connection, for serial-to-Ethernet converters.
+    #[arg(long, requires = "tcp", conflicts_with_all = ["udp", "serial"])]
+    pub rtu_over_tcp: bool,
+    /// Use Modbus/TCP Security, needs --ca.
+    #[arg(long, requires_all = ["tcp", "ca"], conflicts_with_all = ["rtu_over_tcp", "udp", "serial"])]
+    pub tls: bool,
+    /// CA bundle (PEM) the slave's certificate has to chain to.
+    #[arg(long)]
+    pub ca: Option<String>,
+    /// Client certificate chain (PEM).
+    #[arg(long, requires = "key")]
+    pub cert: Option<String>,
+    /// Client private key (PEM).
+    #[arg(long, requires = "cert")]
+    pub key: Option<String>,
+    /// Name the slave's certificate is issued for, defaults to the address.
+    #[arg(long)]
+    pub server_name: Option<String>,
+    /// Accept a certificate issued for another name.
+    #[arg(long)]
+    pub skip_server_name: bool,
+    /// Modbus UDP slave address, uses --port as well.
+    #[arg(long)]
+    pub udp: Option<String>,
+    /// Serial port for Modbus RTU, e.g. COM4 or /dev/ttyUSB0.
+    #[arg(long)]
+    pub serial: Option<String>,
+    #[arg(long, default_value_t = 9600)]
+    pub baudrate: u32,
+    /// Use Modbus ASCII framing on the serial port.
+    #[arg(long, requires = "serial", conflicts_with_all = ["tcp", "udp"])]
+    pub ascii: bool,
+    /// Slave (unit) id, defaults to 1 on serial, ASCII and RTU over TCP.
+    #[arg(long)]
+    pub slave: Option<u8>,
+}
+
+#[derive(Args)]
+pub struct BlockArgs {
+    /// 1 (coils), 3 (holding registers) or 4 (input registers).
+    #[arg(long, default_value_t = 3)]
+    pub function: u8,
+    #[arg(long, default_value_t = 1)]
+    pub register: u16,
+    #[arg(long, default_value_t = 5)]
+    pub count: u16,
+    #[arg(long, value_enum, default_value_t = DataType::Int16)]
+    pub data_type: DataType,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Serialize)]
+struct TimedSnapshot<'a> {
+    timestamp: i64,
+    #[serde(flatten)]
+    snapshot: &'a PollSnapshot,
+}
+
+#[derive(Serialize)]
+struct ScanResult {
+    unit: u8,
+    status: &'static str,
+    detail: String,
+}
+
+impl ConnectionArgs {
+    fn settings(&self) -> ConnectionSettings {
+        match (&self.tcp, &self.udp, &self.serial) {
+            (Some(address), _, _) if self.rtu_over_tcp => ConnectionSettings::RtuOverTcp {
+                address: address.clone(),
+                port: self.port,
+                slave: self.slave.unwrap_or(1),
+                read_only: false,
+            },
+            (Some(address), _, _) if self.tls => ConnectionSettings::Tls {
+                address: address.clone(),
+                port: self.port,
+                tls: TlsSettings {
+                    ca_file: self.ca.clone().unwrap_or_default(),
+                    cert_file: self.cert.clone().unwrap_or_default(),
+                    key_file: self.key.clone().unwrap_or_default(),
+                    server_name: self.server_name.clone().unwrap_or_default(),
+                    skip_server_name: self.skip_server_name,
+                },
+                read_only: false,
+            },
+            (Some(address), _, _) => ConnectionSettings::Tcp {
+                address: address.clone(),
+                port: self.port,
+                read_only: false,
+            },
+            (None, Some(address), _) => ConnectionSettings::Udp {
+                address: address.clone(),
+                port: self.port,
+                read_only: false,
+            },
+            (None, None, Some(com)) if self.ascii => ConnectionSettings::SerialAscii {
+                com: com.clone(),
+                baudrate: self.baudrate,
+                slave: self.slave.unwrap_or(1),
+                read_only: false,
+            },
+            (None, None, Some(com)) => ConnectionSettings::Serial {
+                com: com.clone(),
+                baudrate: self.baudrate,
+                slave: self.slave.unwrap_or(1),
+                read_only: false,
+            },
+            // clap requires one of the three.
+            (None, None, None) => unreachable!(),
+        }
+    }
+
+    async fn open(&self) -> Result<ModbusState, String> {
+        let mut state = ModbusState::new(ProtocolOpts::default(), None);
+        connect(&mut state, self.settings()).await?;
+        if let (Some(slave), Some(ctx)) = (self.slave, state.context.as_mut()) {
+            ctx.set_slave(Slave(slave));
+        }
+        Ok(state)
+    }
+}
+
+impl Cli {
+    /// `mptt.toml` with the command line on top.
+    pub fn server_settings(&self) -> Result<ServerSettings, String> {
+        let mut settings = ServerSettings::load(CONFIG_FILE)?;
+        self.override_settings(&mut settings);
+        Ok(settings)
+    }
+
+    fn override_settings(&self, settings: &mut ServerSettings) {
+        if let Some(bind) = self.bind {
+            settings.bind = bind;
+        }
+        if let Some(port) = self.port {
+            settings.port = port;
+        }
+        if let Some(days) = self.history_days {
+            settings.history_days = days;
+        }
+        settings.https |= self.https || self.https_cert.is_some();
+        if let (Some(cert), Some(key)) = (&self.https_cert, &self.https_key) {
+            settings.cert_file = cert.clone();
+            settings.key_file = key.clone();
+        }
+    }
+}
+
+/// Runs a headless subcommand and returns the process exit code.
+pub async fn run(command: Command) -> i32 {
+    let result = match command {
+        Command::Read {
+            connection,
+            block,
+            output,
+        } => read(&connection, &block, output).await,
+        Command::Write {
+            connection,
+            function,
+            register,
+            data_type,
+            verify,
+            value,
+        } => {
+            let request = WriteRequest {
+                function,
+                register,
+                value,
+                data_type,
+                verify,
+            };
+            write(&connection, &request).await
+        }
+        Command::Poll {
+            connection,
+            block,
+            interval,
+            output,
+        } => poll(&connection, &block, interval, output).await,
+        Command::Scan {
+            connection,
+            first,
+            last,
+            register,
+            timeout,
+            output,
+        } => scan(&connection, first, last, register, timeout, output).await,
+        Command::Serve => Err("`serve` is handled by the binary.".to_string()),
+        Command::User { action } => user(action),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+async fn read_snapshot(state: &mut ModbusState, block: &BlockArgs) -> Result<PollSnapshot, String> {
+    let Some(function_code) = function_code_from(block.function) else {
+        return Err("Unsupported function code.".to_string());
+    };
+    let Some(ctx) = state.context.as_mut() else {
+        return Err("There is no connection.".to_string());
+    };
+    let float32 = block.data_type == DataType::F32;
+    let data = read_block(ctx, function_code, block.register, block.count, float32)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(PollSnapshot {
+        function: block.function,
+        start_register: block.register,
+        data,
+    })
+}
+
+async fn read(
+    connection: &ConnectionArgs,
+    block: &BlockArgs,
+    output: OutputFormat,
+) -> Result<(), String> {
+    let mut state = connection.open().await?;
+    let snapshot = read_snapshot(&mut state, block).await?;
+    print_snapshot(&snapshot, output);
+    Ok(())
+}
+
+async fn write(connection: &ConnectionArgs, request: &WriteRequest) -> Result<(), String> {
+    let audit = AuditLog::open(AUDIT_DB)
+        .map_err(|e| format!("Writes are refused, could not open the audit log: {}", e))?;
+    let mut state = connection.open().await?;
+    state.audit = Some(Arc::new(audit));
+    let (events, mut unaudited) = mpsc::unbounded_channel();
+    state.notifier = Notifier::new(events);
+    let result = write_value(&mut state, request, "cli").await;
+    if let Ok(NotificationEvent::Audit { error, .. }) = unaudited.try_recv() {
+        eprintln!("The audit log could not record this write: {}", error);
+    }
+    println!("{}", result?);
+    Ok(())
+}
+
+fn user(action: UserAction) -> Result<(), String> {
+    let auth = Auth::load(USERS_FILE)?;
+    match action {
+        UserAction::Add { name, role } => {
+            eprint!("Password for {}: ", name);
+            let mut password = String::new();
+            std::io::stdin()
+                .read_line(&mut password)
+                .map_err(|e| e.to_string())?;
+            auth.set_user(&name, role, password.trim_end_matches(['\r', '\n']))?;
+            println!("Saved {} as {}.", name, role);
+        }
+        UserAction::Remove { name } => {
+            auth.remove_user(&name)?;
+            println!("Removed {}.", name);
+        }
+        UserAction::List => {
+            for user in auth.users() {
+                println!("{:<20}{}", user.name, user.role);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn poll(
+    connection: &ConnectionArgs,
+    block: &BlockArgs,
+    interval: f64,
+    output: OutputFormat,
+) -> Result<(), String> {
+    let Ok(period) = Duration::try_from_secs_f64(interval) else {
+        return Err("Invalid interval.".to_string());
+    };
+    if period.is_zero() {
+        return Err("The interval must be greater than zero.".to_string());
+    }
+    let mut state = connection.open().await?;
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match read_snapshot(&mut state, block).await {
+            Ok(snapshot) => print_snapshot(&snapshot, output),
+            // Keep polling through transient errors, like the UI does.
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+async fn scan(
+    connection: &ConnectionArgs,
+    first: u8,
+    last: u8,
+    register: u16,
+    timeout: u64,
+    output: OutputFormat,
+) -> Result<(), String> {
+    let mut state = connection.open().await?;
+    let Some(ctx) = state.context.as_mut() else {
+        return Err("There is no connection.".to_string());
+    };
+    let mut results = Vec::new();
+    for unit in first..=last {
+        ctx.set_slave(Slave(unit));
+        let read = read_block(ctx, FunctionCode::ReadHoldingRegisters, register, 1, false);
+        let (status, detail) =
+            match tokio::time::timeout(Duration::from_millis(timeout), read).await {
+                Ok(Ok(data)) => ("ok", format!("{:?}", data)),
+                // An exception still proves that a device answered.
+                Ok(Err(ReadError::Exception(e))) => ("exception", e),
+                Ok(Err(ReadError::Transport(e))) => ("error", e),
+                Err(_) => ("timeout", String::new()),
+            };
+        if output == OutputFormat::Table && status != "timeout" {
+            println!("{:<6}{:<11}{}", unit, status, detail);
+        }
+        results.push(ScanResult {
+            unit,
+            status,
+            detail,
+        });
+    }
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string(&results).unwrap_or_default()),
+        OutputFormat::Table => {
+            let found = results.iter().filter(|r| r.status != "timeout").count();
+            println!("{} of {} unit ids answered.", found, results.len());
+        }
+    }
+    Ok(())
+}
+
+fn print_snapshot(snapshot: &PollSnapshot, output: OutputFormat) {
+    match output {
+        OutputFormat::Json => {
+            let timed = TimedSnapshot {
+                timestamp: now_millis(),
+                snapshot,
+            };
+            println!("{}", serde_json::to_string(&timed).unwrap_or_default());
+        }
+        OutputFormat::Table => {
+            println!("{:<10}{:<14}Value (HEX)", "Register", "Value");
+            let start = snapshot.start_register as usize;
+            match &snapshot.data {
+                PollData::Registers(values) => {
+                    for (i, value) in values.iter().enumerate() {
+                        println!("{:<10}{:<14}{:#06X}", start + i, value, value);
+                    }
+                }
+                PollData::Floats(values) => {
+                    for (i, value) in values.iter().enumerate() {
+                        println!("{:<10}{:<14.2}", start + i * 2, value);
+                    }
+                }
+                PollData::Coils(values) => {
+                    for (i, value) in values.iter().enumerate() {
+                        println!("{:<10}{:<14}", start + i, *value as u8);
+                    }
+                }
+                PollData::Error(e) => println!("{}", e),
+            }
+            println!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn connection(args: &[&str]) -> Result<ConnectionSettings, clap::Error> {
+        let cli = Cli::try_parse_from(["mptt", "read"].iter().chain(args))?;
+        match cli.command {
+            Some(Command::Read { connection, .. }) => Ok(connection.settings()),
+            _ => panic!("not a read command"),
+        }
+    }
+
+    #[test]
+    fn parses_every_transport() {
+        Cli::command().debug_assert();
+        assert_eq!(
+            connection(&["--tcp", "10.0.0.5"]).unwrap(),
+            ConnectionSettings::Tcp {
+                address: "10.0.0.5".to_string(),
+                port: 502,
+                read_only: false
+            }
+        );
+        assert_eq!(
+            connection(&["--tcp", "10.0.0.5", "--port", "4001", "--rtu-over-tcp"]).unwrap(),
+            ConnectionSettings::RtuOverTcp {
+                address: "10.0.0.5".to_string(),
+                port: 4001,
+                slave: 1,
+                read_only: false
+            }
+        );
+        assert_eq!(
+            connection(&["--udp", "10.0.0.6", "--slave", "3"]).unwrap(),
+            ConnectionSettings::Udp {
+                address: "10.0.0.6".to_string(),
+                port: 502,
+                read_only: false
+            }
+        );
+        assert_eq!(
+            connection(&["--serial", "COM4", "--ascii", "--baudrate", "19200"]).unwrap(),
+            ConnectionSettings::SerialAscii {
+                com: "COM4".to_string(),
+                baudrate: 19200,
+                slave: 1,
+                read_only: false
+            }
+        );
+        assert_eq!(
+            connection(&["--serial", "/dev/ttyUSB0", "--slave", "7"]).unwrap(),
+            ConnectionSettings::Serial {
+                com: "/dev/ttyUSB0".to_string(),
+                baudrate: 9600,
+                slave: 7,
+                read_only: false
+            }
+        );
+        let tls = connection(&[
+            "--tcp",
+            "plc1.local",
+            "--port",
+            "802",
+            "--tls",
+            "--ca",
+            "ca.pem",
+        ]);
+        let ConnectionSettings::Tls {
+            address, port, tls, ..
+        } = tls.unwrap()
+        else {
+            panic!("not a TLS connection");
+        };
+        assert_eq!((address.as_str(), port), ("plc1.local", 802));
+        assert_eq!(tls.ca_file, "ca.pem");
+    }
+
+    #[test]
+    fn rejects_transport_mixups() {
+        // `ConnectionArgs::settings` relies on exactly one transport.
+        assert!(connection(&[]).is_err());
+        assert!(connection(&["--tcp", "10.0.0.5", "--serial", "COM4"]).is_err());
+        assert!(connection(&["--tcp", "10.0.0.5", "--udp", "10.0.0.6"]).is_err());
+        assert!(connection(&["--serial", "COM4", "--rtu-over-tcp"]).is_err());
+        assert!(connection(&["--tcp", "10.0.0.5", "--ascii"]).is_err());
+        assert!(connection(&["--tcp", "10.0.0.5", "--tls"]).is_err());
+        assert!(connection(&["--udp", "10.0.0.6", "--tls", "--ca", "ca.pem"]).is_err());
+        assert!(connection(&[
+            "--tcp",
+            "10.0.0.5",
+            "--tls",
+            "--ca",
+            "ca.pem",
+            "--rtu-over-tcp"
+        ])
+        .is_err());
+        assert!(connection(&["--tcp", "10.0.0.5", "--cert", "client.pem"]).is_err());
+    }
+
+    #[test]
+    fn serve_takes_the_bind_address_and_port() {
+        let cli =
+            Cli::try_parse_from(["mptt", "--bind", "0.0.0.0", "--port", "8080", "serve"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Serve)));
+        let mut settings = ServerSettings::default();
+        cli.override_settings(&mut settings);
+        assert_eq!(settings.bind, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(settings.port, 8080);
+        assert!(!settings.https);
+        assert_eq!(settings.history_days, 7);
+
+        let cli = Cli::try_parse_from(["mptt", "--history-days", "90", "serve"]).unwrap();
+        cli.override_settings(&mut settings);
+        assert_eq!(settings.history_days, 90);
+        assert!(Cli::try_parse_from(["mptt", "--history-days", "0", "serve"]).is_err());
+
+        assert!(Cli::try_parse_from(["mptt", "--https-cert", "ui.pem", "serve"]).is_err());
+        let cli = Cli::try_parse_from([
+            "mptt",
+            "--https-cert",
+            "ui.pem",
+            "--https-key",
+            "ui.key",
+            "serve",
+        ])
+        .unwrap();
+        let mut settings = ServerSettings::default();
+        cli.override_settings(&mut settings);
+        assert!(settings.https);
+        assert_eq!(
+            (settings.cert_file.as_str(), settings.key_file.as_str()),
+            ("ui.pem", "ui.key")
+        );
+        assert!(Cli::try_parse_from(["mptt", "--port", "70000", "serve"]).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use axum::extract::{Form, State};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_serial::{ClearBuffer, SerialPort, SerialStream};
+
+use crate::historian::now_millis;
+use crate::modbus::{modbus_status_bar, ModbusState, MARGIN, TABLE_WIDTH, WINDOW_WIDTH};
+
+const TRAFFIC_CAPACITY: usize = 200;
+const MAX_PDU: usize = 253;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const TARGET_FAILED_TO_RESPOND: u8 = 0x0B;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GatewaySettings {
+    /// Address the Modbus TCP server listens on, loopback by default since clients
+    /// write to the bus without logging in. `0.0.0.0:5020` opens it to the network.
+    pub listen: String,
+    pub com: String,
+    pub baudrate: u32,
+    /// Time the serial device has to answer a forwarded request.
+    pub timeout_ms: u64,
+}
+
+/// One forwarded request as shown in the traffic monitor.
+#[derive(Serialize, Clone, Debug)]
+pub struct TrafficEntry {
+    pub timestamp: i64,
+    pub client: String,
+    pub unit: u8,
+    pub request: String,
+    pub response: String,
+    pub outcome: String,
+    pub micros: u64,
+}
+
+type Traffic = Arc<std::sync::Mutex<VecDeque<TrafficEntry>>>;
+
+/// A running gateway, dropping it stops the server and its client connections.
+pub struct Gateway {
+    pub settings: GatewaySettings,
+    pub traffic: Traffic,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Default for GatewaySettings {
+    fn default() -> Self {
+        GatewaySettings {
+            listen: "127.0.0.1:5020".to_string(),
+            com: "COM4".to_string(),
+            baudrate: 9600,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// CRC-16/MODBUS, sent low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+/// Bytes left in an RTU response after address, function and the following byte.
+fn response_remaining(function: u8, third: u8) -> Option<usize> {
+    match function {
+        f if f & 0x80 != 0 => Some(2),
+        0x01..=0x04 | 0x17 => Some(third as usize + 2),
+        0x05 | 0x06 | 0x0F | 0x10 => Some(5),
+        0x16 => Some(7),
+        _ => None,
+    }
+}
+
+/// Sends `pdu` to `unit` as an RTU frame and returns the response PDU, `None` for broadcasts.
+pub async fn forward<S>(bus: &mut S, unit: u8, pdu: &[u8]) -> Result<Option<Vec<u8>>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+    bus.write_all(&frame).await.map_err(|e| e.to_string())?;
+    if unit == 0 {
+        return Ok(None);
+    }
+
+    let mut response = vec![0; 3];
+    bus.read_exact(&mut response)
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(remaining) = response_remaining(response[1], response[2]) else {
+        return Err("Unexpected function in the response.".to_string());
+    };
+    response.resize(3 + remaining, 0);
+    bus.read_exact(&mut response[3..])
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let (body, crc) = response.split_at(response.len() - 2);
+    if crc16(body).to_le_bytes() != crc {
+        return Err("CRC error.".to_string());
+    }
+    if body[0] != unit {
+        return Err(format!("Answer from unit {}.", body[0]));
+    }
+    Ok(Some(body[1..].to_vec()))
+}
+
+pub async fn start_gateway(settings: GatewaySettings) -> Result<Gateway, String> {
+    let listener = TcpListener::bind(&settings.listen)
+        .await
+        .map_err(|e| format!("Could not listen on {}: {}", settings.listen, e))?;
+    let builder = tokio_serial::new(&settings.com, settings.baudrate);
+    let Ok(port) = SerialStream::open(&builder) else {
+        return Err("Could not open port!".to_string());
+    };
+    let traffic: Traffic = Default::default();
+    let task = tokio::spawn(serve(
+        listener,
+        Arc::new(Mutex::new(port)),
+        traffic.clone(),
+        Duration::from_millis(settings.timeout_ms),
+    ));
+    Ok(Gateway {
+        settings,
+        traffic,
+        task,
+    })
+}
+
+async fn serve(
+    listener: TcpListener,
+    bus: Arc<Mutex<SerialStream>>,
+    traffic: Traffic,
+    timeout: Duration,
+) {
+    // Owned here so that stopping the gateway also drops its clients.
+    let mut clients = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    clients.spawn(handle_client(stream, peer, bus.clone(), traffic.clone(), timeout));
+                }
+                Err(e) => println!("Gateway accept failed: {:?}", e),
+            },
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+        }
+    }
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    bus: Arc<Mutex<SerialStream>>,
+    traffic: Traffic,
+    timeout: Duration,
+) {
+    loop {
+        let mut header = [0; 7];
+        if stream.read_exact(&mut header).await.is_err() {
+            return;
+        }
+        let protocol = u16::from_be_bytes([header[2], header[3]]);
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let unit = header[6];
+        // Not Modbus TCP, there is no way to resynchronise.
+        if protocol != 0 || !(2..=MAX_PDU + 1).contains(&length) {
+            return;
+        }
+        let mut pdu = vec![0; length - 1];
+        if stream.read_exact(&mut pdu).await.is_err() {
+            return;
+        }
+
+        let started = Instant::now();
+        let (response, outcome) = if response_remaining(pdu[0], 0).is_none() {
+            (
+                Some(exception(pdu[0], ILLEGAL_FUNCTION)),
+                "illegal function".to_string(),
+            )
+        } else {
+            let mut bus = bus.lock().await;
+            // Drop late answers to a request that already timed out.
+            let _ = bus.clear(ClearBuffer::Input);
+            match tokio::time::timeout(timeout, forward(&mut *bus, unit, &pdu)).await {
+                Ok(Ok(Some(response))) => {
+                    let outcome = match response[0] & 0x80 {
+                        0 => "ok".to_string(),
+                        _ => format!("exception {:#04X}", response.get(1).unwrap_or(&0)),
+                    };
+                    (Some(response), outcome)
+                }
+                Ok(Ok(None)) => (None, "broadcast".to_string()),
+                Ok(Err(e)) => (Some(exception(pdu[0], TARGET_FAILED_TO_RESPOND)), e),
+                Err(_) => (
+                    Some(exception(pdu[0], TARGET_FAILED_TO_RESPOND)),
+                    "timeout".to_string(),
+                ),
+            }
+        };
+
+        if let Ok(mut traffic) = traffic.lock() {
+            if traffic.len() == TRAFFIC_CAPACITY {
+                traffic.pop_front();
+            }
+            traffic.push_back(TrafficEntry {
+                timestamp: now_millis(),
+                client: peer.to_string(),
+                unit,
+                request: hex(&pdu),
+                response: response.as_deref().map(hex).unwrap_or_default(),
+                outcome,
+                micros: started.elapsed().as_micros() as u64,
+            });
+        }
+
+        if let Some(response) = response {
+            let mut frame = Vec::with_capacity(response.len() + 7);
+            frame.extend_from_slice(&header[..4]);
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(unit);
+            frame.extend_from_slice(&response);
+            if stream.write_all(&frame).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub async fn start_gateway_form(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<GatewaySettings>,
+) -> Markup {
+    let mut mtx = mtx.lock().await;
+    // The old server has to release its port first.
+    mtx.gateway = None;
+    let status = match start_gateway(form_input).await {
+        Ok(gateway) => {
+            let status = format!(
+                "Forwarding {} to {}",
+                gateway.settings.listen, gateway.settings.com
+            );
+            mtx.gateway = Some(gateway);
+            status
+        }
+        Err(e) => e,
+    };
+    gateway_status(&status)
+}
+
+pub async fn stop_gateway(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let mut mtx = mtx.lock().await;
+    match mtx.gateway.take() {
+        Some(_) => gateway_status("Stopped"),
+        None => gateway_status("Not running"),
+    }
+}
+
+fn gateway_status(status: &str) -> Markup {
+    html! {
+        #gateway_status {
+            p { (format!("GATEWAY: {}", status)) }
+        }
+    }
+}
+
+pub async fn gateway_traffic(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let traffic: Vec<TrafficEntry> = match mtx.lock().await.gateway.as_ref() {
+        Some(gateway) => gateway
+            .traffic
+            .lock()
+            .map(|traffic| traffic.iter().rev().cloned().collect())
+            .unwrap_or_default(),
+        None => Vec::new(),
+    };
+    html! {
+        #gateway_traffic {
+            div hx-get="/gateway_traffic" hx-trigger="load delay:1s" hx-target="#gateway_traffic" hx-swap="outerHTML" {}
+            table class="interactive" {
+                thead {
+                    tr {
+                        th { "Client" }
+                        th { "Unit" }
+                        th { "Request" }
+                        th { "Response" }
+                        th { "Result" }
+                        th { "Micros" }
+                    }
+                }
+                tbody {
+                    @for entry in &traffic {
+                        tr {
+                            td { (entry.client) }
+                            td { (entry.unit) }
+                            td { (entry.request) }
+                            td { (entry.response) }
+                            td { (entry.outcome) }
+                            td { (entry.micros) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn gateway_body(state: &ModbusState) -> Markup {
+    let settings = state
+        .gateway
+        .as_ref()
+        .map(|gateway| gateway.settings.clone())
+        .unwrap_or_default();
+    let status = if state.gateway.is_some() {
+        "Running"
+    } else {
+        "Stopped"
+    };
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "TCP to RTU Gateway" }
+                    }
+                    div class="window-body" {
+                        form hx-post="/start_gateway" hx-target="#gateway_status" hx-swap="outerHTML" {
+                            fieldset {
+                                legend { "Gateway Settings" }
+                                div class="field-row-stacked" style="width: 200px" {
+                                    label for="listen" { "Listen on: " }
+                                    input type="text" id="listen" name="listen" value=(settings.listen) {}
+                                    label for="com" { "COM Port: " }
+                                    input type="text" id="com" name="com" value=(settings.com) {}
+                                    label for="baudrate" { "Baudrate: " }
+                                    input type="number" id="baudrate" name="baudrate" value=(settings.baudrate) {}
+                                    label for="timeout_ms" { "Timeout: (ms)" }
+                                    input type="number" id="timeout_ms" name="timeout_ms" value=(settings.timeout_ms) {}
+                                    button hx-post="/start_gateway" { "Start" }
+                                    button hx-get="/stop_gateway" hx-target="#gateway_status" hx-swap="outerHTML" { "Stop" }
+                                }
+                            }
+                        }
+                        (gateway_status(status))
+                        div class="sunken-panel" style=(format!("height: 240px; width: {}px", TABLE_WIDTH)) {
+                            #gateway_traffic {
+                                div hx-get="/gateway_traffic" hx-trigger="load" hx-target="#gateway_traffic" hx-swap="outerHTML" {}
+                            }
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn rtu(body: &[u8]) -> Vec<u8> {
+        let mut frame = body.to_vec();
+        frame.extend_from_slice(&crc16(body).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn frames_rtu_responses() {
+        // Read 10 holding registers from unit 1.
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xCDC5);
+        assert_eq!(response_remaining(0x03, 4), Some(6));
+        assert_eq!(response_remaining(0x01, 1), Some(3));
+        assert_eq!(response_remaining(0x06, 0), Some(5));
+        assert_eq!(response_remaining(0x10, 0), Some(5));
+        assert_eq!(response_remaining(0x16, 0), Some(7));
+        assert_eq!(response_remaining(0x83, 2), Some(2));
+        assert_eq!(response_remaining(0x2B, 0), None);
+        assert_eq!(GatewaySettings::default().listen, "127.0.0.1:5020");
+    }
+
+    #[tokio::test]
+    async fn forwards_to_the_serial_unit() {
+        let (mut bus, mut device) = duplex(64);
+        let slave = tokio::spawn(async move {
+            let mut request = [0; 8];
+            device.read_exact(&mut request).await.unwrap();
+            device
+                .write_all(&rtu(&[0x01, 0x03, 0x02, 0x00, 0x2A]))
+                .await
+                .unwrap();
+            // Next answer comes from the wrong unit, then one with a broken CRC.
+            device.read_exact(&mut request).await.unwrap();
+            device.write_all(&rtu(&[0x02, 0x83, 0x02])).await.unwrap();
+            device.read_exact(&mut request).await.unwrap();
+            device
+                .write_all(&[0x01, 0x83, 0x02, 0x00, 0x00])
+                .await
+                .unwrap();
+            request
+        });
+
+        let pdu = [0x03, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(
+            forward(&mut bus, 1, &pdu).await,
+            Ok(Some(vec![0x03, 0x02, 0x00, 0x2A]))
+        );
+        assert_eq!(
+            forward(&mut bus, 1, &pdu).await,
+            Err("Answer from unit 2.".to_string())
+        );
+        assert_eq!(
+            forward(&mut bus, 1, &pdu).await,
+            Err("CRC error.".to_string())
+        );
+        assert_eq!(
+            slave.await.unwrap(),
+            rtu(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01])[..]
+        );
+
+        // Nobody answers a broadcast.
+        let (mut bus, mut device) = duplex(64);
+        assert_eq!(
+            forward(&mut bus, 0, &[0x06, 0x00, 0x01, 0x00, 0x07]).await,
+            Ok(None)
+        );
+        let mut request = [0; 8];
+        device.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[0], 0);
+    }
+}
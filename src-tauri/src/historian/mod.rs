@@ -0,0 +1,278 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use utoipa::{IntoParams, ToSchema};
+
+/// Keep one week of history by default.
+pub const DEFAULT_RETENTION_DAYS: u64 = 7;
+const PURGE_INTERVAL: u64 = 60; // Check the retention policy at most once a minute.
+/// Last hour when no range is given.
+pub const DEFAULT_QUERY_RANGE: i64 = 60 * 60 * 1000;
+
+/// How long polled values are kept in the historian.
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub max_age: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::days(DEFAULT_RETENTION_DAYS)
+    }
+}
+
+impl RetentionPolicy {
+    pub fn days(days: u64) -> Self {
+        RetentionPolicy {
+            max_age: Duration::from_secs(days * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Sample {
+    pub timestamp: i64,
+    pub value: f64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Bucket {
+    pub start: i64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Historian tag, e.g. `HR1` or a tag name.
+    pub tag: String,
+    /// Start of the range in milliseconds since the UNIX epoch.
+    pub from: Option<i64>,
+    /// End of the range in milliseconds since the UNIX epoch.
+    pub to: Option<i64>,
+    /// Downsampling bucket width in milliseconds.
+    pub bucket: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct HistoryResponse {
+    pub tag: String,
+    pub from: i64,
+    pub to: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples: Option<Vec<Sample>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buckets: Option<Vec<Bucket>>,
+}
+
+/// The poller records and the queries run on the blocking pool, so the state lock isn't held meanwhile.
+pub type SharedHistorian = Arc<Mutex<Historian>>;
+
+/// Embedded SQLite store for polled values.
+pub struct Historian {
+    conn: Connection,
+    retention: RetentionPolicy,
+    last_purge: Option<Instant>,
+}
+
+impl Historian {
+    pub fn open(path: impl AsRef<Path>, retention: RetentionPolicy) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS samples (
+                 tag TEXT NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 value REAL NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS samples_tag_timestamp ON samples (tag, timestamp);",
+        )?;
+        Ok(Historian {
+            conn,
+            retention,
+            last_purge: None,
+        })
+    }
+
+    /// Stores one poll worth of values under the same timestamp.
+    pub fn record(&mut self, timestamp: i64, values: &[(String, f64)]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO samples (tag, timestamp, value) VALUES (?1, ?2, ?3)",
+            )?;
+            for (tag, value) in values {
+                stmt.execute(params![tag, timestamp, value])?;
+            }
+        }
+        tx.commit()?;
+
+        let purge_due = match self.last_purge {
+            Some(last) => last.elapsed() >= Duration::from_secs(PURGE_INTERVAL),
+            None => true,
+        };
+        if purge_due {
+            self.purge(timestamp)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every sample older than the retention policy allows.
+    pub fn purge(&mut self, now: i64) -> rusqlite::Result<usize> {
+        self.last_purge = Some(Instant::now());
+        let cutoff = now - self.retention.max_age.as_millis() as i64;
+        self.conn
+            .execute("DELETE FROM samples WHERE timestamp < ?1", params![cutoff])
+    }
+
+    pub fn samples(&self, tag: &str, from: i64, to: i64) -> rusqlite::Result<Vec<Sample>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT timestamp, value FROM samples
+             WHERE tag = ?1 AND timestamp BETWEEN ?2 AND ?3
+             ORDER BY timestamp",
+        )?;
+        let rows = stmt.query_map(params![tag, from, to], |row| {
+            Ok(Sample {
+                timestamp: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Min/max/avg per `bucket` milliseconds, aligned to the UNIX epoch.
+    pub fn buckets(
+        &self,
+        tag: &str,
+        from: i64,
+        to: i64,
+        bucket: i64,
+    ) -> rusqlite::Result<Vec<Bucket>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT (timestamp / ?4) * ?4 AS start, MIN(value), MAX(value), AVG(value), COUNT(*)
+             FROM samples
+             WHERE tag = ?1 AND timestamp BETWEEN ?2 AND ?3
+             GROUP BY start
+             ORDER BY start",
+        )?;
+        let rows = stmt.query_map(params![tag, from, to, bucket], |row| {
+            Ok(Bucket {
+                start: row.get(0)?,
+                min: row.get(1)?,
+                max: row.get(2)?,
+                avg: row.get(3)?,
+                count: row.get(4)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+/// Runs `query` on the blocking pool, a long range takes a while.
+pub async fn query<T, F>(historian: &SharedHistorian, query: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&Historian) -> rusqlite::Result<T> + Send + 'static,
+{
+    let historian = historian.clone();
+    tokio::task::spawn_blocking(move || {
+        let historian = historian
+            .lock()
+            .map_err(|_| "The historian is not available.".to_string())?;
+        query(&historian).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Records one poll on the blocking pool.
+pub fn record(
+    historian: &SharedHistorian,
+    timestamp: i64,
+    values: &[(String, f64)],
+) -> JoinHandle<()> {
+    let historian = historian.clone();
+    let values = values.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let Ok(mut historian) = historian.lock() else {
+            return;
+        };
+        if let Err(e) = historian.record(timestamp, &values) {
+            println!("Could not record history: {:?}", e);
+        }
+    })
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    #[test]
+    fn records_and_downsamples() {
+        let mut historian = Historian::open(":memory:", RetentionPolicy::default()).unwrap();
+        historian
+            .record(1000, &[("HR1".to_string(), 1.0), ("HR2".to_string(), 10.0)])
+            .unwrap();
+        historian.record(2000, &[("HR1".to_string(), 3.0)]).unwrap();
+        historian.record(3500, &[("HR1".to_string(), 5.0)]).unwrap();
+
+        let samples: Vec<(i64, f64)> = historian
+            .samples("HR1", 1000, 2000)
+            .unwrap()
+            .iter()
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect();
+        assert_eq!(samples, [(1000, 1.0), (2000, 3.0)]);
+        assert_eq!(historian.samples("HR2", 0, 4000).unwrap().len(), 1);
+
+        let buckets: Vec<(i64, f64, f64, f64, u32)> = historian
+            .buckets("HR1", 0, 4000, 2000)
+            .unwrap()
+            .iter()
+            .map(|b| (b.start, b.min, b.max, b.avg, b.count))
+            .collect();
+        assert_eq!(buckets, [(0, 1.0, 1.0, 1.0, 1), (2000, 3.0, 5.0, 4.0, 2)]);
+    }
+
+    #[test]
+    fn purges_what_the_policy_expires() {
+        let mut historian = Historian::open(":memory:", RetentionPolicy::days(1)).unwrap();
+        historian.record(0, &[("HR1".to_string(), 1.0)]).unwrap();
+        historian.record(DAY, &[("HR1".to_string(), 2.0)]).unwrap();
+        assert_eq!(historian.purge(DAY).unwrap(), 0);
+        assert_eq!(historian.purge(DAY + 1).unwrap(), 1);
+        let samples = historian.samples("HR1", 0, 2 * DAY).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].value, 2.0);
+    }
+
+    #[tokio::test]
+    async fn queries_off_the_runtime() {
+        let historian = Historian::open(":memory:", RetentionPolicy::default()).unwrap();
+        let historian: SharedHistorian = Arc::new(Mutex::new(historian));
+        record(&historian, 1000, &[("HR1".to_string(), 1.5)])
+            .await
+            .unwrap();
+        let samples = query(&historian, |historian| historian.samples("HR1", 0, 2000))
+            .await
+            .unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].value, 1.5);
+        let error = query(&historian, |_| Err::<(), _>(rusqlite::Error::InvalidQuery)).await;
+        assert_eq!(error, Err(rusqlite::Error::InvalidQuery.to_string()));
+    }
+}
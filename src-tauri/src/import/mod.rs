@@ -0,0 +1,682 @@
+use axum::extract::{Form, State};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_modbus::FunctionCode;
+
+use crate::modbus::{
+    function_code_from, modbus_status_bar, register_tag, Access, ByteOrder, DataType, ModbusState,
+    PollBlock, Tag, MARGIN, TABLE_WIDTH, WINDOW_WIDTH,
+};
+
+const MAX_BLOCK_REGISTERS: u16 = 125; // Modbus limit for a single register read.
+const MAX_BLOCK_COILS: u16 = 2000;
+const MAX_BLOCK_GAP: u16 = 8; // Unused registers read to save a request.
+
+/// Columns of the register map and the guesses tried for each, in that order.
+const COLUMNS: [(&str, &[&str]); 7] = [
+    ("address", &["address", "register", "addr", "offset", "reg"]),
+    (
+        "name",
+        &["name", "tag", "label", "parameter", "description"],
+    ),
+    ("type", &["type", "data type", "datatype", "format"]),
+    (
+        "scale",
+        &["scale", "factor", "multiplier", "gain", "scaling"],
+    ),
+    ("unit", &["unit", "units", "uom"]),
+    ("access", &["access", "r/w", "rw", "read/write"]),
+    ("function", &["function", "function code", "fc", "table"]),
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressStyle {
+    /// The address is the register offset sent on the wire.
+    #[default]
+    Raw,
+    /// 00001 coils, 30001 input registers, 40001 holding registers.
+    Modicon,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    #[default]
+    Replace,
+    Append,
+}
+
+/// The CSV text, how its columns map to tag fields and what to do with the result.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ImportForm {
+    pub csv: String,
+    pub address_style: AddressStyle,
+    /// Used when no function column is mapped and the address doesn't tell.
+    pub default_function: u8,
+    pub address_column: String,
+    pub name_column: String,
+    pub type_column: String,
+    pub scale_column: String,
+    pub unit_column: String,
+    pub access_column: String,
+    pub function_column: String,
+    pub mode: ImportMode,
+    /// `false` only checks the map.
+    pub apply: bool,
+}
+
+impl Default for ImportForm {
+    fn default() -> Self {
+        ImportForm {
+            csv: String::new(),
+            address_style: AddressStyle::default(),
+            default_function: 3,
+            address_column: String::new(),
+            name_column: String::new(),
+            type_column: String::new(),
+            scale_column: String::new(),
+            unit_column: String::new(),
+            access_column: String::new(),
+            function_column: String::new(),
+            mode: ImportMode::default(),
+            apply: false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ImportIssue {
+    /// CSV line, 0 for problems that aren't tied to a row.
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub tags: Vec<Tag>,
+    pub poll_blocks: Vec<PollBlock>,
+    pub issues: Vec<ImportIssue>,
+}
+
+impl ImportForm {
+    fn column(&self, field: &str) -> &str {
+        match field {
+            "address" => &self.address_column,
+            "name" => &self.name_column,
+            "type" => &self.type_column,
+            "scale" => &self.scale_column,
+            "unit" => &self.unit_column,
+            "access" => &self.access_column,
+            _ => &self.function_column,
+        }
+    }
+}
+
+fn reader(csv: &str) -> csv::Reader<&[u8]> {
+    // Spreadsheets exported with a comma decimal separator use `;` between fields.
+    let first_line = csv.lines().next().unwrap_or_default();
+    let delimiter = if first_line.matches(';').count() > first_line.matches(',').count() {
+        b';'
+    } else {
+        b','
+    };
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(csv.as_bytes())
+}
+
+pub fn csv_headers(csv: &str) -> Result<Vec<String>, String> {
+    let mut reader = reader(csv);
+    let headers = reader.headers().map_err(|e| e.to_string())?;
+    Ok(headers.iter().map(|header| header.to_string()).collect())
+}
+
+/// Header that most likely holds `field`, empty when none looks right.
+pub fn guess_column(headers: &[String], field: &str) -> String {
+    let Some((_, guesses)) = COLUMNS.iter().find(|(name, _)| *name == field) else {
+        return String::new();
+    };
+    let lower: Vec<String> = headers.iter().map(|h| h.to_lowercase()).collect();
+    let exact = guesses
+        .iter()
+        .find_map(|guess| lower.iter().position(|h| h == guess));
+    let partial = || {
+        guesses
+            .iter()
+            .find_map(|guess| lower.iter().position(|h| h.contains(guess)))
+    };
+    exact
+        .or_else(partial)
+        .map(|i| headers[i].clone())
+        .unwrap_or_default()
+}
+
+fn parse_address(
+    address: &str,
+    style: AddressStyle,
+    function: Option<u8>,
+) -> Result<(Option<u8>, u16), String> {
+    let number: u32 = match address.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(|_| format!("Bad address `{}`.", address))?;
+    let (table, register) = match style {
+        AddressStyle::Raw => (function, number),
+        AddressStyle::Modicon => match number {
+            1..=9_999 => (Some(1), number - 1),
+            30_001..=39_999 => (Some(4), number - 30_001),
+            40_001..=49_999 => (Some(3), number - 40_001),
+            300_001..=365_536 => (Some(4), number - 300_001),
+            400_001..=465_536 => (Some(3), number - 400_001),
+            10_001..=19_999 | 100_001..=165_536 => {
+                return Err("Discrete inputs are not supported.".to_string())
+            }
+            _ => return Err(format!("`{}` is not a Modicon address.", address)),
+        },
+    };
+    let register = u16::try_from(register).map_err(|_| format!("Bad address `{}`.", address))?;
+    Ok((table, register))
+}
+
+fn parse_function(function: &str) -> Result<Option<u8>, String> {
+    match function.to_lowercase().as_str() {
+        "" => Ok(None),
+        "1" | "01" | "coil" | "coils" => Ok(Some(1)),
+        "3" | "03" | "hr" | "holding" | "holding register" | "holding registers" => Ok(Some(3)),
+        "4" | "04" | "ir" | "input" | "input register" | "input registers" => Ok(Some(4)),
+        _ => Err(format!("Unsupported function `{}`.", function)),
+    }
+}
+
+/// Data type and whether the type implies a coil.
+fn parse_type(data_type: &str) -> Result<(DataType, bool), String> {
+    match data_type.to_lowercase().as_str() {
+        "" | "int16" | "uint16" | "i16" | "u16" | "int" | "uint" | "word" | "short" => {
+            Ok((DataType::Int16, false))
+        }
+        "f32" | "float" | "float32" | "real" | "ieee754" => Ok((DataType::F32, false)),
+        "bool" | "boolean" | "bit" | "coil" => Ok((DataType::Int16, true)),
+        _ => Err(format!("Unsupported type `{}`.", data_type)),
+    }
+}
+
+fn parse_access(access: &str) -> Result<Access, String> {
+    match access.to_lowercase().as_str() {
+        "" | "r" | "ro" | "read" | "read only" | "read_only" => Ok(Access::ReadOnly),
+        "rw" | "r/w" | "w" | "write" | "read/write" | "read write" | "read_write" => {
+            Ok(Access::ReadWrite)
+        }
+        _ => Err(format!("Unsupported access `{}`.", access)),
+    }
+}
+
+/// Turns a register map into tags and the poll blocks that cover them.
+pub fn import_register_map(form: &ImportForm) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut reader = reader(&form.csv);
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            report.issues.push(ImportIssue {
+                line: 0,
+                message: e.to_string(),
+            });
+            return report;
+        }
+    };
+
+    // Index of each field's column, `None` when it isn't mapped.
+    let mut columns = Vec::new();
+    for (field, _) in COLUMNS {
+        let column = form.column(field);
+        let index = headers.iter().position(|header| header == column);
+        if !column.is_empty() && index.is_none() {
+            report.issues.push(ImportIssue {
+                line: 0,
+                message: format!("There is no `{}` column.", column),
+            });
+        }
+        columns.push(index);
+    }
+    if columns[0].is_none() || columns[1].is_none() {
+        report.issues.push(ImportIssue {
+            line: 0,
+            message: "The address and name columns must be mapped.".to_string(),
+        });
+        return report;
+    }
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                report.issues.push(ImportIssue {
+                    line: e.position().map(|p| p.line()).unwrap_or_default(),
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let field = |i: usize| {
+            columns[i]
+                .and_then(|column| record.get(column))
+                .unwrap_or_default()
+        };
+        let fields: [&str; 7] = std::array::from_fn(field);
+        // Blank rows and section titles are common in vendor sheets.
+        if fields[0].is_empty() {
+            continue;
+        }
+        match tag_from_row(form, fields) {
+            Ok(tag) => report.tags.push(tag),
+            Err(message) => report.issues.push(ImportIssue { line, message }),
+        }
+    }
+
+    let tags = std::mem::take(&mut report.tags);
+    report.tags = check_tags(Vec::new(), tags, &mut report.issues);
+    report.poll_blocks = poll_blocks_for(&report.tags);
+    report
+}
+
+/// `fields` are in the order of `COLUMNS`.
+fn tag_from_row(form: &ImportForm, fields: [&str; 7]) -> Result<Tag, String> {
+    let [address, name, data_type, scale, unit, access, function] = fields;
+    if name.is_empty() {
+        return Err(format!("Address `{}` has no name.", address));
+    }
+    let (data_type, coil) = parse_type(data_type)?;
+    let function = parse_function(function)?;
+    let (table, register) = parse_address(address, form.address_style, function)?;
+    let function = match (table, coil) {
+        (Some(function), _) => function,
+        (None, true) => 1,
+        (None, false) => form.default_function,
+    };
+    if function_code_from(function).is_none() {
+        return Err(format!("Unsupported function `{}`.", function));
+    }
+    if function == 1 && data_type == DataType::F32 {
+        return Err(format!("Coil `{}` can't be a float.", name));
+    }
+    if data_type == DataType::F32 && register == u16::MAX {
+        return Err(format!("`{}` runs past register 65535.", name));
+    }
+    let scale = match scale {
+        "" => 1.0,
+        scale => scale
+            .replace(',', ".")
+            .parse()
+            .map_err(|_| format!("Bad scale `{}`.", scale))?,
+    };
+    Ok(Tag {
+        name: name.to_string(),
+        function,
+        register,
+        data_type,
+        scale,
+        unit: unit.to_string(),
+        access: parse_access(access)?,
+        byte_order: ByteOrder::default(),
+        unit_id: None,
+    })
+}
+
+/// Adds `tags` to `kept`, dropping duplicate names and tags overlapping an earlier one and reporting both.
+fn check_tags(mut kept: Vec<Tag>, tags: Vec<Tag>, issues: &mut Vec<ImportIssue>) -> Vec<Tag> {
+    for tag in tags {
+        if kept.iter().any(|other| other.name == tag.name) {
+            issues.push(ImportIssue {
+                line: 0,
+                message: format!("`{}` is defined twice.", tag.name),
+            });
+            continue;
+        }
+        let end = tag.register as u32 + tag.width() as u32;
+        let overlap = kept.iter().find(|other| {
+            other.unit_id == tag.unit_id
+                && other.function == tag.function
+                && (other.register as u32) < end
+                && (tag.register as u32) < other.register as u32 + other.width() as u32
+        });
+        if let Some(other) = overlap {
+            issues.push(ImportIssue {
+                line: 0,
+                message: format!("`{}` overlaps `{}`.", tag.name, other.name),
+            });
+            continue;
+        }
+        kept.push(tag);
+    }
+    kept
+}
+
+/// Groups tags into as few reads as the Modbus limits allow.
+pub fn poll_blocks_for(tags: &[Tag]) -> Vec<PollBlock> {
+    let mut sorted: Vec<&Tag> = tags.iter().collect();
+    sorted.sort_by_key(|tag| (tag.unit_id, tag.function, tag.register));
+
+    let mut blocks: Vec<PollBlock> = Vec::new();
+    for tag in sorted {
+        let max = if tag.function == 1 {
+            MAX_BLOCK_COILS
+        } else {
+            MAX_BLOCK_REGISTERS
+        };
+        let end = tag.register as u32 + tag.width() as u32;
+        if let Some(block) = blocks.last_mut() {
+            let block_end = block.register as u32 + block.count as u32;
+            if block.function == tag.function
+                && block.unit_id == tag.unit_id
+                && tag.register as u32 <= block_end + MAX_BLOCK_GAP as u32
+                && end - block.register as u32 <= max as u32
+            {
+                block.count = (end.max(block_end) - block.register as u32) as u16;
+                continue;
+            }
+        }
+        let function_code =
+            function_code_from(tag.function).unwrap_or(FunctionCode::ReadHoldingRegisters);
+        blocks.push(PollBlock {
+            name: register_tag(&function_code, tag.register),
+            function: tag.function,
+            register: tag.register,
+            count: tag.width(),
+            data_type: DataType::Int16,
+            unit_id: tag.unit_id,
+        });
+    }
+    blocks
+}
+
+/// Adds the imported tags and blocks to the running project.
+/// Appending regenerates the poll blocks from all tags, `report` ends up with what was added.
+fn apply_import(state: &mut ModbusState, report: &mut ImportReport, mode: ImportMode) {
+    let project = &mut state.project;
+    match mode {
+        ImportMode::Replace => {
+            project.tags = report.tags.clone();
+            project.poll_blocks = report.poll_blocks.clone();
+        }
+        ImportMode::Append => {
+            // Imported tags replace the ones of the same name and may not overlap the others.
+            project
+                .tags
+                .retain(|tag| report.tags.iter().all(|t| t.name != tag.name));
+            let existing = project.tags.len();
+            let tags = std::mem::take(&mut report.tags);
+            project.tags = check_tags(std::mem::take(&mut project.tags), tags, &mut report.issues);
+            project.poll_blocks = poll_blocks_for(&project.tags);
+            report.tags = project.tags[existing..].to_vec();
+            report.poll_blocks = project.poll_blocks.clone();
+        }
+    }
+}
+
+pub async fn import_columns(Form(form_input): Form<ImportForm>) -> Markup {
+    match csv_headers(&form_input.csv) {
+        Ok(headers) => column_mapping(&headers),
+        Err(e) => html! {
+            #import_mapping {
+                p { (format!("Error: {}", e)) }
+            }
+        },
+    }
+}
+
+pub async fn import_csv(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<ImportForm>,
+) -> Markup {
+    let mut report = import_register_map(&form_input);
+    if form_input.apply {
+        let mut mtx = mtx.lock().await;
+        apply_import(&mut mtx, &mut report, form_input.mode);
+    }
+    import_report(&report, form_input.apply)
+}
+
+fn column_mapping(headers: &[String]) -> Markup {
+    html! {
+        #import_mapping {
+            fieldset {
+                legend { "Columns" }
+                div class="field-row-stacked" style="width: 200px" {
+                    @for (field, _) in COLUMNS {
+                        @let guess = guess_column(headers, field);
+                        label for=(format!("{}_column", field)) { (format!("{}: ", field)) }
+                        select name=(format!("{}_column", field)) id=(format!("{}_column", field)) {
+                            option value="" { "(none)" }
+                            @for header in headers {
+                                option value=(header) selected[*header == guess] { (header) }
+                            }
+                        }
+                    }
+                }
+                div class="field-row" {
+                    button hx-post="/import_csv" hx-target="#import_report" hx-swap="outerHTML" hx-vals=r#"{"apply": "false"}"# { "Check" }
+                    button hx-post="/import_csv" hx-target="#import_report" hx-swap="outerHTML" hx-vals=r#"{"apply": "true"}"# { "Import" }
+                }
+            }
+        }
+    }
+}
+
+fn import_report(report: &ImportReport, applied: bool) -> Markup {
+    html! {
+        #import_report {
+            p {
+                (format!("{} tags in {} poll blocks, {} problems.", report.tags.len(), report.poll_blocks.len(), report.issues.len()))
+                @if applied { " Imported into the project." }
+            }
+            @if !report.issues.is_empty() {
+                div class="sunken-panel" style=(format!("height: 120px; width: {}px", TABLE_WIDTH)) {
+                    table class="interactive" {
+                        thead {
+                            tr {
+                                th { "Line" }
+                                th { "Problem" }
+                            }
+                        }
+                        tbody {
+                            @for issue in &report.issues {
+                                tr {
+                                    td { @if issue.line > 0 { (issue.line) } }
+                                    td { (issue.message) }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            div class="sunken-panel" style=(format!("height: 120px; width: {}px", TABLE_WIDTH)) {
+                table class="interactive" {
+                    thead {
+                        tr {
+                            th { "Block" }
+                            th { "Function" }
+                            th { "Register" }
+                            th { "Count" }
+                        }
+                    }
+                    tbody {
+                        @for block in &report.poll_blocks {
+                            tr {
+                                td { (block.name) }
+                                td { (block.function) }
+                                td { (block.register) }
+                                td { (block.count) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn import_body() -> Markup {
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Import Register Map" }
+                    }
+                    div class="window-body" {
+                        form #import_form {
+                            fieldset {
+                                legend { "Register Map" }
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="csv" { "CSV: (paste from the spreadsheet export)" }
+                                    textarea id="csv" name="csv" rows="8" {}
+                                    label for="address_style" { "Addresses: " }
+                                    select name="address_style" id="address_style" {
+                                        option value="raw" { "Register offset" }
+                                        option value="modicon" { "Modicon (40001...)" }
+                                    }
+                                    label for="default_function" { "Default Function Code: " }
+                                    select name="default_function" id="default_function" {
+                                        option value="3" { "0x03-Read Holding Registers" }
+                                        option value="4" { "0x04-Read Input Registers" }
+                                        option value="1" { "0x01-Read Coils" }
+                                    }
+                                    label for="mode" { "Existing Tags: " }
+                                    select name="mode" id="mode" {
+                                        option value="replace" { "Replace" }
+                                        option value="append" { "Keep and add" }
+                                    }
+                                    button hx-post="/import_columns" hx-target="#import_mapping" hx-swap="outerHTML" { "Map Columns" }
+                                }
+                            }
+                            #import_mapping {}
+                        }
+                        #import_report {}
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::ProtocolOpts;
+
+    fn form(csv: &str, address_style: AddressStyle) -> ImportForm {
+        let headers = csv_headers(csv).unwrap();
+        ImportForm {
+            csv: csv.to_string(),
+            address_style,
+            address_column: guess_column(&headers, "address"),
+            name_column: guess_column(&headers, "name"),
+            type_column: guess_column(&headers, "type"),
+            scale_column: guess_column(&headers, "scale"),
+            unit_column: guess_column(&headers, "unit"),
+            access_column: guess_column(&headers, "access"),
+            function_column: guess_column(&headers, "function"),
+            ..ImportForm::default()
+        }
+    }
+
+    #[test]
+    fn imports_modicon_map() {
+        let csv = "Register;Tag Name;Data Type;Factor;Unit;R/W\n\
+                   40001;Voltage;float;0,1;V;R\n\
+                   40003;Current;uint16;;A;R\n\
+                   30010;Energy;int16;1;kWh;R\n\
+                   00005;Relay;bool;;;RW\n";
+        let report = import_register_map(&form(csv, AddressStyle::Modicon));
+        assert_eq!(report.issues, Vec::new());
+        assert_eq!(report.tags.len(), 4);
+        assert_eq!(report.tags[0].register, 0);
+        assert_eq!(report.tags[0].scale, 0.1);
+        assert_eq!(report.tags[2].function, 4);
+        assert_eq!(report.tags[3].access, Access::ReadWrite);
+        let blocks: Vec<(u8, u16, u16)> = report
+            .poll_blocks
+            .iter()
+            .map(|b| (b.function, b.register, b.count))
+            .collect();
+        assert_eq!(blocks, vec![(1, 4, 1), (3, 0, 3), (4, 9, 1)]);
+    }
+
+    #[test]
+    fn reports_overlaps_and_bad_types() {
+        let csv = "address,name,type\n\
+                   10,A,f32\n\
+                   11,B,int16\n\
+                   12,C,double\n\
+                   13,A,int16\n";
+        let report = import_register_map(&form(csv, AddressStyle::Raw));
+        let messages: Vec<&str> = report.issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Unsupported type `double`.",
+                "`B` overlaps `A`.",
+                "`A` is defined twice."
+            ]
+        );
+        assert_eq!(report.issues[0].line, 4);
+        assert_eq!(report.tags.len(), 1);
+    }
+
+    #[test]
+    fn handles_the_end_of_the_address_space() {
+        let csv = "address,name,type\n\
+                   65533,Counter,int16\n\
+                   65534,Total,f32\n\
+                   65535,Overflow,f32\n";
+        let report = import_register_map(&form(csv, AddressStyle::Raw));
+        let messages: Vec<&str> = report.issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(messages, vec!["`Overflow` runs past register 65535."]);
+        let blocks: Vec<(u16, u16)> = report
+            .poll_blocks
+            .iter()
+            .map(|b| (b.register, b.count))
+            .collect();
+        assert_eq!(blocks, vec![(65533, 3)]);
+
+        // A hand-edited project may still hold such a tag.
+        let mut tags = report.tags.clone();
+        tags[0].register = 65535;
+        let mut issues = Vec::new();
+        let kept = check_tags(vec![tags[1].clone()], vec![tags[0].clone()], &mut issues);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(issues[0].message, "`Counter` overlaps `Total`.");
+    }
+
+    #[test]
+    fn appending_twice_keeps_one_read_per_block() {
+        let csv = "address,name\n\
+                   0,Setpoint\n\
+                   1,Speed\n";
+        let mut state = ModbusState::new(ProtocolOpts::default(), None);
+        for _ in 0..2 {
+            let mut report = import_register_map(&form(csv, AddressStyle::Raw));
+            apply_import(&mut state, &mut report, ImportMode::Append);
+            assert_eq!(report.issues, Vec::new());
+        }
+        assert_eq!(state.project.tags.len(), 2);
+        assert_eq!(state.project.poll_blocks.len(), 1);
+        assert_eq!(state.project.poll_blocks[0].count, 2);
+
+        let mut report = import_register_map(&form("address,name\n1,Torque\n", AddressStyle::Raw));
+        apply_import(&mut state, &mut report, ImportMode::Append);
+        assert_eq!(report.issues[0].message, "`Torque` overlaps `Speed`.");
+        assert_eq!(report.tags.len(), 0);
+        assert_eq!(state.project.tags.len(), 2);
+    }
+}
@@ -1,3 +1,19 @@
+pub mod alarms;
+pub mod api;
+pub mod audit;
+pub mod auth;
+pub mod cli;
+pub mod gateway;
+pub mod historian;
+pub mod import;
+pub mod live;
 pub mod modbus;
+pub mod mqtt;
+pub mod notify;
+pub mod project;
+pub mod server;
+pub mod stats;
+pub mod templates;
+pub mod trend;
 
 pub use modbus::*;
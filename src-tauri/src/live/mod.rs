@@ -0,0 +1,173 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::modbus::{
+    alarm_status_field, connection_status_field, heartbeat_field, modbus_table, LiveEvent,
+    ModbusState,
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct LiveQuery {
+    /// `json` (default) or `html` for fragments that can be swapped into the pages.
+    pub format: Option<String>,
+}
+
+/// Pushes value changes, connection status, scan time and alarm counts to the client.
+pub async fn live_ws(
+    ws: WebSocketUpgrade,
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Query(query): Query<LiveQuery>,
+) -> Response {
+    let html = query.format.as_deref() == Some("html");
+    ws.on_upgrade(move |socket| stream_events(socket, mtx, html))
+}
+
+async fn stream_events(mut socket: WebSocket, mtx: Arc<Mutex<ModbusState>>, html: bool) {
+    let (mut events, initial) = {
+        let mtx = mtx.lock().await;
+        let (active, unacknowledged) = mtx.alarms.counts();
+        let initial = [
+            LiveEvent::Status {
+                connected: mtx.context.is_some(),
+                link: mtx.link.state.clone(),
+                tls: mtx.tls_peer.clone(),
+            },
+            LiveEvent::ScanTime {
+                micros: mtx.poll_time.map(|time| time.as_micros() as u64),
+            },
+            LiveEvent::Values {
+                snapshot: mtx.last_poll.clone(),
+            },
+            LiveEvent::Alarms {
+                active,
+                unacknowledged,
+            },
+        ];
+        (mtx.events.subscribe(), initial)
+    };
+
+    for event in &initial {
+        if send_event(&mut socket, event, html).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if send_event(&mut socket, &event, html).await.is_err() {
+                        return;
+                    }
+                }
+                // A slow client only misses intermediate values.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn send_event(
+    socket: &mut WebSocket,
+    event: &LiveEvent,
+    html: bool,
+) -> Result<(), axum::Error> {
+    let text = if html {
+        event_fragment(event).into_string()
+    } else {
+        serde_json::to_string(event).unwrap_or_default()
+    };
+    socket.send(Message::Text(text)).await
+}
+
+/// HTML fragment whose root id matches the element it replaces.
+fn event_fragment(event: &LiveEvent) -> Markup {
+    match event {
+        LiveEvent::Values { snapshot } => html! {
+            #modbus_table { (modbus_table(snapshot.as_ref())) }
+        },
+        LiveEvent::Status { link, tls, .. } => html! {
+            #modbus_connect_content { (connection_status_field(link, tls.as_ref())) }
+        },
+        LiveEvent::ScanTime { micros } => html! {
+            #heartbeat { (heartbeat_field(micros.map(std::time::Duration::from_micros))) }
+        },
+        LiveEvent::Alarms {
+            active,
+            unacknowledged,
+        } => html! {
+            #alarm_status { (alarm_status_field(*active, *unacknowledged)) }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::{LinkState, PollData, PollSnapshot};
+
+    #[test]
+    fn events_carry_their_type() {
+        let event = LiveEvent::Status {
+            connected: true,
+            link: LinkState::Connected,
+            tls: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "type": "status",
+                "connected": true,
+                "link": { "state": "connected" }
+            })
+        );
+        let event = LiveEvent::ScanTime { micros: Some(1500) };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"scan_time","micros":1500}"#
+        );
+    }
+
+    #[test]
+    fn fragments_replace_their_element() {
+        let values = LiveEvent::Values {
+            snapshot: Some(PollSnapshot {
+                function: 3,
+                start_register: 1,
+                data: PollData::Registers(vec![42]),
+            }),
+        };
+        assert!(event_fragment(&values).into_string().contains("0x002A"));
+        let alarms = LiveEvent::Alarms {
+            active: 2,
+            unacknowledged: 1,
+        };
+        for (event, id) in [
+            (values, "modbus_table"),
+            (LiveEvent::ScanTime { micros: None }, "heartbeat"),
+            (alarms, "alarm_status"),
+            (
+                LiveEvent::Status {
+                    connected: false,
+                    link: LinkState::Disconnected,
+                    tls: None,
+                },
+                "modbus_connect_content",
+            ),
+        ] {
+            let fragment = event_fragment(&event).into_string();
+            assert!(fragment.starts_with(&format!(r#"<div id="{}">"#, id)));
+        }
+    }
+}
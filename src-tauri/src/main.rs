@@ -2,22 +2,59 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use axum::{
+    extract::State,
+    middleware::from_fn_with_state,
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
-use std::{process::Command, sync::Mutex, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, process::Command, sync::Mutex, time::Duration};
+use tokio::sync::watch;
 
+use clap::Parser;
 use maud::{html, Markup, DOCTYPE};
+use mptt::alarms::{acknowledge_alarm, add_alarm, alarm_tables, alarms_body, delete_alarm};
+use mptt::api::api_router;
+use mptt::audit::{audit_body, audit_table, export_audit, AuditLog, AUDIT_DB};
+use mptt::auth::{login_body, logout, require_login, setup, Auth, USERS_FILE};
+use mptt::cli::{self, Cli};
+use mptt::gateway::{gateway_body, gateway_traffic, start_gateway_form, stop_gateway};
+use mptt::historian::{Historian, RetentionPolicy};
+use mptt::import::{import_body, import_columns, import_csv};
+use mptt::live::live_ws;
 use mptt::modbus::*;
+use mptt::mqtt::{mqtt_body, mqtt_status_fragment, run_mqtt, start_mqtt, stop_mqtt};
+use mptt::notify::{
+    add_notification, delete_notification, notification_log, notifications_body, run_notifier,
+    test_notification,
+};
+use mptt::project::{load_project, open_project, project_body, save_project};
+use mptt::server::{bind, browser_url, serve, tls_acceptor, ServerSettings, Shutdown};
+use mptt::stats::{reset_statistics, statistics_body, statistics_table};
+use mptt::templates::{
+    apply_template_form, export_template, import_template, save_template_form, templates_body,
+};
+use mptt::trend::{trend_body, trend_chart};
 use std::sync::Arc;
 use tauri::{
-    CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem,
+    AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu,
+    SystemTrayMenuItem,
 };
-use tokio_modbus::FunctionCode;
 use tower_http::services::ServeDir;
 
+const HISTORY_DB: &str = "./history.sqlite";
+
 struct AppState {
-    shutdown_signal: Arc<Mutex<bool>>,
+    /// The web server thread, stopped and restarted from the tray.
+    server: Mutex<Option<ServerThread>>,
+    /// Address of the web UI once the server listens.
+    url: Arc<Mutex<Option<String>>>,
+    project: Option<PathBuf>,
+    settings: Result<ServerSettings, String>,
+}
+
+struct ServerThread {
+    stop: watch::Sender<bool>,
+    thread: std::thread::JoinHandle<()>,
 }
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
@@ -26,44 +63,101 @@ fn greet(name: &str) -> String {
 }
 
 fn main() {
+    let cli = Cli::parse();
+    let settings = cli.server_settings();
+    match cli.command {
+        None => run_tray(cli.project, settings),
+        Some(cli::Command::Serve) => {
+            let result = settings.and_then(|settings| {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                let (stop, shutdown) = Shutdown::channel();
+                rt.block_on(async move {
+                    // Ctrl+C closes the connections and files before exiting.
+                    tokio::spawn(async move {
+                        if tokio::signal::ctrl_c().await.is_ok() {
+                            let _ = stop.send(true);
+                        }
+                    });
+                    run_server(shutdown, cli.project, settings, |_| {}).await
+                })
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(command) => {
+            let code = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt.block_on(cli::run(command)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            };
+            std::process::exit(code);
+        }
+    }
+}
+
+fn run_tray(project: Option<PathBuf>, settings: Result<ServerSettings, String>) {
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let show_ui = CustomMenuItem::new("show_ui".to_string(), "Show UI");
+    let status = CustomMenuItem::new("status".to_string(), "Starting the server...").disabled();
+    let stop_server_item = CustomMenuItem::new("stop_server".to_string(), "Stop Server");
+    let restart_server_item = CustomMenuItem::new("restart_server".to_string(), "Restart Server");
     let app_state = AppState {
-        shutdown_signal: Arc::new(Mutex::new(false)),
+        server: Mutex::new(None),
+        url: Arc::new(Mutex::new(None)),
+        project,
+        settings,
     };
-    let shutdown_signal = app_state.shutdown_signal.clone();
-    let state = shutdown_signal.clone();
-    std::thread::spawn(move || {
-        if let Ok(rt) = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-        {
-            let _ = rt.block_on(run_server(state));
-        }
-    });
     let tray_menu = SystemTrayMenu::new()
+        .add_item(status)
+        .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(show_ui)
+        .add_item(stop_server_item)
+        .add_item(restart_server_item)
         .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(quit);
     tauri::Builder::default()
         .manage(app_state)
         .system_tray(SystemTray::new().with_menu(tray_menu))
-        .on_system_tray_event(move |_app, event| match event {
+        .setup(|app| {
+            start_server(&app.handle());
+            Ok(())
+        })
+        .on_system_tray_event(move |app, event| match event {
             SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
                 "quit" => {
+                    stop_server(app);
                     std::process::exit(0);
                 }
+                "stop_server" => stop_server(app),
+                "restart_server" => {
+                    stop_server(app);
+                    start_server(app);
+                }
                 "start_runtime" => {}
                 "show_ui" => {
+                    let state = app.state::<AppState>();
+                    let Some(url) = state.url.lock().ok().and_then(|url| url.clone()) else {
+                        return;
+                    };
                     let _output = if cfg!(target_os = "windows") {
                         Command::new("explorer")
-                            .arg("http://127.0.0.1:3000")
+                            .arg(url)
                             .spawn()
                             .expect("failed to execute process")
                     } else {
                         Command::new("open")
                             .arg("-n")
-                            .arg("http://127.0.0.1:3000")
+                            .arg(url)
                             .spawn()
                             .expect("failed to execute process")
                     };
@@ -83,48 +177,168 @@ fn main() {
         });
 }
 
-async fn run_server(_shutdown_signal: Arc<Mutex<bool>>) {
-    let state = Arc::new(tokio::sync::Mutex::new(ModbusState {
-        context: None,
-        poll_time: None,
-        protocol_options: ProtocolOpts {
-            function_code: FunctionCode::ReadHoldingRegisters,
-            start_register: 1,
-            count: 5,
-            float32: false,
-        },
-    }));
+/// Runs the web server on its own thread, the tray shows where it listens or why it stopped.
+fn start_server(app: &AppHandle) {
+    let state = app.state::<AppState>();
+    let (stop, shutdown) = Shutdown::channel();
+    let (project, settings, url) = (
+        state.project.clone(),
+        state.settings.clone(),
+        state.url.clone(),
+    );
+    let tray = app.tray_handle();
+    let thread = std::thread::spawn(move || {
+        let status = tray.get_item("status");
+        let _ = status.set_title("Starting the server...");
+        let result = settings.and_then(|settings| {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| e.to_string())?;
+            let https = settings.https;
+            rt.block_on(run_server(shutdown, project, settings, |address| {
+                let address = browser_url(address, https);
+                let _ = status.set_title(format!("Listening on {}", address));
+                let _ = tray.set_tooltip("MPTT");
+                if let Ok(mut url) = url.lock() {
+                    *url = Some(address);
+                }
+            }))
+        });
+        if let Ok(mut url) = url.lock() {
+            *url = None;
+        }
+        let stopped = match result {
+            Ok(()) => "Server stopped".to_string(),
+            Err(e) => format!("Server stopped: {}", e),
+        };
+        let _ = status.set_title(&stopped);
+        let _ = tray.set_tooltip(&format!("MPTT: {}", stopped));
+    });
+    if let Ok(mut server) = state.server.lock() {
+        *server = Some(ServerThread { stop, thread });
+    }
+}
+
+/// Shuts the server down and waits until its connections and files are closed.
+fn stop_server(app: &AppHandle) {
+    let state = app.state::<AppState>();
+    let server = state
+        .server
+        .lock()
+        .ok()
+        .and_then(|mut server| server.take());
+    if let Some(server) = server {
+        let _ = app
+            .tray_handle()
+            .get_item("status")
+            .set_title("Stopping the server...");
+        let _ = server.stop.send(true);
+        let _ = server.thread.join();
+    }
+}
+
+/// Serves the web UI until it fails or `shutdown` is requested, `bound` gets the address once it listens.
+async fn run_server(
+    shutdown: Shutdown,
+    project_path: Option<PathBuf>,
+    settings: ServerSettings,
+    bound: impl FnOnce(SocketAddr),
+) -> Result<(), String> {
+    let auth = Auth::load(USERS_FILE).map_err(|e| format!("Could not load the users: {}", e))?;
+    let auth = Arc::new(auth.secure_cookies(settings.https));
+    let tls = if settings.https {
+        Some(tls_acceptor(&settings).map_err(|e| format!("HTTPS: {}", e))?)
+    } else {
+        None
+    };
+    let listener = bind(&settings).await?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    let historian = match Historian::open(HISTORY_DB, RetentionPolicy::days(settings.history_days))
+    {
+        Ok(historian) => Some(historian),
+        Err(e) => {
+            println!("Could not open the historian: {:?}", e);
+            None
+        }
+    };
+    let mut state = ModbusState::new(ProtocolOpts::default(), historian);
+    match AuditLog::open(AUDIT_DB) {
+        Ok(audit) => state.audit = Some(Arc::new(audit)),
+        Err(e) => println!("Could not open the audit log, writes are refused: {:?}", e),
+    }
+    if let Some(path) = project_path {
+        if let Err(e) = open_project(&mut state, &path).await {
+            println!("Could not open the project {}: {}", path.display(), e);
+        }
+    }
+    let state = Arc::new(tokio::sync::Mutex::new(state));
+    let poller = tokio::spawn(run_poller(state.clone(), shutdown.clone()));
+    let notifier = tokio::spawn(run_notifier(state.clone(), shutdown.clone()));
+    let bridge = tokio::spawn(run_mqtt(state.clone(), shutdown.clone()));
     let app = Router::new()
         .route("/", get(modbus_tcp))
         .route("/modbus_serial", get(modbus_serial))
         .route("/poll_modbus", get(poll_modbus))
         .route("/heartbeat", get(heartbeat))
         .route("/disconnect_modbus", get(disconnect_modbus))
-        .route("/connect_modbus_tcp", post(connect_modbus_tcp))
         .route("/connect_modbus_serial", post(connect_modbus_serial))
-        .route("/write_modbus", post(write_modbus))
+        .route("/cancel_write", get(cancel_write))
         .route("/update_modbus", post(update_modbus))
+        .route("/ws", get(live_ws))
+        .route("/trend", get(trend))
+        .route("/trend_chart", get(trend_chart))
+        .route("/project", get(project))
+        .route("/save_project", post(save_project))
+        .route("/load_project", post(load_project))
+        .route("/import", get(import))
+        .route("/import_columns", post(import_columns))
+        .route("/import_csv", post(import_csv))
+        .route("/templates", get(templates))
+        .route("/gateway", get(gateway))
+        .route("/start_gateway", post(start_gateway_form))
+        .route("/stop_gateway", get(stop_gateway))
+        .route("/gateway_traffic", get(gateway_traffic))
+        .route("/statistics", get(statistics))
+        .route("/statistics_table", get(statistics_table))
+        .route("/reset_statistics", post(reset_statistics))
+        .route("/alarms", get(alarms))
+        .route("/alarm_tables", get(alarm_tables))
+        .route("/acknowledge_alarm", post(acknowledge_alarm))
+        .route("/add_alarm", post(add_alarm))
+        .route("/delete_alarm", post(delete_alarm))
+        .route("/notifications", get(notifications))
+        .route("/add_notification", post(add_notification))
+        .route("/delete_notification", post(delete_notification))
+        .route("/test_notification", post(test_notification))
+        .route("/notification_log", get(notification_log))
+        .route("/mqtt", get(mqtt))
+        .route("/audit", get(audit))
+        .route("/audit_table", get(audit_table))
+        .route("/export_audit", get(export_audit))
+        .route("/start_mqtt", post(start_mqtt))
+        .route("/stop_mqtt", get(stop_mqtt))
+        .route("/mqtt_status", get(mqtt_status_fragment))
+        .route("/apply_template", post(apply_template_form))
+        .route("/save_template", post(save_template_form))
+        .route("/import_template", post(import_template))
+        .route("/export_template", get(export_template))
+        .route("/login", get(login_page).post(mptt::auth::login))
+        .route("/setup", post(setup))
+        .route("/logout", post(logout))
+        .merge(api_router())
         .nest_service("/assets", ServeDir::new("./assets/"))
-        .with_state(state);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Starting server...");
-
-    axum::serve(listener, app)
-        /*
-        .with_graceful_shutdown(async move {
-            loop {
-                if let Ok(mut signal) = shutdown_signal.lock() {
-                    if *signal {
-                        println!("Shutting down server...");
-                        *signal = false;
-                        return;
-                    }
-                }
-            }
-        })
-         */
-        .await
-        .unwrap();
+        .layer(from_fn_with_state(auth.clone(), require_login))
+        .layer(Extension(auth))
+        .with_state(state.clone());
+    println!("Listening on {}", browser_url(address, settings.https));
+    bound(address);
+
+    let result = serve(listener, app, tls, shutdown).await;
+    println!("Shutting down server...");
+    let _ = tokio::join!(poller, notifier, bridge);
+    shut_down(&mut *state.lock().await).await;
+    result
 }
 
 fn header(title: &str, icon: &str) -> Markup {
@@ -142,14 +356,15 @@ fn header(title: &str, icon: &str) -> Markup {
            link rel="stylesheet" href="assets/css/docs/vs.css" {}
            // Calling HTMX
            script src="assets/htmx.min.js" {}
+           script src="assets/live.js" defer {}
+           script src="assets/auth.js" {}
         }
 
     }
 }
 
-pub async fn modbus_tcp() -> Markup {
+fn sidebar() -> Markup {
     html! {
-        (header("MPTT Modbus TCP", "MPTT"))
         aside {
             ul class="tree-view" style="height: 500px;" {
                 li {
@@ -162,39 +377,49 @@ pub async fn modbus_tcp() -> Markup {
                    li {
                        a href="/modbus_serial" { "Modbus Serial" }
                    }
+                   li {
+                       a href="/gateway" { "TCP to RTU Gateway" }
+                   }
                }
-               details {
-                   summary { "About" }
-                   ul {
-                       li {
-                           "Author: Abdelkader Madoui"
-                       }
-                       li {
-                           "Email: abdelkadermadoui@protonmail.com"
-                       }
+               li {
+                   a href="" { "Project" }
+               }
+               ul {
+                   li {
+                       a href="/project" { "Project File" }
+                   }
+                   li {
+                       a href="/import" { "Import Register Map" }
+                   }
+                   li {
+                       a href="/templates" { "Device Templates" }
                    }
                }
-           }
-        }
-        (modbus_tcp_body())
-    }
-}
-
-pub async fn modbus_serial() -> Markup {
-    html! {
-        (header("MPTT Modbus Serial", "MPTT"))
-        aside {
-            ul class="tree-view" style="height: 500px;" {
-                li {
-                    a href="" { "Modbus" }
-                }
+               li {
+                   a href="" { "Data" }
+               }
                ul {
                    li {
-                       a href="/" { "Modbus TCP" }
+                       a href="/trend" { "Trend" }
                    }
                    li {
-                       a href="/modbus_serial" { "Modbus Serial" }
+                       a href="/statistics" { "Statistics" }
+                   }
+                   li {
+                       a href="/alarms" { "Alarms" }
+                   }
+                   li {
+                       a href="/notifications" { "Notifications" }
                    }
+                   li {
+                       a href="/mqtt" { "MQTT Bridge" }
+                   }
+                   li {
+                       a href="/audit" { "Audit Log" }
+                   }
+               }
+               li {
+                   a href="" hx-post="/logout" { "Log Out" }
                }
                details {
                    summary { "About" }
@@ -208,8 +433,119 @@ pub async fn modbus_serial() -> Markup {
                    }
                }
            }
-
         }
-        (modbus_serial_body())
+    }
+}
+
+pub async fn modbus_tcp(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Modbus TCP", "MPTT"))
+        (sidebar())
+        (modbus_tcp_body(&mtx))
+    }
+}
+
+pub async fn modbus_serial(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Modbus Serial", "MPTT"))
+        (sidebar())
+        (modbus_serial_body(&mtx))
+    }
+}
+
+pub async fn trend(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Trend", "MPTT"))
+        (sidebar())
+        (trend_body(&mtx.project.display))
+    }
+}
+
+pub async fn project(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Project", "MPTT"))
+        (sidebar())
+        (project_body(&mtx))
+    }
+}
+
+pub async fn import() -> Markup {
+    html! {
+        (header("MPTT Import", "MPTT"))
+        (sidebar())
+        (import_body())
+    }
+}
+
+pub async fn templates() -> Markup {
+    html! {
+        (header("MPTT Device Templates", "MPTT"))
+        (sidebar())
+        (templates_body())
+    }
+}
+
+pub async fn statistics(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Statistics", "MPTT"))
+        (sidebar())
+        (statistics_body(&mtx))
+    }
+}
+
+pub async fn alarms(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Alarms", "MPTT"))
+        (sidebar())
+        (alarms_body(&mtx))
+    }
+}
+
+pub async fn notifications(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Notifications", "MPTT"))
+        (sidebar())
+        (notifications_body(&mtx))
+    }
+}
+
+pub async fn login_page(Extension(auth): Extension<Arc<Auth>>) -> Markup {
+    html! {
+        (header("MPTT Log In", "MPTT"))
+        (login_body(&auth))
+    }
+}
+
+pub async fn audit(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Audit Log", "MPTT"))
+        (sidebar())
+        (audit_body(&mtx))
+    }
+}
+
+pub async fn mqtt(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT MQTT Bridge", "MPTT"))
+        (sidebar())
+        (mqtt_body(&mtx))
+    }
+}
+
+pub async fn gateway(State(mtx): State<Arc<tokio::sync::Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    html! {
+        (header("MPTT Gateway", "MPTT"))
+        (sidebar())
+        (gateway_body(&mtx))
     }
 }
@@ -0,0 +1,225 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::prelude::*;
+
+use super::pdu::{parse_response, request_pdu};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_FRAME: usize = 513; // ':' + 2 * (address + PDU + LRC) + CR LF
+
+/// Two's complement of the byte sum.
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// `:` followed by address, PDU and LRC as upper case hex, ended by CR LF.
+pub fn encode_frame(slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(pdu.len() + 2);
+    data.push(slave);
+    data.extend_from_slice(pdu);
+    data.push(lrc(&data));
+
+    let mut frame = Vec::with_capacity(data.len() * 2 + 3);
+    frame.push(b':');
+    for byte in data {
+        frame.extend_from_slice(format!("{:02X}", byte).as_bytes());
+    }
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Address and PDU of a received frame, trailing CR and LF are optional.
+pub fn decode_frame(frame: &[u8]) -> Result<(u8, Vec<u8>), String> {
+    let Some(hex) = frame.strip_prefix(b":") else {
+        return Err("Missing ':' start of frame.".to_string());
+    };
+    let hex = hex.strip_suffix(b"\n").unwrap_or(hex);
+    let hex = hex.strip_suffix(b"\r").unwrap_or(hex);
+    if hex.len() % 2 != 0 {
+        return Err("Odd number of hex digits.".to_string());
+    }
+    let data = hex
+        .chunks_exact(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or("Invalid hex digit.")?;
+    let [slave, pdu @ .., received] = data.as_slice() else {
+        return Err("Frame too short.".to_string());
+    };
+    if pdu.is_empty() {
+        return Err("Frame too short.".to_string());
+    }
+    if lrc(&data[..data.len() - 1]) != *received {
+        return Err("LRC error.".to_string());
+    }
+    Ok((*slave, pdu.to_vec()))
+}
+
+/// Modbus ASCII client over any byte stream, usually a serial port.
+pub struct AsciiClient<T> {
+    transport: BufReader<T>,
+    slave: Slave,
+}
+
+impl<T> fmt::Debug for AsciiClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AsciiClient({:?})", self.slave)
+    }
+}
+
+impl<T> SlaveContext for AsciiClient<T> {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+impl<T> AsciiClient<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Reads lines until a frame from our slave arrives, skipping noise and other slaves.
+    async fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            let mut line = Vec::new();
+            let read = (&mut self.transport)
+                .take(MAX_FRAME as u64)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed."));
+            }
+            let Some(start) = line.iter().position(|b| *b == b':') else {
+                continue;
+            };
+            let (slave, pdu) =
+                decode_frame(&line[start..]).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if slave == self.slave.0 {
+                return Ok(pdu);
+            }
+        }
+    }
+
+    async fn exchange(&mut self, pdu: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let frame = encode_frame(self.slave.0, pdu);
+        self.transport.get_mut().write_all(&frame).await?;
+        self.transport.get_mut().flush().await?;
+        // Broadcasts are never answered.
+        if self.slave.0 == 0 {
+            return Ok(None);
+        }
+        match tokio::time::timeout(RESPONSE_TIMEOUT, self.read_frame()).await {
+            Ok(pdu) => pdu.map(Some),
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, "No response.")),
+        }
+    }
+}
+
+#[async_trait]
+impl<T> Client for AsciiClient<T>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let pdu = request_pdu(&request)?;
+        match self.exchange(&pdu).await? {
+            Some(response) => Ok(parse_response(&request, &response)?),
+            None => Err(Error::new(ErrorKind::Unsupported, "Broadcasts have no response.").into()),
+        }
+    }
+
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.transport.get_mut().shutdown().await
+    }
+}
+
+pub fn attach_ascii<T>(transport: T, slave: Slave) -> Context
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let client: Box<dyn Client> = Box::new(AsciiClient {
+        transport: BufReader::new(transport),
+        slave,
+    });
+    Context::from(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_read_holding_registers() {
+        let frame = encode_frame(1, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(frame, b":010300000001FB\r\n");
+    }
+
+    #[test]
+    fn lrc_wraps_around() {
+        assert_eq!(lrc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]), 0xFB);
+        assert_eq!(lrc(&[0xFF, 0x01]), 0x00);
+        assert_eq!(lrc(&[]), 0x00);
+    }
+
+    #[test]
+    fn decodes_with_or_without_line_ending() {
+        let expected = Ok((0x11, vec![0x03, 0x02, 0x00, 0x2A]));
+        assert_eq!(decode_frame(b":110302002AC0\r\n"), expected);
+        assert_eq!(decode_frame(b":110302002AC0\n"), expected);
+        assert_eq!(decode_frame(b":110302002AC0"), expected);
+        assert_eq!(decode_frame(b":110302002ac0\r\n"), expected);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        assert_eq!(
+            decode_frame(b":110302002AC1\r\n"),
+            Err("LRC error.".to_string())
+        );
+        assert_eq!(
+            decode_frame(b"110302002AC0\r\n"),
+            Err("Missing ':' start of frame.".to_string())
+        );
+        assert_eq!(
+            decode_frame(b":110302002AC\r\n"),
+            Err("Odd number of hex digits.".to_string())
+        );
+        assert_eq!(
+            decode_frame(b":11G302002AC0\r\n"),
+            Err("Invalid hex digit.".to_string())
+        );
+        assert_eq!(
+            decode_frame(b":11EF\r\n"),
+            Err("Frame too short.".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_registers_through_context() {
+        let (client, mut device) = tokio::io::duplex(256);
+        let mut ctx = attach_ascii(client, Slave(0x11));
+        let device = tokio::spawn(async move {
+            let mut request = [0; 17];
+            device.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b":110300000001EB\r\n");
+            // Noise and another slave's answer come first on a shared bus.
+            device.write_all(b"\0\r\n:2103020001D9\r\n").await.unwrap();
+            device.write_all(b":1103020001E9\r\n").await.unwrap();
+        });
+        let response = ctx.call(Request::ReadHoldingRegisters(0, 1)).await;
+        assert_eq!(
+            response.unwrap().unwrap(),
+            Response::ReadHoldingRegisters(vec![1])
+        );
+        device.await.unwrap();
+    }
+}
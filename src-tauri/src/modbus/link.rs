@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+use crate::historian::now_millis;
+use crate::notify::{NotificationEvent, Notifier};
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+const LOG_CAPACITY: usize = 100;
+
+/// Where the connection is in its life cycle.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LinkState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    /// A request failed at the transport level, the context is dropped on the next poll.
+    Faulted {
+        error: String,
+    },
+    /// Waiting `delay_ms` before reconnect attempt `attempt`.
+    Backoff {
+        attempt: u32,
+        delay_ms: u64,
+        error: String,
+    },
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkState::Disconnected => write!(f, "No connection"),
+            LinkState::Connecting => write!(f, "Connecting..."),
+            LinkState::Connected => write!(f, "Connected"),
+            LinkState::Faulted { error } => write!(f, "Faulted: {}", error),
+            LinkState::Backoff {
+                attempt,
+                delay_ms,
+                error,
+            } => write!(
+                f,
+                "Reconnect {} in {} s after: {}",
+                attempt,
+                delay_ms / 1000,
+                error
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct LinkTransition {
+    pub timestamp: i64,
+    pub state: LinkState,
+}
+
+/// Connection state machine, the poller reconnects when a retry is due.
+#[derive(Default)]
+pub struct Link {
+    pub state: LinkState,
+    /// Latest transitions, oldest first.
+    pub log: VecDeque<LinkTransition>,
+    attempt: u32,
+    retry_at: Option<Instant>,
+    /// Named in the events, kept after a disconnect so it is still known then.
+    connection: String,
+    pub notifier: Notifier,
+}
+
+/// 1, 2, 4 ... seconds, at most a minute.
+pub fn backoff_delay(attempt: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(BACKOFF_MAX)
+}
+
+impl Link {
+    fn set(&mut self, state: LinkState) {
+        if state == self.state {
+            return;
+        }
+        let timestamp = now_millis();
+        // Reconnect attempts would flood the sinks, only the outcome is sent.
+        if !matches!(state, LinkState::Connecting | LinkState::Backoff { .. }) {
+            self.notifier.notify(NotificationEvent::Connection {
+                timestamp,
+                connection: self.connection.clone(),
+                state: state.clone(),
+            });
+        }
+        if self.log.len() == LOG_CAPACITY {
+            self.log.pop_front();
+        }
+        self.log.push_back(LinkTransition {
+            timestamp,
+            state: state.clone(),
+        });
+        self.state = state;
+    }
+
+    pub fn connecting(&mut self, connection: String) {
+        self.connection = connection;
+        self.set(LinkState::Connecting);
+    }
+
+    pub fn connected(&mut self) {
+        self.attempt = 0;
+        self.retry_at = None;
+        self.set(LinkState::Connected);
+    }
+
+    pub fn disconnected(&mut self) {
+        self.attempt = 0;
+        self.retry_at = None;
+        self.set(LinkState::Disconnected);
+    }
+
+    /// Only a working connection can fault, later errors are part of the same outage.
+    pub fn fault(&mut self, error: String) {
+        if self.state == LinkState::Connected {
+            self.set(LinkState::Faulted { error });
+        }
+    }
+
+    /// Schedules the next reconnect attempt.
+    pub fn back_off(&mut self, error: String) {
+        let delay = backoff_delay(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+        self.retry_at = Some(Instant::now() + delay);
+        self.set(LinkState::Backoff {
+            attempt: self.attempt,
+            delay_ms: delay.as_millis() as u64,
+            error,
+        });
+    }
+
+    pub fn retry_due(&self) -> bool {
+        matches!(self.state, LinkState::Backoff { .. })
+            && self.retry_at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let delays: Vec<u64> = (0..8).map(|i| backoff_delay(i).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff_delay(u32::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn fault_backoff_reconnect() {
+        let mut link = Link::default();
+        link.fault("ignored while disconnected".to_string());
+        assert_eq!(link.state, LinkState::Disconnected);
+
+        link.connecting("127.0.0.1:502".to_string());
+        link.connected();
+        link.fault("Broken pipe".to_string());
+        link.fault("Broken pipe again".to_string());
+        link.back_off("Broken pipe".to_string());
+        link.back_off("Connection refused".to_string());
+        assert_eq!(
+            link.state,
+            LinkState::Backoff {
+                attempt: 2,
+                delay_ms: 2000,
+                error: "Connection refused".to_string()
+            }
+        );
+        assert!(!link.retry_due());
+        link.connecting("127.0.0.1:502".to_string());
+        link.connected();
+        let states: Vec<&LinkState> = link.log.iter().map(|t| &t.state).collect();
+        assert_eq!(states.len(), 7);
+        assert_eq!(
+            states[2],
+            &LinkState::Faulted {
+                error: "Broken pipe".to_string()
+            }
+        );
+        // A new outage starts from the shortest delay again.
+        link.back_off("Reset".to_string());
+        assert!(matches!(
+            link.state,
+            LinkState::Backoff {
+                attempt: 1,
+                delay_ms: 1000,
+                ..
+            }
+        ));
+    }
+}
@@ -1,43 +1,128 @@
-use axum::extract::{Form, State};
+use axum::extract::{ConnectInfo, Form, State};
+use axum::Extension;
 use maud::{html, Markup};
 use std::sync::Arc;
 use std::time::Duration;
-use std::time::Instant;
-use tokio::sync::Mutex;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Mutex};
 use tokio_modbus::prelude::*;
-use tokio_serial::SerialStream;
+use tokio_serial::{DataBits, Parity, SerialStream};
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use tokio_modbus::client::Context;
 use tokio_modbus::FunctionCode;
+use utoipa::ToSchema;
 
-const MARGIN: usize = 20;
-const WINDOW_WIDTH: usize = 400;
+use crate::alarms::Alarms;
+use crate::audit::{client_origin, AuditEntry, AuditLog};
+use crate::auth::{may_unlock_writes, CurrentUser};
+use crate::gateway::Gateway;
+use crate::historian::{self, now_millis, Historian, SharedHistorian};
+use crate::mqtt::{Batch, MqttFeed};
+use crate::notify::{DeliveryLog, NotificationEvent, Notifier};
+use crate::project::Project;
+use crate::stats::{monitor, SharedStats};
+use crate::trend::TrendBuffer;
+
+mod ascii;
+mod link;
+mod pdu;
+mod poller;
+mod tags;
+mod tls;
+mod udp;
+pub use ascii::*;
+pub use link::*;
+pub use poller::*;
+pub use tags::*;
+pub use tls::*;
+pub use udp::*;
+
+pub(crate) const MARGIN: usize = 20;
+pub(crate) const WINDOW_WIDTH: usize = 400;
 const TABLE_HEIGHT: usize = 180;
-const TABLE_WIDTH: usize = WINDOW_WIDTH - 20;
+pub(crate) const TABLE_WIDTH: usize = WINDOW_WIDTH - 20;
 const TABLE_COL_WIDTH: usize = TABLE_WIDTH / 3;
 
 const STATUS_BAR_FIELD_WIDTH: usize = (WINDOW_WIDTH - 15) / 2;
 
 const SERIAL_TIMEOUT: u64 = 2; // 2 seconds timeout for the serial port.
+const LIVE_EVENT_CAPACITY: usize = 64;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ModbusSerialForm {
     pub com: String,
     pub baudrate: u32,
     pub slave: u8,
+    #[serde(default)]
+    pub framing: SerialFraming,
+    /// Rejects every write while connected with these settings.
+    #[serde(default)]
+    pub read_only: bool,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SerialFraming {
+    #[default]
+    Rtu,
+    /// `:` prefixed hex frames with LRC, sent as 7 data bits with even parity.
+    Ascii,
+}
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
 pub struct ModbusTcpForm {
     pub address: String,
     pub port: usize,
+    #[serde(default)]
+    pub framing: TcpFraming,
+    /// Only used with RTU framing, MBAP carries the unit id itself.
+    #[serde(default = "default_slave")]
+    pub slave: u8,
+    // TLS only, see `TlsSettings`.
+    #[serde(default)]
+    pub ca_file: String,
+    #[serde(default)]
+    pub cert_file: String,
+    #[serde(default)]
+    pub key_file: String,
+    #[serde(default)]
+    pub server_name: String,
+    #[serde(default)]
+    pub skip_server_name: bool,
+    /// Rejects every write while connected with these settings.
+    #[serde(default)]
+    pub read_only: bool,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpFraming {
+    /// Modbus TCP.
+    #[default]
+    Mbap,
+    /// Raw RTU frames with CRC, as sent by serial-to-Ethernet converters.
+    Rtu,
+    /// Modbus TCP frames sent as UDP datagrams.
+    Udp,
+    /// Modbus/TCP Security, MBAP over mutual TLS, usually on port 802.
+    Tls,
+}
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct ModbusWriteForm {
     pub register: u16,
     pub write_function: String,
     pub float32: String,
-    pub value: u16,
+    pub value: f64,
+    /// Read the value back after writing it.
+    #[serde(default)]
+    pub verify: Option<String>,
+    /// Show the current and the new value before writing.
+    #[serde(default)]
+    pub confirm: Option<String>,
+    #[serde(default)]
+    pub confirmed: Option<String>,
 }
 #[derive(Serialize, Deserialize)]
 pub struct ModbusPollingForm {
@@ -56,10 +141,164 @@ pub struct ModbusSerialFormInput {
     pub function: String,
     pub float32: String,
 }
+/// Where the active connection goes to, kept so it can be listed and reopened.
+/// `read_only` rejects every write while connected.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConnectionSettings {
+    Tcp {
+        address: String,
+        port: usize,
+        #[serde(default)]
+        read_only: bool,
+    },
+    Serial {
+        com: String,
+        baudrate: u32,
+        slave: u8,
+        #[serde(default)]
+        read_only: bool,
+    },
+    RtuOverTcp {
+        address: String,
+        port: usize,
+        slave: u8,
+        #[serde(default)]
+        read_only: bool,
+    },
+    SerialAscii {
+        com: String,
+        baudrate: u32,
+        slave: u8,
+        #[serde(default)]
+        read_only: bool,
+    },
+    Udp {
+        address: String,
+        port: usize,
+        #[serde(default)]
+        read_only: bool,
+    },
+    Tls {
+        address: String,
+        port: usize,
+        #[serde(default)]
+        tls: TlsSettings,
+        #[serde(default)]
+        read_only: bool,
+    },
+}
+
+fn default_slave() -> u8 {
+    1
+}
+
+#[derive(Serialize, Deserialize, ToSchema, ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    #[default]
+    Int16,
+    F32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct WriteRequest {
+    /// 5 (single coil), 6 (single register) or 16 (multiple registers, for `f32`).
+    pub function: u8,
+    pub register: u16,
+    pub value: f64,
+    #[serde(default)]
+    pub data_type: DataType,
+    /// Read the target back after writing and fail if it differs.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+pub enum ReadError {
+    Exception(String),
+    Transport(String),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Exception(e) => write!(f, "Exception: {}", e),
+            ReadError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 pub struct ModbusState {
     pub context: Option<Context>,
+    pub connection: Option<ConnectionSettings>,
     pub poll_time: Option<Duration>,
     pub protocol_options: ProtocolOpts,
+    pub historian: Option<SharedHistorian>,
+    /// Records every write attempt when open.
+    pub audit: Option<Arc<AuditLog>>,
+    pub last_poll: Option<PollSnapshot>,
+    pub events: broadcast::Sender<LiveEvent>,
+    pub project: Project,
+    /// Where the project was last loaded from or saved to.
+    pub project_path: Option<PathBuf>,
+    pub gateway: Option<Gateway>,
+    /// Certificate details of a TLS connection.
+    pub tls_peer: Option<TlsPeer>,
+    pub link: Link,
+    /// Shared with the connection, which counts every request.
+    pub stats: SharedStats,
+    pub alarms: Alarms,
+    /// For events that are neither alarms nor connection changes.
+    pub notifier: Notifier,
+    pub deliveries: DeliveryLog,
+    pub mqtt: MqttFeed,
+    /// Recent polled values for the trend page.
+    pub trend: TrendBuffer,
+}
+
+impl ModbusState {
+    pub fn new(protocol_options: ProtocolOpts, historian: Option<Historian>) -> Self {
+        let (events, _) = broadcast::channel(LIVE_EVENT_CAPACITY);
+        ModbusState {
+            context: None,
+            connection: None,
+            poll_time: None,
+            protocol_options,
+            historian: historian.map(|historian| Arc::new(std::sync::Mutex::new(historian))),
+            audit: None,
+            last_poll: None,
+            events,
+            project: Project::default(),
+            project_path: None,
+            gateway: None,
+            tls_peer: None,
+            link: Link::default(),
+            stats: SharedStats::default(),
+            alarms: Alarms::default(),
+            notifier: Notifier::default(),
+            deliveries: DeliveryLog::default(),
+            mqtt: MqttFeed::default(),
+            trend: TrendBuffer::default(),
+        }
+    }
+
+    /// Whether the settings of the active connection reject writes.
+    pub fn writes_locked(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(ConnectionSettings::read_only)
+    }
+
+    /// Locks or unlocks writes for the active connection, false without one.
+    pub fn lock_writes(&mut self, read_only: bool) -> bool {
+        match &mut self.connection {
+            Some(settings) => {
+                *settings.read_only_mut() = read_only;
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 pub struct ProtocolOpts {
@@ -69,70 +308,574 @@ pub struct ProtocolOpts {
     pub float32: bool,
 }
 
-pub async fn connect_modbus_tcp(
-    State(mtx): State<Arc<Mutex<ModbusState>>>,
-    Form(form_input): Form<ModbusTcpForm>,
-) -> Markup {
-    println!("{}:{}", &form_input.address, &form_input.port);
+impl Default for ModbusTcpForm {
+    fn default() -> Self {
+        ModbusTcpForm {
+            address: "127.0.0.1".to_string(),
+            port: 5502,
+            framing: TcpFraming::default(),
+            slave: default_slave(),
+            ca_file: String::new(),
+            cert_file: String::new(),
+            key_file: String::new(),
+            server_name: String::new(),
+            skip_server_name: false,
+            read_only: false,
+        }
+    }
+}
+
+impl Default for ModbusSerialForm {
+    fn default() -> Self {
+        ModbusSerialForm {
+            com: "COM4".to_string(),
+            baudrate: 9600,
+            slave: 1,
+            framing: SerialFraming::default(),
+            read_only: false,
+        }
+    }
+}
+
+impl Default for ProtocolOpts {
+    fn default() -> Self {
+        ProtocolOpts {
+            function_code: FunctionCode::ReadHoldingRegisters,
+            start_register: 1,
+            count: 5,
+            float32: false,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionSettings::Tcp { address, port, .. } => write!(f, "TCP {}:{}", address, port),
+            ConnectionSettings::Serial { com, baudrate, .. } => {
+                write!(f, "RTU {} {}", com, baudrate)
+            }
+            ConnectionSettings::RtuOverTcp { address, port, .. } => {
+                write!(f, "RTU over TCP {}:{}", address, port)
+            }
+            ConnectionSettings::SerialAscii { com, baudrate, .. } => {
+                write!(f, "ASCII {} {}", com, baudrate)
+            }
+            ConnectionSettings::Udp { address, port, .. } => write!(f, "UDP {}:{}", address, port),
+            ConnectionSettings::Tls { address, port, .. } => write!(f, "TLS {}:{}", address, port),
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// Slave addressed when no unit id is given.
+    pub fn default_slave(&self) -> Slave {
+        match self {
+            ConnectionSettings::Tcp { .. }
+            | ConnectionSettings::Udp { .. }
+            | ConnectionSettings::Tls { .. } => Slave::tcp_device(),
+            ConnectionSettings::Serial { slave, .. }
+            | ConnectionSettings::RtuOverTcp { slave, .. }
+            | ConnectionSettings::SerialAscii { slave, .. } => Slave(*slave),
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        match self {
+            ConnectionSettings::Tcp { read_only, .. }
+            | ConnectionSettings::Serial { read_only, .. }
+            | ConnectionSettings::RtuOverTcp { read_only, .. }
+            | ConnectionSettings::SerialAscii { read_only, .. }
+            | ConnectionSettings::Udp { read_only, .. }
+            | ConnectionSettings::Tls { read_only, .. } => *read_only,
+        }
+    }
+
+    fn read_only_mut(&mut self) -> &mut bool {
+        match self {
+            ConnectionSettings::Tcp { read_only, .. }
+            | ConnectionSettings::Serial { read_only, .. }
+            | ConnectionSettings::RtuOverTcp { read_only, .. }
+            | ConnectionSettings::SerialAscii { read_only, .. }
+            | ConnectionSettings::Udp { read_only, .. }
+            | ConnectionSettings::Tls { read_only, .. } => read_only,
+        }
+    }
+
+    /// Same device, the write lock may have changed meanwhile.
+    pub fn same_device(&self, other: &ConnectionSettings) -> bool {
+        let mut other = other.clone();
+        *other.read_only_mut() = self.read_only();
+        *self == other
+    }
 
-    let sock_address = format!("{}:{}", &form_input.address, &form_input.port);
-    let sock_address = sock_address.parse();
-    if let Ok(sock_address) = sock_address {
-        if let Ok(ctx) = tcp::connect(sock_address).await {
-            let mut mtx = mtx.lock().await;
-            mtx.context = Some(ctx);
-            html! {
-                #modbus_connect_content {
-                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: Connected"  }
+    /// Opens the connection, with the negotiated details when it uses TLS.
+    pub async fn connect(&self) -> Result<(Context, Option<TlsPeer>), String> {
+        let context = match self {
+            ConnectionSettings::Tcp { address, port, .. } => {
+                let sock_address = format!("{}:{}", address, port);
+                let Ok(sock_address) = sock_address.parse() else {
+                    return Err("Could not parse the address or port!".to_string());
+                };
+                tcp::connect(sock_address)
+                    .await
+                    .map_err(|_| "Could not connect to slave!".to_string())
+            }
+            ConnectionSettings::Serial {
+                com,
+                baudrate,
+                slave,
+                ..
+            } => {
+                let builder =
+                    tokio_serial::new(com, *baudrate).timeout(Duration::from_secs(SERIAL_TIMEOUT));
+                match SerialStream::open(&builder) {
+                    Ok(port) => Ok(rtu::attach_slave(port, Slave(*slave))),
+                    Err(_) => Err("Could not open port!".to_string()),
                 }
             }
-        } else {
-            let mut mtx = mtx.lock().await;
-            mtx.context = None;
-            html! {
-                #modbus_connect_content {
-                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  "STATUS: Could not connect to slave!"  }
+            ConnectionSettings::RtuOverTcp {
+                address,
+                port,
+                slave,
+                ..
+            } => {
+                let sock_address = format!("{}:{}", address, port);
+                let Ok(sock_address) = sock_address.parse::<SocketAddr>() else {
+                    return Err("Could not parse the address or port!".to_string());
+                };
+                match TcpStream::connect(sock_address).await {
+                    Ok(stream) => Ok(rtu::attach_slave(stream, Slave(*slave))),
+                    Err(_) => Err("Could not connect to slave!".to_string()),
                 }
             }
+            ConnectionSettings::SerialAscii {
+                com,
+                baudrate,
+                slave,
+                ..
+            } => {
+                let builder = tokio_serial::new(com, *baudrate)
+                    .data_bits(DataBits::Seven)
+                    .parity(Parity::Even)
+                    .timeout(Duration::from_secs(SERIAL_TIMEOUT));
+                match SerialStream::open(&builder) {
+                    Ok(port) => Ok(attach_ascii(port, Slave(*slave))),
+                    Err(_) => Err("Could not open port!".to_string()),
+                }
+            }
+            ConnectionSettings::Udp { address, port, .. } => {
+                let sock_address = format!("{}:{}", address, port);
+                let Ok(sock_address) = sock_address.parse() else {
+                    return Err("Could not parse the address or port!".to_string());
+                };
+                connect_udp(sock_address)
+                    .await
+                    .map_err(|_| "Could not open a UDP socket!".to_string())
+            }
+            ConnectionSettings::Tls {
+                address, port, tls, ..
+            } => {
+                let sock_address = format!("{}:{}", address, port);
+                let Ok(sock_address) = sock_address.parse() else {
+                    return Err("Could not parse the address or port!".to_string());
+                };
+                let (ctx, peer) = connect_tls(sock_address, address, tls).await?;
+                return Ok((ctx, Some(peer)));
+            }
+        };
+        context.map(|ctx| (ctx, None))
+    }
+}
+
+/// Replaces the active connection, a failed attempt leaves the state disconnected.
+pub async fn connect(state: &mut ModbusState, settings: ConnectionSettings) -> Result<(), String> {
+    state.link.connecting(settings.to_string());
+    match settings.connect().await {
+        Ok((ctx, tls_peer)) => {
+            state.context = Some(monitor(ctx, &state.stats, &settings));
+            state.connection = Some(settings);
+            state.tls_peer = tls_peer;
+            state.link.connected();
+            Ok(())
         }
+        Err(e) => {
+            state.context = None;
+            state.connection = None;
+            state.tls_peer = None;
+            state.link.disconnected();
+            Err(e)
+        }
+    }
+}
+
+/// Returns `false` when there was no connection to close.
+pub async fn disconnect(state: &mut ModbusState) -> bool {
+    let configured = state.connection.take().is_some();
+    state.tls_peer = None;
+    state.link.disconnected();
+    match state.context.take() {
+        Some(mut ctx) => {
+            let _ = ctx.disconnect().await;
+            true
+        }
+        // Waiting to reconnect.
+        None => configured,
+    }
+}
+
+/// Closes the slave connection, the gateway and the databases once the background tasks stopped.
+pub async fn shut_down(state: &mut ModbusState) {
+    disconnect(state).await;
+    state.gateway = None;
+    state.historian = None;
+    state.audit = None;
+}
+
+pub fn function_code_from(function: u8) -> Option<FunctionCode> {
+    match function {
+        1 => Some(FunctionCode::ReadCoils),
+        3 => Some(FunctionCode::ReadHoldingRegisters),
+        4 => Some(FunctionCode::ReadInputRegisters),
+        _ => None,
+    }
+}
+
+/// Reads `count` items starting at `start_register`, pairing registers when `float32` is set.
+pub async fn read_block(
+    ctx: &mut Context,
+    function_code: FunctionCode,
+    start_register: u16,
+    count: u16,
+    float32: bool,
+) -> Result<PollData, ReadError> {
+    let data = match function_code {
+        FunctionCode::ReadCoils => ctx
+            .read_coils(start_register, count)
+            .await
+            .map(|result| result.map(PollData::Coils)),
+        FunctionCode::ReadInputRegisters => ctx
+            .read_input_registers(start_register, count)
+            .await
+            .map(|result| result.map(|registers| registers_as_poll_data(registers, float32))),
+        _ => ctx
+            .read_holding_registers(start_register, count)
+            .await
+            .map(|result| result.map(|registers| registers_as_poll_data(registers, float32))),
+    };
+    match data {
+        Ok(Ok(data)) => Ok(data),
+        Ok(Err(e)) => Err(ReadError::Exception(format!("{:?}", e))),
+        Err(e) => Err(ReadError::Transport(format!("{:?}", e))),
+    }
+}
+
+fn registers_as_poll_data(registers: Vec<u16>, float32: bool) -> PollData {
+    if float32 && registers.len() >= 2 {
+        PollData::Floats(registers_as_floats(&registers))
     } else {
-        html! {
-            #modbus_connect_content {
-                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  "STATUS: Could not parse the address or port!"  }
+        PollData::Registers(registers)
+    }
+}
+
+/// Single write path shared by the UI, the API, MQTT and the CLI, returns the message to show.
+///
+/// Every attempt goes to the audit log with `origin`, the client IP or the source.
+/// Nothing is written while the audit log can't take the entry.
+pub async fn write_value(
+    state: &mut ModbusState,
+    request: &WriteRequest,
+    origin: &str,
+) -> Result<String, String> {
+    if !request.value.is_finite() {
+        return Err("Bad input!".to_string());
+    }
+    let Some(audit) = state.audit.as_ref() else {
+        return Err("Writes are refused, the audit log is not available.".to_string());
+    };
+    let pending = match audit.begin() {
+        Ok(pending) => pending,
+        Err(e) => return Err(format!("Writes are refused, the audit log: {}", e)),
+    };
+    let old_value = polled_value(state, request);
+    let result = try_write(state, request).await;
+    let connection = state.connection.as_ref();
+    let entry = AuditEntry {
+        timestamp: now_millis(),
+        connection: connection.map(|s| s.to_string()).unwrap_or_default(),
+        unit_id: connection.map(|s| s.default_slave().0),
+        function: request.function,
+        register: request.register,
+        old_value,
+        new_value: request.value,
+        success: result.is_ok(),
+        result: match &result {
+            Ok(message) => message.clone(),
+            Err(e) => e.clone(),
+        },
+        origin: origin.to_string(),
+    };
+    // The device has the value either way, the missing entry goes to the sinks.
+    if let Err(e) = pending.commit(&entry) {
+        state.notifier.notify(NotificationEvent::Audit {
+            error: e.to_string(),
+            entry,
+        });
+    }
+    result
+}
+
+/// Value of the write target in the last poll, if that poll covered it.
+fn polled_value(state: &ModbusState, request: &WriteRequest) -> Option<f64> {
+    let snapshot = state.last_poll.as_ref()?;
+    let read_function = if request.function == 5 { 1 } else { 3 };
+    let float32 = request.function != 5 && request.data_type == DataType::F32;
+    let (step, values) = snapshot.data.samples();
+    if snapshot.function != read_function || (step == 2) != float32 {
+        return None;
+    }
+    let offset = request.register.checked_sub(snapshot.start_register)?;
+    if offset % step != 0 {
+        return None;
+    }
+    values.get((offset / step) as usize).copied()
+}
+
+async fn try_write(state: &mut ModbusState, request: &WriteRequest) -> Result<String, String> {
+    if state.writes_locked() {
+        return Err("Writes are locked for this connection.".to_string());
+    }
+    let Some(ctx) = state.context.as_mut() else {
+        return Err("STATUS: There is no connection!".to_string());
+    };
+    let result = match (request.function, request.data_type) {
+        (6, DataType::Int16) => {
+            if request.value.fract() != 0.0 {
+                return Err("Only whole numbers accepted!".to_string());
+            }
+            if request.value < 0.0 || request.value > u16::MAX as f64 {
+                return Err("Value out of range!".to_string());
             }
+            ctx.write_single_register(request.register, request.value as u16)
+                .await
+        }
+        (5, _) => {
+            let coil = if request.value == 1.0 {
+                true
+            } else if request.value == 0.0 {
+                false
+            } else {
+                return Err("Only 1 or 0 values accepted!".to_string());
+            };
+            ctx.write_single_coil(request.register, coil).await
+        }
+        (6 | 16, DataType::F32) => {
+            let bits = (request.value as f32).to_bits();
+            let registers = [(bits >> 16) as u16, bits as u16];
+            ctx.write_multiple_registers(request.register, &registers)
+                .await
+        }
+        _ => return Err("Bad input!".to_string()),
+    };
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => return Err(format!("{:?}", e)),
+        Err(e) => {
+            let e = format!("{:?}", e);
+            state.link.fault(e.clone());
+            return Err(e);
+        }
+    }
+    let message = format!("Wrote: {} to {}", request.value, write_target(request));
+    if !request.verify {
+        return Ok(message);
+    }
+    let expected = written_value(request);
+    match read_target(state, request).await {
+        Ok(value) if value == expected => Ok(format!("{}, verified", message)),
+        Ok(value) => Err(format!("{}, but read back {}", message, value)),
+        Err(e) => Err(format!("{}, but could not read it back: {}", message, e)),
+    }
+}
+
+fn write_target(request: &WriteRequest) -> String {
+    match request.function {
+        5 => format!("Coil: {}", request.register),
+        _ => format!("H-Register: {}", request.register),
+    }
+}
+
+/// The value a device holds after accepting `request`.
+fn written_value(request: &WriteRequest) -> f64 {
+    match (request.function, request.data_type) {
+        (5, _) => (request.value as i64 == 1) as u8 as f64,
+        (_, DataType::F32) => request.value as f32 as f64,
+        _ => request.value as u16 as f64,
+    }
+}
+
+/// Reads the coil or register(s) a write goes to, decoded the same way.
+pub async fn read_target(state: &mut ModbusState, request: &WriteRequest) -> Result<f64, String> {
+    let Some(ctx) = state.context.as_mut() else {
+        return Err("STATUS: There is no connection!".to_string());
+    };
+    let (function_code, count, float32) = match (request.function, request.data_type) {
+        (5, _) => (FunctionCode::ReadCoils, 1, false),
+        (_, DataType::F32) => (FunctionCode::ReadHoldingRegisters, 2, true),
+        _ => (FunctionCode::ReadHoldingRegisters, 1, false),
+    };
+    match read_block(ctx, function_code, request.register, count, float32).await {
+        Ok(data) => data
+            .samples()
+            .1
+            .first()
+            .copied()
+            .ok_or_else(|| "Nothing was read.".to_string()),
+        Err(ReadError::Transport(e)) => {
+            state.link.fault(e.clone());
+            Err(e)
         }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The connect form of the TCP page, answers with the status bar.
+#[utoipa::path(
+    post,
+    path = "/connect_modbus_tcp",
+    request_body(content = ModbusTcpForm, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, description = "Connection status", body = String, content_type = "text/html"))
+)]
+pub async fn connect_modbus_tcp(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    user: Option<Extension<CurrentUser>>,
+    Form(mut form_input): Form<ModbusTcpForm>,
+) -> Markup {
+    println!("{}:{}", &form_input.address, &form_input.port);
+
+    let mut mtx = mtx.lock().await;
+    // Reconnecting doesn't lift a lock either.
+    if !may_unlock_writes(user.as_deref()) {
+        form_input.read_only |= mtx.project.tcp.read_only || mtx.writes_locked();
     }
+    let read_only = form_input.read_only;
+    let settings = match form_input.framing {
+        TcpFraming::Mbap => ConnectionSettings::Tcp {
+            address: form_input.address.clone(),
+            port: form_input.port,
+            read_only,
+        },
+        TcpFraming::Rtu => ConnectionSettings::RtuOverTcp {
+            address: form_input.address.clone(),
+            port: form_input.port,
+            slave: form_input.slave,
+            read_only,
+        },
+        TcpFraming::Udp => ConnectionSettings::Udp {
+            address: form_input.address.clone(),
+            port: form_input.port,
+            read_only,
+        },
+        TcpFraming::Tls => ConnectionSettings::Tls {
+            address: form_input.address.clone(),
+            port: form_input.port,
+            tls: TlsSettings {
+                ca_file: form_input.ca_file.trim().to_string(),
+                cert_file: form_input.cert_file.trim().to_string(),
+                key_file: form_input.key_file.trim().to_string(),
+                server_name: form_input.server_name.trim().to_string(),
+                skip_server_name: form_input.skip_server_name,
+            },
+            read_only,
+        },
+    };
+    mtx.project.tcp = form_input;
+    let result = connect(&mut mtx, settings).await;
+    connect_status(result, mtx.tls_peer.as_ref())
 }
 
 pub async fn connect_modbus_serial(
     State(mtx): State<Arc<Mutex<ModbusState>>>,
-    Form(form_input): Form<ModbusSerialForm>,
+    user: Option<Extension<CurrentUser>>,
+    Form(mut form_input): Form<ModbusSerialForm>,
 ) -> Markup {
     println!("{}:{}", &form_input.com, &form_input.baudrate);
 
-    //let sock_address = format!("{}:{}", &form_input.address, &form_input.port);
-    let slave = Slave(form_input.slave);
-    let builder = tokio_serial::new(&form_input.com, form_input.baudrate)
-        .timeout(Duration::from_secs(SERIAL_TIMEOUT));
-    let port = SerialStream::open(&builder);
+    let mut mtx = mtx.lock().await;
+    if !may_unlock_writes(user.as_deref()) {
+        form_input.read_only |= mtx.project.serial.read_only || mtx.writes_locked();
+    }
+    let settings = match form_input.framing {
+        SerialFraming::Rtu => ConnectionSettings::Serial {
+            com: form_input.com.clone(),
+            baudrate: form_input.baudrate,
+            slave: form_input.slave,
+            read_only: form_input.read_only,
+        },
+        SerialFraming::Ascii => ConnectionSettings::SerialAscii {
+            com: form_input.com.clone(),
+            baudrate: form_input.baudrate,
+            slave: form_input.slave,
+            read_only: form_input.read_only,
+        },
+    };
+    mtx.project.serial = form_input;
+    connect_status(connect(&mut mtx, settings).await, None)
+}
 
-    if let Ok(port) = port {
-        let ctx = rtu::attach_slave(port, slave);
-        let mut mtx = mtx.lock().await;
-        mtx.context = Some(ctx);
-        html! {
-            #modbus_connect_content {
-                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: Connected"  }
-            }
-        }
-    } else {
-        html! {
-            #modbus_connect_content {
-                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  "STATUS: Could not open port!"  }
+fn connect_status(result: Result<(), String>, tls_peer: Option<&TlsPeer>) -> Markup {
+    html! {
+        #modbus_connect_content {
+            @match result {
+                Ok(()) => (connection_status_field(&LinkState::Connected, tls_peer)),
+                Err(e) => {
+                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+                        (format!("STATUS: {}", e))
+                    }
+                }
             }
         }
     }
 }
+
+/// Historian tag name for a polled register, e.g. `HR1` or `IR10`.
+pub fn register_tag(function_code: &FunctionCode, register: u16) -> String {
+    match function_code {
+        FunctionCode::ReadCoils => format!("C{}", register),
+        FunctionCode::ReadInputRegisters => format!("IR{}", register),
+        _ => format!("HR{}", register),
+    }
+}
+
+fn record_history(state: &mut ModbusState, unit_id: Option<u8>, snapshot: &PollSnapshot) {
+    let values = history_values(&state.project.tags, unit_id, snapshot);
+    if !values.is_empty() {
+        record_values(state, &values);
+    }
+}
+
+fn record_values(state: &mut ModbusState, values: &[(String, f64)]) {
+    let now = now_millis();
+    state.trend.record(now, values);
+    if let Some(historian) = state.historian.as_ref() {
+        historian::record(historian, now, values);
+    }
+    state.alarms.evaluate(&state.project.alarms, values, now);
+    if !state.mqtt.is_running() {
+        return;
+    }
+    state.mqtt.publish(Batch {
+        timestamp: now,
+        connection: state
+            .connection
+            .as_ref()
+            .map(|settings| settings.to_string())
+            .unwrap_or_default(),
+        project: state.project.name.clone(),
+        values: values.to_vec(),
+    });
+}
+
 pub fn double_register_as_float(reg1: u16, reg2: u16) -> f32 {
     let data_32bit_rep = ((reg1 as u32) << 16) | reg2 as u32;
     let data_32_array = data_32bit_rep.to_ne_bytes();
@@ -141,62 +884,70 @@ pub fn double_register_as_float(reg1: u16, reg2: u16) -> f32 {
 
 pub async fn disconnect_modbus(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
     let mut mtx = mtx.lock().await;
-    match mtx.context.as_mut() {
-        Some(ctx) => match ctx.disconnect().await {
-            Ok(_ctx) => {
-                mtx.context = None;
-                html! {
-                    #modbus_connect_content {
-                            p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: Disconnected"  }
-                    }
-                }
-            }
-            _ => {
-                mtx.context = None;
-                html! {
-                    #modbus_connect_content {
-                            p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: Disconnected"  }
-                    }
-                }
+    if disconnect(&mut mtx).await {
+        html! {
+            #modbus_connect_content {
+                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: Disconnected"  }
             }
-        },
-        None => {
-            html! {
-                #modbus_connect_content {
-                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: "  }
-                }
+        }
+    } else {
+        html! {
+            #modbus_connect_content {
+                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: "  }
             }
         }
     }
 }
 
 pub async fn heartbeat(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
-    match mtx.lock().await.poll_time {
-        Some(time) => {
-            html! {
-                #heartbeat {
-                    div hx-get="/heartbeat" hx-trigger="load delay:1s" hx-target="#heartbeat" hx-swap="innerHTML" {
-                       p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
-                           (format!("SCANTIME:  {}   micros", time.as_micros()))
-                       }
-                    }
-                }
+    let poll_time = mtx.lock().await.poll_time;
+    html! {
+        #heartbeat {
+            div hx-get="/heartbeat" hx-trigger="load delay:1s" hx-target="#heartbeat" hx-swap="innerHTML" {
+               (heartbeat_field(poll_time))
             }
         }
-        None => {
-            html! {
-                #heartbeat {
-                    div hx-get="/heartbeat" hx-trigger="load delay:1s" hx-target="#heartbeat" hx-swap="innerHTML" {
-                       p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){
-                           "SCANTIME: "
-                       }
-                    }
+    }
+}
+
+pub fn heartbeat_field(poll_time: Option<Duration>) -> Markup {
+    html! {
+        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+            @match poll_time {
+                Some(time) => (format!("SCANTIME:  {}   micros", time.as_micros())),
+                None => "SCANTIME: ",
+            }
+        }
+    }
+}
+
+pub fn alarm_status_field(active: usize, unacknowledged: usize) -> Markup {
+    html! {
+        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+            @if unacknowledged == 0 && active == 0 {
+                "ALARMS: None"
+            } @else {
+                a href="/alarms" style=(if unacknowledged > 0 { "color: red" } else { "" }) {
+                    (format!("ALARMS: {} active, {} unacknowledged", active, unacknowledged))
                 }
             }
         }
     }
 }
 
+pub fn connection_status_field(link: &LinkState, tls_peer: Option<&TlsPeer>) -> Markup {
+    html! {
+        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+            (format!("STATUS: {}", link))
+        }
+        @if let (LinkState::Connected, Some(peer)) = (link, tls_peer) {
+            p class="status-bar-field" title=(format!("{}, serial {}", peer.cipher_suite, peer.serial)) {
+                (format!("TLS: {}", peer))
+            }
+        }
+    }
+}
+
 pub async fn update_modbus(
     State(mtx): State<Arc<Mutex<ModbusState>>>,
     Form(form_input): Form<ModbusPollingForm>,
@@ -265,542 +1016,218 @@ pub async fn update_modbus(
     }
 }
 
+/// The write form, answers with the outcome or a confirmation prompt.
+#[utoipa::path(
+    post,
+    path = "/write_modbus",
+    request_body(content = ModbusWriteForm, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, description = "Write outcome", body = String, content_type = "text/html"))
+)]
 pub async fn write_modbus(
     State(mtx): State<Arc<Mutex<ModbusState>>>,
+    info: Option<ConnectInfo<SocketAddr>>,
+    user: Option<Extension<CurrentUser>>,
     Form(form_input): Form<ModbusWriteForm>,
 ) -> Markup {
-    let mtx = mtx.clone();
-    let mut res = mtx.lock().await;
-
-    match res.context.as_mut() {
-        Some(ctx) => match form_input.write_function.as_str() {
-            "6" => {
-                let res = ctx
-                    .write_single_register(form_input.register, form_input.value)
-                    .await;
-                match res {
-                    Ok(res) => match res {
-                        Ok(_) => {
-                            html! {
-                                #modbus_connect_content {
-                                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("Wrote: {} to H-Register: {}", form_input.value, form_input.register))  }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            html! {
-                                #modbus_connect_content {
-                                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("{:?}", e))  }
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        html! {
-                            #modbus_connect_content {
-                                    p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("{:?}", e))  }
-                            }
-                        }
-                    }
+    let request = WriteRequest {
+        function: form_input.write_function.parse().unwrap_or(0),
+        register: form_input.register,
+        value: form_input.value,
+        data_type: match form_input.float32.as_str() {
+            "f32" => DataType::F32,
+            _ => DataType::Int16,
+        },
+        verify: form_input.verify.is_some(),
+    };
+    let mut mtx = mtx.lock().await;
+    let unconfirmed = form_input.confirm.is_some() && form_input.confirmed.is_none();
+    if unconfirmed && mtx.context.is_some() && !mtx.writes_locked() {
+        let current = read_target(&mut mtx, &request).await;
+        return write_confirmation(&mtx, &form_input, &request, current);
+    }
+    let origin = client_origin(info, user.as_deref());
+    let message = match write_value(&mut mtx, &request, &origin).await {
+        Ok(message) => message,
+        Err(e) => e,
+    };
+    html! {
+        #modbus_connect_content {
+                p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (message)  }
+        }
+    }
+}
+
+/// Asks before writing, the Confirm button resends the form with `confirmed` set.
+fn write_confirmation(
+    state: &ModbusState,
+    form_input: &ModbusWriteForm,
+    request: &WriteRequest,
+    current: Result<f64, String>,
+) -> Markup {
+    let read_function = if request.function == 5 { 1 } else { 3 };
+    let tag = state
+        .project
+        .tags
+        .iter()
+        .find(|tag| tag.function == read_function && tag.register == request.register);
+    let mut values = serde_json::json!({
+        "register": form_input.register,
+        "write_function": form_input.write_function,
+        "float32": form_input.float32,
+        "value": form_input.value,
+        "confirmed": "true",
+    });
+    if form_input.verify.is_some() {
+        values["verify"] = "true".into();
+    }
+    html! {
+        #modbus_connect_content {
+            p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+                "WRITE TO: " (write_target(request))
+                @if let Some(tag) = tag { " (" (tag.name) ")" }
+            }
+            p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+                @match current {
+                    Ok(value) => (format!("CURRENT: {}", value)),
+                    Err(e) => (format!("CURRENT: unknown, {}", e)),
                 }
             }
-            "5" => match form_input.value {
-                1 => {
-                    let res = ctx.write_single_coil(form_input.register, true).await;
-                    match res {
-                        Ok(_) => {
-                            html! {
-                                #modbus_connect_content {
-                                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("Wrote: {} to Coil: {}", form_input.value, form_input.register))  }
-                                }
-                            }
+            p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
+                (format!("NEW: {}", written_value(request)))
+            }
+            div class="field-row" {
+                button hx-post="/write_modbus" hx-vals=(values.to_string()) hx-target="#modbus_connect_content" hx-swap="innerHTML" { "Confirm" }
+                button hx-get="/cancel_write" hx-target="#modbus_connect_content" hx-swap="innerHTML" { "Cancel" }
+            }
+        }
+    }
+}
+
+pub async fn cancel_write() -> Markup {
+    html! {
+        #modbus_connect_content {
+                p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS: Write cancelled"  }
+        }
+    }
+}
+
+/// Write form shared by the TCP and serial pages.
+fn write_options(state: &ModbusState) -> Markup {
+    let locked = state.writes_locked();
+    html! {
+        form hx-post="/write_modbus" hx-target="#modbus_connect_content" hx-swap="innerHTML" {
+            fieldset {
+                legend { "Write Options" }
+                details {
+                    summary { "Show" }
+                    div class="field-row-stacked" style="width: 200px" {
+                        label for="write_function" { "Function Code: " }
+                        select name="write_function" id="write_function" {
+                            option value="6" { "0x06-Write Holding Register" }
+                            option value="5" { "0x05-Write Coil" }
                         }
-                        Err(e) => {
-                            html! {
-                                #modbus_connect_content {
-                                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("{:?}", e))  }
-                                }
-                            }
+                        label for="register" { "Register: " }
+                        input type="number" id="register" name="register" value="1" {}
+                        label for="float32" { "Use double registers as float: " }
+                        select name="float32" id="function" {
+                            option value="int16" { "16 bit integer" }
+                            option value="f32" { "32 bit float" }
                         }
+                        label for="value" { "Value: " }
+                        input type="number" step="any" id="value" name="value" value="1" {}
                     }
-                }
-                0 => {
-                    let res = ctx.write_single_coil(form_input.register, false).await;
-                    match res {
-                        Ok(_) => {
-                            html! {
-                                #modbus_connect_content {
-                                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("Wrote: {} to Coil: {}", form_input.value, form_input.register))  }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            html! {
-                                #modbus_connect_content {
-                                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  (format!("{:?}", e))  }
-                                }
-                            }
-                        }
+                    div class="field-row" {
+                        input type="checkbox" id="confirm" name="confirm" value="true" checked {}
+                        label for="confirm" { "Ask for confirmation" }
                     }
-                }
-                _ => {
-                    html! {
-                        #modbus_connect_content {
-                                p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){ "Only 1 or 0 values accepted!"  }
-                        }
+                    div class="field-row" {
+                        input type="checkbox" id="verify" name="verify" value="true" {}
+                        label for="verify" { "Read back and verify" }
                     }
-                }
-            },
-            _ => {
-                html! {
-                    #modbus_connect_content {
-                            p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){ "Bad input!"  }
+                    div class="field-row" {
+                        @if locked {
+                            button type="submit" disabled { "Write" }
+                            p { "Writes are locked for this connection." }
+                        } @else {
+                            button type="submit" { "Write" }
+                        }
                     }
                 }
             }
-        },
-        None => {
-            html! {
-                #modbus_connect_content {
-                        p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)){  "STATUS: There is no connection!"  }
-                }
-            }
         }
     }
 }
+
+/// Renders the last values read by the poller, it never issues a read itself.
 pub async fn poll_modbus(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
-    let mtx = mtx.clone();
-    let mut res = mtx.lock().await;
-    let now = Instant::now();
-    let function_code = res.protocol_options.function_code.clone();
-    let start_register = res.protocol_options.start_register;
-    let count = res.protocol_options.count;
-    let float32 = res.protocol_options.float32;
-    match res.context.as_mut() {
-        Some(ctx) => match function_code {
-            FunctionCode::ReadInputRegisters => {
-                let result = ctx.read_input_registers(start_register, count).await;
-                match result {
-                    Ok(result) => match result {
-                        Ok(result) => {
-                            if float32 && result.len() >= 2 {
-                                let mut buff: Vec<f32> = Vec::new();
-                                let result_copy = result.clone();
-                                let mut i = 0;
-                                while i < (result_copy.len() - 1) {
-                                    buff.push(double_register_as_float(
-                                        result_copy[i],
-                                        result_copy[i + 1],
-                                    ));
-                                    i += 2;
-                                }
-                                res.poll_time = Some(now.elapsed());
-                                html! {
-                                     #modbus_table {
-                                        div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                                            table class="interactive" {
-                                                thead {
-                                                    tr {
-                                                        th {
-                                                            "Register"
-                                                        }
-                                                        th { "Value" }
-                                                        th { "Value (HEX)" }
-                                                    }
-                                                }
-                                                tbody {
-                                                    @for (i, value) in buff.iter().enumerate() {
-                                                        tr {
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (start_register as usize + (i * 2)) }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    (format!("{:.2}", value))
-                                                                }
-                                                            }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    ""
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            } else {
-                                res.poll_time = Some(now.elapsed());
-                                html! {
-                                     #modbus_table {
-                                        div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                                            table class="interactive" {
-                                                thead {
-                                                    tr {
-                                                        th {
-                                                            "Register"
-                                                        }
-                                                        th { "Value" }
-                                                        th { "Value (HEX)" }
-                                                    }
-                                                }
-                                                tbody {
-                                                    @for (i, value) in result.iter().enumerate() {
-                                                        tr {
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (start_register as usize + i) }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    (format!("{}", value))
-                                                                }
-                                                            }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    (format!("{:#06X}", value))
-                                                                }
-                                                            }
-                                                        }
-
-                                                    }
-                                                }
+    let mtx = mtx.lock().await;
+    html! {
+         #modbus_table {
+            div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
+                (modbus_table(mtx.last_poll.as_ref()))
+            }
+        }
+    }
+}
 
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            html! {
-                                 #modbus_table {
-                                    div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-                                        table class="interactive" {
-                                            thead {
-                                                tr {
-                                                    th {
-                                                        "Register"
-                                                    }
-                                                    th { "Value" }
-                                                    th { "Value (HEX)" }
-                                                }
-                                            }
-                                            tbody {
-                                                tr {
-                                                    td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                        (format!("{:?}", e))
-
-                                                    }
-                                                    td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                    }
-                                                    td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        res.poll_time = None;
-                        html! {
-                            #modbus_table {
-                               div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus" hx-swap="innerHTML" {
-
-                                   table class="interactive" {
-                                       thead {
-                                           tr {
-                                               th {
-                                                   "Register"
-                                               }
-                                               th { "Value" }
-                                           }
-                                       }
-                                       tbody {
-                                           tr {
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) { "0" }
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                   {
-                                                       p { (format!("{:?}", e)) }
-                                                   }
-                                               }
-                                           }
-                                       }
-
-                                   }
-                               }
-                           }
-                        }
+pub fn modbus_table(snapshot: Option<&PollSnapshot>) -> Markup {
+    html! {
+        table class="interactive" {
+            thead {
+                tr {
+                    th {
+                        "Register"
                     }
+                    th { "Value" }
+                    th { "Value (HEX)" }
                 }
             }
-            FunctionCode::ReadHoldingRegisters => {
-                let result = ctx.read_holding_registers(start_register, count).await;
-                match result {
-                    Ok(result) => match result {
-                        Ok(result) => {
-                            if float32 && result.len() >= 2 {
-                                let mut buff: Vec<f32> = Vec::new();
-                                let result_copy = result.clone();
-                                let mut i = 0;
-                                while i < (result_copy.len() - 1) {
-                                    buff.push(double_register_as_float(
-                                        result_copy[i],
-                                        result_copy[i + 1],
-                                    ));
-                                    i += 2;
+            tbody {
+                @match snapshot {
+                    Some(snapshot) => {
+                        @match &snapshot.data {
+                            PollData::Registers(values) => {
+                                @for (i, value) in values.iter().enumerate() {
+                                    (modbus_table_row(snapshot.start_register as usize + i, &value.to_string(), &format!("{:#06X}", value)))
                                 }
-                                res.poll_time = Some(now.elapsed());
-                                html! {
-                                     #modbus_table {
-                                        div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                                            table class="interactive" {
-                                                thead {
-                                                    tr {
-                                                        th {
-                                                            "Register"
-                                                        }
-                                                        th { "Value" }
-                                                        th { "Value (HEX)" }
-                                                    }
-                                                }
-                                                tbody {
-                                                    @for (i, value) in buff.iter().enumerate() {
-                                                        tr {
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (start_register as usize + (i * 2)) }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    (format!("{:.2}", value))
-                                                                }
-                                                            }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    ""
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            } else {
-                                res.poll_time = Some(now.elapsed());
-                                html! {
-                                     #modbus_table {
-                                        div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                                            table class="interactive" {
-                                                thead {
-                                                    tr {
-                                                        th {
-                                                            "Register"
-                                                        }
-                                                        th { "Value" }
-                                                        th { "Value (HEX)" }
-                                                    }
-                                                }
-                                                tbody {
-                                                    @for (i, value) in result.iter().enumerate() {
-                                                        tr {
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (start_register as usize + i) }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    (format!("{}", value))
-                                                                }
-                                                            }
-                                                            td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                                {
-                                                                    (format!("{:#06X}", value))
-                                                                }
-                                                            }
-                                                        }
-
-                                                    }
-                                                }
-
-                                            }
-                                        }
-                                    }
+                            }
+                            PollData::Floats(values) => {
+                                @for (i, value) in values.iter().enumerate() {
+                                    (modbus_table_row(snapshot.start_register as usize + (i * 2), &format!("{:.2}", value), ""))
                                 }
                             }
-                        }
-                        Err(e) => {
-                            html! {
-                                 #modbus_table {
-                                    div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-                                        table class="interactive" {
-                                            thead {
-                                                tr {
-                                                    th {
-                                                        "Register"
-                                                    }
-                                                    th { "Value" }
-                                                    th { "Value (HEX)" }
-                                                }
-                                            }
-                                            tbody {
-                                                tr {
-                                                    td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                        (format!("{:?}", e))
-
-                                                    }
-                                                    td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                    }
-                                                    td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
+                            PollData::Coils(values) => {
+                                @for (i, value) in values.iter().enumerate() {
+                                    (modbus_table_row(snapshot.start_register as usize + i, if *value { "1" } else { "0" }, ""))
                                 }
                             }
-                        }
-                    },
-                    Err(e) => {
-                        res.poll_time = None;
-                        html! {
-                            #modbus_table {
-                               div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus" hx-swap="innerHTML" {
-
-                                   table class="interactive" {
-                                       thead {
-                                           tr {
-                                               th {
-                                                   "Register"
-                                               }
-                                               th { "Value" }
-                                           }
-                                       }
-                                       tbody {
-                                           tr {
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) { "0" }
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                   {
-                                                       p { (format!("{:?}", e)) }
-                                                   }
-                                               }
-                                           }
-                                       }
-
-                                   }
-                               }
-                           }
-                        }
-                    }
-                }
-            }
-            _ => {
-                let result = ctx.read_holding_registers(start_register, count).await;
-                match result {
-                    Ok(_result) => {
-                        res.poll_time = Some(now.elapsed());
-                        html! {
-                            #modbus_table {
-                               div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                                   table class="interactive" {
-                                       thead {
-                                           tr {
-                                               th {
-                                                   "Register"
-                                               }
-                                               th { "Value" }
-                                           }
-                                       }
-                                       tbody {
-                                           tr {
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) { "0" }
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                   {
-                                                       p { "" }
-                                                   }
-                                               }
-                                           }
-                                       }
-
-                                   }
-                               }
-                           }
+                            PollData::Error(e) => {
+                                (modbus_table_row(0, e, ""))
+                            }
                         }
                     }
-                    Err(e) => {
-                        res.poll_time = None;
-                        html! {
-                            #modbus_table {
-                               div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                                   table class="interactive" {
-                                       thead {
-                                           tr {
-                                               th {
-                                                   "Register"
-                                               }
-                                               th { "Value" }
-                                           }
-                                       }
-                                       tbody {
-                                           tr {
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) { "0" }
-                                               td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                                   {
-                                                       p { (format!("{:?}", e)) }
-                                                   }
-                                               }
-                                           }
-                                       }
-
-                                   }
-                               }
-                           }
-                        }
+                    None => {
+                        (modbus_table_row(0, "No connection.", ""))
                     }
                 }
             }
-        },
-        None => {
-            html! {
-                #modbus_table {
-                   div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table" hx-swap="innerHTML" {
-
-                       table class="interactive" {
-                           thead {
-                               tr {
-                                   th {
-                                       "Register"
-                                   }
-                                   th { "Value" }
-                                   th { "Value (HEX)" }
-                               }
-                           }
-                           tbody {
-                               tr {
-                                   td style=(format!("width: {}px", TABLE_COL_WIDTH)) { "0" }
-                                   td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                       {
-                                           p { "No connection." }
-                                       }
-                                   }
-                                   td style=(format!("width: {}px", TABLE_COL_WIDTH)) {
-                                       {
-                                            ""
-                                       }
-                                   }
-                               }
-                           }
-
-                       }
-                   }
-               }
-            }
         }
     }
 }
-pub fn modbus_serial_body() -> Markup {
+
+fn modbus_table_row(register: usize, value: &str, hex: &str) -> Markup {
+    html! {
+        tr {
+            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (register) }
+            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (value) }
+            td style=(format!("width: {}px", TABLE_COL_WIDTH)) { (hex) }
+        }
+    }
+}
+
+pub fn modbus_serial_body(state: &ModbusState) -> Markup {
+    let serial = &state.project.serial;
+    let polling = PollBlock::from(&state.protocol_options);
     html! {
         body {
             main {
@@ -814,11 +1241,22 @@ pub fn modbus_serial_body() -> Markup {
                                 legend { "Slave Settings" }
                                 div class="field-row-stacked" style="width: 200px" {
                                     label for="com" { "COM Port: " }
-                                    input type="text" id="com" name="com" value="COM4" {}
+                                    input type="text" id="com" name="com" value=(serial.com) {}
                                     label for="baudrate" { "Baudrate: " }
-                                    input type="number" id="baudrate" name="baudrate" value="9600" {}
+                                    input type="number" id="baudrate" name="baudrate" value=(serial.baudrate) {}
                                     label for="slave" { "Slave ID: " }
-                                    input type="number" id="slave" name="slave" value="1" {}
+                                    input type="number" id="slave" name="slave" value=(serial.slave) {}
+                                    label for="framing" { "Framing: " }
+                                    select name="framing" id="framing" {
+                                        option value="rtu" { "RTU" }
+                                        option value="ascii" selected[serial.framing == SerialFraming::Ascii] { "ASCII (7E1)" }
+                                    }
+                                }
+                                div class="field-row" {
+                                    input type="checkbox" id="read_only" name="read_only" value="true" checked[serial.read_only] {}
+                                    label for="read_only" { "Read only (reject writes)" }
+                                }
+                                div class="field-row" {
                                     //button type="submit" { "Connect" }
                                     button hx-post="/connect_modbus_serial" { "Connect" }
                                     button hx-get="/disconnect_modbus" hx-target="#modbus_connect_content" hx-swap="innerHTML" { "Disconnect" }
@@ -831,48 +1269,24 @@ pub fn modbus_serial_body() -> Markup {
                                 div class="field-row-stacked" style="width: 200px" {
                                     label for="function" { "Function Code: " }
                                     select name="function" id="function" {
-                                        option value="3" { "0x03-Read Holding Registers" }
-                                        option value="4" { "0x04-Read Input Registers" }
-                                        option value="1" { "0x01-Read Coils" }
+                                        option value="3" selected[polling.function == 3] { "0x03-Read Holding Registers" }
+                                        option value="4" selected[polling.function == 4] { "0x04-Read Input Registers" }
+                                        option value="1" selected[polling.function == 1] { "0x01-Read Coils" }
                                     }
                                     label for="register" { "Register: " }
-                                    input type="number" id="register" name="register" value="1" {}
+                                    input type="number" id="register" name="register" value=(polling.register) {}
                                     label for="count" { "Count: (Default 5)" }
-                                    input type="number" id="count" name="count" value="5" {}
+                                    input type="number" id="count" name="count" value=(polling.count) {}
                                     label for="float32" { "Use double registers as float: " }
                                     select name="float32" id="function" {
                                         option value="int16" { "16 bit integer" }
-                                        option value="f32" { "32 bit float" }
+                                        option value="f32" selected[polling.data_type == DataType::F32] { "32 bit float" }
                                     }
                                     button hx-post="/update_modbus" { "Send" }
                                 }
                             }
                         }
-                        form hx-post="/write_modbus" hx-target="#modbus_connect_content" hx-swap="innerHTML" {
-                            fieldset {
-                                legend { "Write Options" }
-                                details {
-                                    summary { "Show" }
-                                    div class="field-row-stacked" style="width: 200px" {
-                                        label for="write_function" { "Function Code: " }
-                                        select name="write_function" id="write_function" {
-                                            option value="6" { "0x06-Write Holding Register" }
-                                            option value="5" { "0x05-Write Coil" }
-                                        }
-                                        label for="register" { "Register: " }
-                                        input type="number" id="register" name="register" value="1" {}
-                                        label for="float32" { "Use double registers as float: " }
-                                        select name="float32" id="function" {
-                                            option value="int16" { "16 bit integer" }
-                                            option value="f32" { "32 bit float" }
-                                        }
-                                        label for="value" { "Value: " }
-                                        input type="number" id="value" name="value" value="1" {}
-                                        button type="submit" { "Write" }
-                                    }
-                                }
-                            }
-                        }
+                        (write_options(state))
                         div class="sunken-panel" style=(format!("height: {}px; width: {}px", TABLE_HEIGHT, TABLE_WIDTH)) {
                             #modbus_table {
                                 table class="interactive" {
@@ -904,7 +1318,6 @@ pub fn modbus_serial_body() -> Markup {
 
                             }
                         }
-                        div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table"  hx-swap="innerHTML" {}
                     }
                     // Status bar
                     (modbus_status_bar())
@@ -915,7 +1328,9 @@ pub fn modbus_serial_body() -> Markup {
 
     }
 }
-pub fn modbus_tcp_body() -> Markup {
+pub fn modbus_tcp_body(state: &ModbusState) -> Markup {
+    let tcp = &state.project.tcp;
+    let polling = PollBlock::from(&state.protocol_options);
     html! {
         body {
             main {
@@ -929,9 +1344,41 @@ pub fn modbus_tcp_body() -> Markup {
                                 legend { "Slave Settings" }
                                 div class="field-row-stacked" style="width: 200px" {
                                     label for="address" { "Address: " }
-                                    input type="text" id="address" name="address" value="127.0.0.1" {}
-                                    label for="port" { "Port: (Default 502)" }
-                                    input type="number" id="port" name="port" value="5502" {}
+                                    input type="text" id="address" name="address" value=(tcp.address) {}
+                                    label for="port" { "Port: (Default 502, 802 for TLS)" }
+                                    input type="number" id="port" name="port" value=(tcp.port) {}
+                                    label for="framing" { "Framing: " }
+                                    select name="framing" id="framing" {
+                                        option value="mbap" { "Modbus TCP" }
+                                        option value="rtu" selected[tcp.framing == TcpFraming::Rtu] { "RTU over TCP" }
+                                        option value="udp" selected[tcp.framing == TcpFraming::Udp] { "Modbus UDP" }
+                                        option value="tls" selected[tcp.framing == TcpFraming::Tls] { "Modbus/TCP Security (TLS)" }
+                                    }
+                                    label for="slave" { "Slave ID: (RTU over TCP)" }
+                                    input type="number" id="slave" name="slave" value=(tcp.slave) {}
+                                }
+                            }
+                            fieldset {
+                                legend { "Modbus/TCP Security" }
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="ca_file" { "CA Bundle: (PEM file)" }
+                                    input type="text" id="ca_file" name="ca_file" value=(tcp.ca_file) {}
+                                    label for="cert_file" { "Client Certificate: (PEM file)" }
+                                    input type="text" id="cert_file" name="cert_file" value=(tcp.cert_file) {}
+                                    label for="key_file" { "Client Key: (PEM file)" }
+                                    input type="text" id="key_file" name="key_file" value=(tcp.key_file) {}
+                                    label for="server_name" { "Server Name: (empty for the address)" }
+                                    input type="text" id="server_name" name="server_name" value=(tcp.server_name) {}
+                                }
+                                div class="field-row" {
+                                    input type="checkbox" id="skip_server_name" name="skip_server_name" value="true" checked[tcp.skip_server_name] {}
+                                    label for="skip_server_name" { "Skip server name verification" }
+                                }
+                                div class="field-row" {
+                                    input type="checkbox" id="read_only" name="read_only" value="true" checked[tcp.read_only] {}
+                                    label for="read_only" { "Read only (reject writes)" }
+                                }
+                                div class="field-row" {
                                     //button type="submit" { "Connect" }
                                     button hx-post="/connect_modbus_tcp" { "Connect" }
                                     button hx-get="/disconnect_modbus" hx-target="#modbus_connect_content" hx-swap="innerHTML" { "Disconnect" }
@@ -944,49 +1391,24 @@ pub fn modbus_tcp_body() -> Markup {
                                 div class="field-row-stacked" style="width: 200px" {
                                     label for="function" { "Function Code: " }
                                     select name="function" id="function" {
-                                        option value="3" { "0x03-Read Holding Registers" }
-                                        option value="4" { "0x04-Read Input Registers" }
-                                        option value="1" { "0x01-Read Coils" }
+                                        option value="3" selected[polling.function == 3] { "0x03-Read Holding Registers" }
+                                        option value="4" selected[polling.function == 4] { "0x04-Read Input Registers" }
+                                        option value="1" selected[polling.function == 1] { "0x01-Read Coils" }
                                     }
                                     label for="register" { "Register: " }
-                                    input type="number" id="register" name="register" value="1" {}
+                                    input type="number" id="register" name="register" value=(polling.register) {}
                                     label for="count" { "Count: (Default 5)" }
-                                    input type="number" id="count" name="count" value="5" {}
+                                    input type="number" id="count" name="count" value=(polling.count) {}
                                     label for="float32" { "Use double registers as float: " }
                                     select name="float32" id="function" {
                                         option value="int16" { "16 bit integer" }
-                                        option value="f32" { "32 bit float" }
+                                        option value="f32" selected[polling.data_type == DataType::F32] { "32 bit float" }
                                     }
                                     button hx-post="/update_modbus" { "Send" }
                                 }
                             }
                         }
-                        form hx-post="/write_modbus" hx-target="#modbus_connect_content" hx-swap="innerHTML" {
-                            fieldset {
-                                legend { "Write Options" }
-                                details {
-                                    summary { "Show" }
-                                    div class="field-row-stacked" style="width: 200px" {
-                                        label for="write_function" { "Function Code: " }
-                                        select name="write_function" id="write_function" {
-                                            option value="6" { "0x06-Write Holding Register" }
-                                            option value="5" { "0x05-Write Coil" }
-                                        }
-                                        label for="register" { "Register: " }
-                                        input type="number" id="register" name="register" value="1" {}
-                                        label for="float32" { "Use double registers as float: " }
-                                        select name="float32" id="function" {
-                                            option value="int16" { "16 bit integer" }
-                                            option value="f32" { "32 bit float" }
-                                        }
-                                        label for="value" { "Value: " }
-                                        input type="number" id="value" name="value" value="1" {}
-                                        button type="submit" { "Write" }
-                                    }
-
-                                }
-                            }
-                        }
+                        (write_options(state))
 
                         div class="sunken-panel" style=(format!("height: {}px; width: {}px", TABLE_HEIGHT, TABLE_WIDTH)) {
                             #modbus_table {
@@ -1019,7 +1441,6 @@ pub fn modbus_tcp_body() -> Markup {
 
                             }
                         }
-                        div hx-get="/poll_modbus" hx-trigger="load delay:1s" hx-target="#modbus_table"  hx-swap="innerHTML" {}
                     }
                     // Status bar
                     (modbus_status_bar())
@@ -1033,16 +1454,324 @@ pub fn modbus_tcp_body() -> Markup {
 pub fn modbus_status_bar() -> Markup {
     html! {
         div class="status-bar" {
+            // Kept up to date by assets/live.js over the /ws endpoint.
             #heartbeat {
-                div hx-get="/heartbeat" hx-trigger="load delay:1s" hx-target="#heartbeat" hx-swap="innerHTML" {
-                   p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {
-                       "SCANTIME:   "
-                   }
-                }
+                (heartbeat_field(None))
             }
             #modbus_connect_content {
                 p class="status-bar-field" style=(format!("width: {}px", STATUS_BAR_FIELD_WIDTH)) {  "STATUS:  No connection"  }
             }
+            #alarm_status {
+                (alarm_status_field(0, 0))
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Shutdown;
+    use async_trait::async_trait;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Device memory, writes to `stuck` are acknowledged but dropped.
+    #[derive(Debug)]
+    struct Memory {
+        coils: Vec<bool>,
+        registers: Vec<u16>,
+        stuck: Option<u16>,
+    }
+
+    impl SlaveContext for Memory {
+        fn set_slave(&mut self, _: Slave) {}
+    }
+
+    #[async_trait]
+    impl Client for Memory {
+        async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+            let stuck = |register| self.stuck == Some(register);
+            Ok(Ok(match request {
+                Request::ReadCoils(start, count) => {
+                    Response::ReadCoils(self.coils[start as usize..][..count as usize].to_vec())
+                }
+                Request::ReadHoldingRegisters(start, count) => Response::ReadHoldingRegisters(
+                    self.registers[start as usize..][..count as usize].to_vec(),
+                ),
+                Request::WriteSingleCoil(register, coil) => {
+                    if !stuck(register) {
+                        self.coils[register as usize] = coil;
+                    }
+                    Response::WriteSingleCoil(register, coil)
+                }
+                Request::WriteSingleRegister(register, word) => {
+                    if !stuck(register) {
+                        self.registers[register as usize] = word;
+                    }
+                    Response::WriteSingleRegister(register, word)
+                }
+                Request::WriteMultipleRegisters(start, words) => {
+                    if !stuck(start) {
+                        self.registers[start as usize..][..words.len()].copy_from_slice(&words);
+                    }
+                    Response::WriteMultipleRegisters(start, words.len() as u16)
+                }
+                _ => return Ok(Err(ExceptionCode::IllegalFunction)),
+            }))
+        }
+
+        async fn disconnect(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A device that stops answering after `answers` reads of zeros.
+    #[derive(Debug)]
+    struct Dying {
+        answers: usize,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl SlaveContext for Dying {
+        fn set_slave(&mut self, _: Slave) {}
+    }
+
+    #[async_trait]
+    impl Client for Dying {
+        async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            match request {
+                Request::ReadHoldingRegisters(_, count) if calls <= self.answers => {
+                    Ok(Ok(Response::ReadHoldingRegisters(vec![0; count as usize])))
+                }
+                _ => Err(io::Error::new(io::ErrorKind::TimedOut, "Request timed out.").into()),
+            }
+        }
+
+        async fn disconnect(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connected(stuck: Option<u16>) -> ModbusState {
+        let mut state = ModbusState::new(ProtocolOpts::default(), None);
+        let client: Box<dyn Client> = Box::new(Memory {
+            coils: vec![false; 8],
+            registers: vec![0; 8],
+            stuck,
+        });
+        state.context = Some(Context::from(client));
+        state.connection = Some(ConnectionSettings::Tcp {
+            address: "127.0.0.1".to_string(),
+            port: 502,
+            read_only: false,
+        });
+        state.audit = Some(Arc::new(AuditLog::open(":memory:").unwrap()));
+        state
+    }
+
+    fn request(function: u8, register: u16, value: f64, data_type: DataType) -> WriteRequest {
+        WriteRequest {
+            function,
+            register,
+            value,
+            data_type,
+            verify: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_and_audits_writes() {
+        let mut state = connected(Some(4));
+        state.last_poll = Some(PollSnapshot {
+            function: 3,
+            start_register: 0,
+            data: PollData::Registers(vec![0, 0, 5, 0]),
+        });
+        let message = write_value(&mut state, &request(6, 2, 1234.0, DataType::Int16), "test")
+            .await
+            .unwrap();
+        assert_eq!(message, "Wrote: 1234 to H-Register: 2, verified");
+        assert!(
+            write_value(&mut state, &request(16, 0, 2.5, DataType::F32), "test")
+                .await
+                .is_ok()
+        );
+        assert!(
+            write_value(&mut state, &request(5, 3, 1.0, DataType::Int16), "test")
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            read_target(&mut state, &request(6, 0, 0.0, DataType::F32)).await,
+            Ok(2.5)
+        );
+
+        let error = write_value(&mut state, &request(6, 4, 7.0, DataType::Int16), "test")
+            .await
+            .unwrap_err();
+        assert_eq!(error, "Wrote: 7 to H-Register: 4, but read back 0");
+
+        let entries = state.audit.as_ref().unwrap().entries(None).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].old_value, Some(5.0));
+        assert_eq!(entries[3].unit_id, Some(255));
+        assert_eq!(entries[3].origin, "test");
+        // The float and the coil aren't in the polled block.
+        assert_eq!(entries[2].old_value, None);
+        assert_eq!(entries[1].old_value, None);
+        assert!(!entries[0].success);
+        assert_eq!(entries[0].result, error);
+    }
+
+    #[tokio::test]
+    async fn rejects_fractional_values() {
+        let mut state = connected(None);
+        for (function, value, error) in [
+            (6, 12.7, "Only whole numbers accepted!"),
+            (6, f64::NAN, "Bad input!"),
+            (6, -1.0, "Value out of range!"),
+            (5, 1.7, "Only 1 or 0 values accepted!"),
+            (5, 0.2, "Only 1 or 0 values accepted!"),
+        ] {
+            let request = request(function, 1, value, DataType::Int16);
+            assert_eq!(
+                write_value(&mut state, &request, "test").await,
+                Err(error.to_string())
+            );
+        }
+        assert_eq!(
+            read_target(&mut state, &request(6, 1, 0.0, DataType::Int16)).await,
+            Ok(0.0)
+        );
+        assert_eq!(
+            read_target(&mut state, &request(5, 1, 0.0, DataType::Int16)).await,
+            Ok(0.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_it_cannot_audit() {
+        let mut state = connected(None);
+        state.audit = None;
+        let error = write_value(&mut state, &request(6, 0, 1.0, DataType::Int16), "test")
+            .await
+            .unwrap_err();
+        assert_eq!(error, "Writes are refused, the audit log is not available.");
+        assert_eq!(
+            read_target(&mut state, &request(6, 0, 0.0, DataType::Int16)).await,
+            Ok(0.0)
+        );
+
+        let path = std::env::temp_dir().join(format!("mptt-audit-{}.db", std::process::id()));
+        state.audit = Some(Arc::new(AuditLog::open(path.to_str().unwrap()).unwrap()));
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("DROP TABLE writes")
+            .unwrap();
+        let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+        state.notifier = Notifier::new(sender);
+        // The write went out, only its entry is missing.
+        let message = write_value(&mut state, &request(6, 0, 1.0, DataType::Int16), "test")
+            .await
+            .unwrap();
+        assert_eq!(message, "Wrote: 1 to H-Register: 0, verified");
+        let Ok(NotificationEvent::Audit { entry, .. }) = events.try_recv() else {
+            panic!("no audit event");
+        };
+        assert!(entry.success);
+        state.audit = None;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn read_only_connections_reject_writes() {
+        let mut state = connected(None);
+        assert!(state.lock_writes(true));
+        assert!(state.writes_locked());
+        let error = write_value(&mut state, &request(6, 0, 1.0, DataType::Int16), "test")
+            .await
+            .unwrap_err();
+        assert_eq!(error, "Writes are locked for this connection.");
+        assert_eq!(
+            read_target(&mut state, &request(6, 0, 0.0, DataType::Int16)).await,
+            Ok(0.0)
+        );
+
+        // The lock belongs to the connection, not to the saved forms.
+        assert!(state.lock_writes(false));
+        state.project.tcp.read_only = true;
+        assert!(
+            write_value(&mut state, &request(6, 0, 1.0, DataType::Int16), "test")
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_dead_device_costs_one_request_per_poll() {
+        // Dead from the start, then after the main block and one extra block.
+        for answers in [0, 2] {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let mut state = connected(None);
+            let client: Box<dyn Client> = Box::new(Dying {
+                answers,
+                calls: calls.clone(),
+            });
+            state.context = Some(Context::from(client));
+            state.link.connected();
+            state.project.poll_blocks = vec![PollBlock::default(); 4];
+            poll_once(&mut state).await;
+            assert_eq!(calls.load(Ordering::SeqCst), answers + 1);
+            assert!(matches!(state.link.state, LinkState::Faulted { .. }));
+            assert_eq!(state.poll_time, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_poller_publishes_values_and_status() {
+        let mut state = connected(None);
+        state.link.connected();
+        let mut events = state.events.subscribe();
+        let mtx = Arc::new(Mutex::new(state));
+        let (stop, shutdown) = Shutdown::channel();
+        let poller = tokio::spawn(run_poller(mtx.clone(), shutdown));
+
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            seen.push(serde_json::to_value(&event).unwrap()["type"].clone());
+            if let LiveEvent::Values { snapshot } = &event {
+                assert_eq!(
+                    snapshot.as_ref().map(|snapshot| &snapshot.data),
+                    Some(&PollData::Registers(vec![0; 5]))
+                );
+            }
+        }
+        assert_eq!(seen, ["status", "values", "scan_time"]);
+        stop.send(true).unwrap();
+        poller.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shuts_down_the_poller_and_the_connection() {
+        let state = connected(None);
+        let mtx = Arc::new(Mutex::new(state));
+        let (stop, shutdown) = Shutdown::channel();
+        let poller = tokio::spawn(run_poller(mtx.clone(), shutdown));
+        stop.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), poller)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut state = mtx.lock().await;
+        shut_down(&mut state).await;
+        assert!(state.context.is_none() && state.connection.is_none());
+        assert!(state.audit.is_none());
+    }
+}
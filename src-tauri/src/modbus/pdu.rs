@@ -0,0 +1,184 @@
+use std::io::{Error, ErrorKind};
+use tokio_modbus::prelude::*;
+
+/// PDU encoding shared by the transports tokio-modbus doesn't provide.
+pub fn request_pdu(request: &Request<'_>) -> Result<Vec<u8>, Error> {
+    let mut pdu = Vec::new();
+    let push = |pdu: &mut Vec<u8>, word: u16| pdu.extend_from_slice(&word.to_be_bytes());
+    match request {
+        Request::ReadCoils(address, quantity)
+        | Request::ReadDiscreteInputs(address, quantity)
+        | Request::ReadHoldingRegisters(address, quantity)
+        | Request::ReadInputRegisters(address, quantity) => {
+            pdu.push(request_function(request));
+            push(&mut pdu, *address);
+            push(&mut pdu, *quantity);
+        }
+        Request::WriteSingleCoil(address, coil) => {
+            pdu.push(0x05);
+            push(&mut pdu, *address);
+            push(&mut pdu, if *coil { 0xFF00 } else { 0x0000 });
+        }
+        Request::WriteSingleRegister(address, word) => {
+            pdu.push(0x06);
+            push(&mut pdu, *address);
+            push(&mut pdu, *word);
+        }
+        Request::WriteMultipleCoils(address, coils) => {
+            let bytes = pack_coils(coils);
+            pdu.push(0x0F);
+            push(&mut pdu, *address);
+            push(&mut pdu, coils.len() as u16);
+            pdu.push(bytes.len() as u8);
+            pdu.extend_from_slice(&bytes);
+        }
+        Request::WriteMultipleRegisters(address, words) => {
+            pdu.push(0x10);
+            push(&mut pdu, *address);
+            push(&mut pdu, words.len() as u16);
+            pdu.push((words.len() * 2) as u8);
+            for word in words.iter() {
+                push(&mut pdu, *word);
+            }
+        }
+        Request::ReportServerId => pdu.push(0x11),
+        Request::MaskWriteRegister(address, and_mask, or_mask) => {
+            pdu.push(0x16);
+            push(&mut pdu, *address);
+            push(&mut pdu, *and_mask);
+            push(&mut pdu, *or_mask);
+        }
+        Request::ReadWriteMultipleRegisters(read_address, quantity, write_address, words) => {
+            pdu.push(0x17);
+            push(&mut pdu, *read_address);
+            push(&mut pdu, *quantity);
+            push(&mut pdu, *write_address);
+            push(&mut pdu, words.len() as u16);
+            pdu.push((words.len() * 2) as u8);
+            for word in words.iter() {
+                push(&mut pdu, *word);
+            }
+        }
+        _ => return Err(invalid("Unsupported request.")),
+    }
+    Ok(pdu)
+}
+
+fn request_function(request: &Request<'_>) -> u8 {
+    match request {
+        Request::ReadCoils(..) => 0x01,
+        Request::ReadDiscreteInputs(..) => 0x02,
+        Request::ReadHoldingRegisters(..) => 0x03,
+        Request::ReadInputRegisters(..) => 0x04,
+        Request::WriteSingleCoil(..) => 0x05,
+        Request::WriteSingleRegister(..) => 0x06,
+        Request::WriteMultipleCoils(..) => 0x0F,
+        Request::WriteMultipleRegisters(..) => 0x10,
+        Request::ReportServerId => 0x11,
+        Request::MaskWriteRegister(..) => 0x16,
+        Request::ReadWriteMultipleRegisters(..) => 0x17,
+        _ => 0x00,
+    }
+}
+
+fn pack_coils(coils: &[bool]) -> Vec<u8> {
+    coils
+        .chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (i, coil)| byte | ((*coil as u8) << i))
+        })
+        .collect()
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+pub fn exception_code(code: u8) -> ExceptionCode {
+    match code {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::ServerDeviceFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::ServerDeviceBusy,
+        0x08 => ExceptionCode::MemoryParityError,
+        0x0A => ExceptionCode::GatewayPathUnavailable,
+        0x0B => ExceptionCode::GatewayTargetDevice,
+        code => ExceptionCode::Custom(code),
+    }
+}
+
+/// Decodes the answer to `request`, an exception response gives the inner `Err`.
+pub fn parse_response(
+    request: &Request<'_>,
+    pdu: &[u8],
+) -> Result<Result<Response, ExceptionCode>, Error> {
+    let function = request_function(request);
+    let (&received, data) = pdu
+        .split_first()
+        .ok_or_else(|| invalid("Empty response."))?;
+    if received == function | 0x80 {
+        let code = data
+            .first()
+            .ok_or_else(|| invalid("Truncated exception."))?;
+        return Ok(Err(exception_code(*code)));
+    }
+    if received != function {
+        return Err(invalid("Function code mismatch."));
+    }
+    let word = |i: usize| -> Result<u16, Error> {
+        data.get(i..i + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| invalid("Truncated response."))
+    };
+    let counted = || -> Result<&[u8], Error> {
+        let count = *data.first().ok_or_else(|| invalid("Truncated response."))? as usize;
+        data.get(1..1 + count)
+            .ok_or_else(|| invalid("Truncated response."))
+    };
+    let words = |bytes: &[u8]| -> Vec<u16> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect()
+    };
+    let response = match request {
+        Request::ReadCoils(_, quantity) | Request::ReadDiscreteInputs(_, quantity) => {
+            let bytes = counted()?;
+            let coils: Vec<bool> = (0..*quantity as usize)
+                .map(|i| bytes.get(i / 8).map(|byte| byte & (1 << (i % 8)) != 0))
+                .collect::<Option<Vec<bool>>>()
+                .ok_or_else(|| invalid("Truncated response."))?;
+            match request {
+                Request::ReadCoils(..) => Response::ReadCoils(coils),
+                _ => Response::ReadDiscreteInputs(coils),
+            }
+        }
+        Request::ReadHoldingRegisters(..) => Response::ReadHoldingRegisters(words(counted()?)),
+        Request::ReadInputRegisters(..) => Response::ReadInputRegisters(words(counted()?)),
+        Request::ReadWriteMultipleRegisters(..) => {
+            Response::ReadWriteMultipleRegisters(words(counted()?))
+        }
+        Request::WriteSingleCoil(..) => Response::WriteSingleCoil(word(0)?, word(2)? == 0xFF00),
+        Request::WriteSingleRegister(..) => Response::WriteSingleRegister(word(0)?, word(2)?),
+        Request::WriteMultipleCoils(..) => Response::WriteMultipleCoils(word(0)?, word(2)?),
+        Request::WriteMultipleRegisters(..) => Response::WriteMultipleRegisters(word(0)?, word(2)?),
+        Request::MaskWriteRegister(..) => Response::MaskWriteRegister(word(0)?, word(2)?, word(4)?),
+        Request::ReportServerId => {
+            let bytes = counted()?;
+            let (&server_id, rest) = bytes
+                .split_first()
+                .ok_or_else(|| invalid("Truncated response."))?;
+            let (&run, additional) = rest
+                .split_first()
+                .ok_or_else(|| invalid("Truncated response."))?;
+            Response::ReportServerId(server_id, run == 0xFF, additional.to_vec())
+        }
+        _ => return Err(invalid("Unsupported request.")),
+    };
+    Ok(Ok(response))
+}
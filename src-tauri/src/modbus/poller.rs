@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+use tokio_modbus::prelude::*;
+use utoipa::ToSchema;
+
+use crate::historian::now_millis;
+use crate::server::Shutdown;
+use crate::stats::monitor;
+
+use super::{
+    double_register_as_float, read_block, record_history, ConnectionSettings, LinkState,
+    ModbusState, ProtocolOpts, ReadError, TlsPeer,
+};
+
+const POLL_INTERVAL: u64 = 1; // Poll the configured block every second.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Values of the last poll of the configured block.
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PollData {
+    Registers(Vec<u16>),
+    Floats(Vec<f32>),
+    Coils(Vec<bool>),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq, Debug)]
+pub struct PollSnapshot {
+    pub function: u8,
+    pub start_register: u16,
+    pub data: PollData,
+}
+
+/// Events pushed to live clients (e.g. the `/ws` endpoint).
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Values {
+        snapshot: Option<PollSnapshot>,
+    },
+    Status {
+        connected: bool,
+        link: LinkState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tls: Option<TlsPeer>,
+    },
+    ScanTime {
+        micros: Option<u64>,
+    },
+    Alarms {
+        active: usize,
+        unacknowledged: usize,
+    },
+}
+
+impl PollData {
+    /// Register step between values and the values as historian samples.
+    pub fn samples(&self) -> (u16, Vec<f64>) {
+        match self {
+            PollData::Registers(values) => (1, values.iter().map(|v| *v as f64).collect()),
+            PollData::Floats(values) => (2, values.iter().map(|v| *v as f64).collect()),
+            PollData::Coils(values) => (1, values.iter().map(|v| *v as u8 as f64).collect()),
+            PollData::Error(_) => (1, Vec::new()),
+        }
+    }
+}
+
+pub fn registers_as_floats(registers: &[u16]) -> Vec<f32> {
+    registers
+        .chunks_exact(2)
+        .map(|pair| double_register_as_float(pair[0], pair[1]))
+        .collect()
+}
+
+/// Reads the configured block once and stores the result in `last_poll`, then the extra blocks.
+pub async fn poll_once(state: &mut ModbusState) {
+    let now = Instant::now();
+    let function_code = state.protocol_options.function_code;
+    let start_register = state.protocol_options.start_register;
+    let count = state.protocol_options.count;
+    let float32 = state.protocol_options.float32;
+
+    let Some(ctx) = state.context.as_mut() else {
+        state.last_poll = None;
+        return;
+    };
+
+    let mut link_up = true;
+    let data = match read_block(ctx, function_code, start_register, count, float32).await {
+        Ok(data) => {
+            state.poll_time = Some(now.elapsed());
+            data
+        }
+        Err(ReadError::Exception(e)) => PollData::Error(e),
+        Err(ReadError::Transport(e)) => {
+            state.poll_time = None;
+            state.link.fault(e.clone());
+            link_up = false;
+            PollData::Error(e)
+        }
+    };
+
+    let snapshot = PollSnapshot {
+        function: function_code.value(),
+        start_register,
+        data,
+    };
+    record_history(state, None, &snapshot);
+    state.last_poll = Some(snapshot);
+
+    // The state stays locked during a poll, a dead device must only cost one request timeout.
+    if link_up {
+        poll_blocks(state).await;
+    }
+}
+
+/// Reads the project's extra poll blocks, they only feed the historian.
+/// Stops at the first transport error and faults the link.
+async fn poll_blocks(state: &mut ModbusState) {
+    let blocks: Vec<(Option<u8>, ProtocolOpts)> = state
+        .project
+        .poll_blocks
+        .iter()
+        .filter_map(|block| Some((block.unit_id, block.protocol_options()?)))
+        .collect();
+    let default_slave = state.connection.as_ref().map(|c| c.default_slave());
+    for (unit_id, block) in blocks {
+        let Some(ctx) = state.context.as_mut() else {
+            return;
+        };
+        if let Some(unit_id) = unit_id {
+            ctx.set_slave(Slave(unit_id));
+        }
+        let result = read_block(
+            ctx,
+            block.function_code,
+            block.start_register,
+            block.count,
+            block.float32,
+        )
+        .await;
+        // Later reads, including the main block, go to the connection's own slave.
+        if let (Some(_), Some(slave)) = (unit_id, default_slave) {
+            ctx.set_slave(slave);
+        }
+        let data = match result {
+            Ok(data) => data,
+            Err(ReadError::Exception(_)) => continue,
+            Err(ReadError::Transport(e)) => {
+                state.poll_time = None;
+                state.link.fault(e);
+                return;
+            }
+        };
+        let snapshot = PollSnapshot {
+            function: block.function_code.value(),
+            start_register: block.start_register,
+            data,
+        };
+        record_history(state, unit_id, &snapshot);
+    }
+}
+
+/// Polls the connected slave in the background and publishes changes as `LiveEvent`s.
+pub async fn run_poller(mtx: Arc<Mutex<ModbusState>>, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut link = LinkState::Disconnected;
+    let mut tls_peer = None;
+    let mut last_poll = None;
+    let mut alarms = (0, 0);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.requested() => return,
+        }
+        let mut state = mtx.lock().await;
+        poll_once(&mut state).await;
+        let retry = supervise(&mut state).await;
+        if state.connection.is_some() {
+            let state = &mut *state;
+            state
+                .alarms
+                .check_stale(&state.project.alarms, now_millis());
+        } else {
+            state.alarms.stop_watching();
+        }
+
+        // Nobody listening is not an error, so the send results are ignored.
+        if state.link.state != link || state.tls_peer != tls_peer {
+            link = state.link.state.clone();
+            tls_peer = state.tls_peer.clone();
+            let _ = state.events.send(LiveEvent::Status {
+                connected: state.context.is_some(),
+                link: link.clone(),
+                tls: tls_peer.clone(),
+            });
+        }
+        if state.last_poll != last_poll {
+            last_poll = state.last_poll.clone();
+            let _ = state.events.send(LiveEvent::Values {
+                snapshot: last_poll.clone(),
+            });
+        }
+        let _ = state.events.send(LiveEvent::ScanTime {
+            micros: state.poll_time.map(|time| time.as_micros() as u64),
+        });
+        if state.alarms.counts() != alarms {
+            alarms = state.alarms.counts();
+            let _ = state.events.send(LiveEvent::Alarms {
+                active: alarms.0,
+                unacknowledged: alarms.1,
+            });
+        }
+        drop(state);
+
+        if let Some(settings) = retry {
+            reconnect(&mtx, settings).await;
+        }
+    }
+}
+
+/// Drops a faulted context and returns the settings when a reconnect attempt is due.
+async fn supervise(state: &mut ModbusState) -> Option<ConnectionSettings> {
+    if let LinkState::Faulted { error } = &state.link.state {
+        let error = error.clone();
+        if let Some(mut ctx) = state.context.take() {
+            let _ = ctx.disconnect().await;
+        }
+        state.tls_peer = None;
+        state.link.back_off(error);
+    }
+    if !state.link.retry_due() {
+        return None;
+    }
+    let settings = state.connection.clone()?;
+    state.link.connecting(settings.to_string());
+    Some(settings)
+}
+
+/// Connects without holding the lock, so the UI stays responsive while a device reboots.
+async fn reconnect(mtx: &Mutex<ModbusState>, settings: ConnectionSettings) {
+    let result = tokio::time::timeout(CONNECT_TIMEOUT, settings.connect())
+        .await
+        .unwrap_or_else(|_| Err("Connect timed out.".to_string()));
+    let mut state = mtx.lock().await;
+    // The user disconnected or connected elsewhere meanwhile.
+    let same = state
+        .connection
+        .as_ref()
+        .is_some_and(|connection| connection.same_device(&settings));
+    if state.link.state != LinkState::Connecting || !same {
+        return;
+    }
+    match result {
+        Ok((ctx, tls_peer)) => {
+            state.context = Some(monitor(ctx, &state.stats, &settings));
+            state.tls_peer = tls_peer;
+            state.link.connected();
+            if let Ok(mut stats) = state.stats.lock() {
+                stats.connection(&settings.to_string()).reconnects += 1;
+            }
+        }
+        Err(e) => state.link.back_off(e),
+    }
+}
@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+use tokio_modbus::FunctionCode;
+use utoipa::ToSchema;
+
+use super::{
+    function_code_from, register_tag, DataType, PollData, PollSnapshot, ProtocolOpts, WriteRequest,
+};
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    #[default]
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Order of the four bytes of a 32 bit value, `Abcd` is high word first.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    #[default]
+    Abcd,
+    Cdab,
+    Badc,
+    Dcba,
+}
+
+/// A named register, its value is `raw * scale` in `unit`.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    /// Read function code: 1 (coils), 3 (holding registers) or 4 (input registers).
+    pub function: u8,
+    pub register: u16,
+    #[serde(default)]
+    pub data_type: DataType,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub unit: String,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub byte_order: ByteOrder,
+    /// Slave the tag is read from, the connection's own when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_id: Option<u8>,
+}
+
+/// A contiguous range read by the poller on every scan.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct PollBlock {
+    #[serde(default)]
+    pub name: String,
+    pub function: u8,
+    pub register: u16,
+    pub count: u16,
+    #[serde(default)]
+    pub data_type: DataType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_id: Option<u8>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl Default for PollBlock {
+    fn default() -> Self {
+        PollBlock::from(&ProtocolOpts::default())
+    }
+}
+
+impl From<&ProtocolOpts> for PollBlock {
+    fn from(options: &ProtocolOpts) -> Self {
+        PollBlock {
+            name: String::new(),
+            unit_id: None,
+            function: options.function_code.value(),
+            register: options.start_register,
+            count: options.count,
+            data_type: if options.float32 {
+                DataType::F32
+            } else {
+                DataType::Int16
+            },
+        }
+    }
+}
+
+impl ByteOrder {
+    pub fn float(self, first: u16, second: u16) -> f32 {
+        let (high, low) = match self {
+            ByteOrder::Abcd => (first, second),
+            ByteOrder::Cdab => (second, first),
+            ByteOrder::Badc => (first.swap_bytes(), second.swap_bytes()),
+            ByteOrder::Dcba => (second.swap_bytes(), first.swap_bytes()),
+        };
+        f32::from_bits(((high as u32) << 16) | low as u32)
+    }
+}
+
+impl Tag {
+    /// Number of registers the tag spans.
+    pub fn width(&self) -> u16 {
+        match self.data_type {
+            DataType::Int16 => 1,
+            DataType::F32 => 2,
+        }
+    }
+
+    /// Write of the scaled `value`, only for writable coils and holding registers.
+    pub fn write_request(&self, value: f64) -> Result<WriteRequest, String> {
+        if self.access != Access::ReadWrite {
+            return Err(format!("{} is read only.", self.name));
+        }
+        if self.unit_id.is_some() {
+            return Err(format!("{} is on another unit id.", self.name));
+        }
+        let function = match (self.function, self.data_type) {
+            (1, _) => 5,
+            (3, DataType::Int16) => 6,
+            (3, DataType::F32) if self.byte_order == ByteOrder::Abcd => 16,
+            (3, DataType::F32) => return Err(format!("{} isn't in ABCD byte order.", self.name)),
+            _ => return Err(format!("{} can't be written.", self.name)),
+        };
+        if self.scale == 0.0 {
+            return Err(format!("{} has a scale of 0.", self.name));
+        }
+        let mut raw = value / self.scale;
+        // 12.3 at a scale of 0.1 divides to 123.00000000000001.
+        if function != 16 && (raw - raw.round()).abs() < 1e-6 {
+            raw = raw.round();
+        }
+        Ok(WriteRequest {
+            function,
+            register: self.register,
+            value: raw,
+            data_type: self.data_type,
+            verify: false,
+        })
+    }
+}
+
+impl PollBlock {
+    /// `None` when the function code can't be polled.
+    pub fn protocol_options(&self) -> Option<ProtocolOpts> {
+        Some(ProtocolOpts {
+            function_code: function_code_from(self.function)?,
+            start_register: self.register,
+            count: self.count,
+            float32: self.data_type == DataType::F32,
+        })
+    }
+}
+
+/// Historian values of a block polled from `unit_id`, its tags first, then the registers no tag covers.
+pub fn history_values(
+    tags: &[Tag],
+    unit_id: Option<u8>,
+    snapshot: &PollSnapshot,
+) -> Vec<(String, f64)> {
+    let decoded = decode_tags(tags, unit_id, snapshot);
+    let function_code = FunctionCode::new(snapshot.function);
+    let (step, samples) = snapshot.data.samples();
+    let registers = samples.into_iter().enumerate().filter_map(|(i, value)| {
+        let register = snapshot.start_register as u32 + i as u32 * step as u32;
+        let covered = decoded.iter().any(|(tag, _)| {
+            (tag.register as u32..tag.register as u32 + tag.width() as u32).contains(&register)
+        });
+        (!covered).then(|| (register_tag(&function_code, register as u16), value))
+    });
+    decoded
+        .iter()
+        .map(|(tag, value)| (tag.name.clone(), *value))
+        .chain(registers)
+        .collect()
+}
+
+/// Scaled values of the tags that fall inside a block polled from `unit_id`.
+pub fn decode_tags<'a>(
+    tags: &'a [Tag],
+    unit_id: Option<u8>,
+    snapshot: &PollSnapshot,
+) -> Vec<(&'a Tag, f64)> {
+    let mut values = Vec::new();
+    let polled = tags
+        .iter()
+        .filter(|tag| tag.unit_id == unit_id && tag.function == snapshot.function);
+    for tag in polled {
+        let Some(offset) = tag.register.checked_sub(snapshot.start_register) else {
+            continue;
+        };
+        let offset = offset as usize;
+        let raw = match (&snapshot.data, tag.data_type) {
+            (PollData::Registers(registers), DataType::Int16) => {
+                registers.get(offset).map(|v| *v as f64)
+            }
+            (PollData::Registers(registers), DataType::F32) => registers
+                .get(offset..offset + 2)
+                .map(|pair| tag.byte_order.float(pair[0], pair[1]) as f64),
+            (PollData::Floats(floats), DataType::F32)
+                if offset.is_multiple_of(2) && tag.byte_order == ByteOrder::Abcd =>
+            {
+                floats.get(offset / 2).map(|v| *v as f64)
+            }
+            (PollData::Coils(coils), _) => coils.get(offset).map(|v| *v as u8 as f64),
+            _ => None,
+        };
+        if let Some(raw) = raw {
+            values.push((tag, raw * tag.scale));
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str, register: u16, data_type: DataType, byte_order: ByteOrder) -> Tag {
+        Tag {
+            name: name.to_string(),
+            function: 3,
+            register,
+            data_type,
+            scale: 1.0,
+            unit: String::new(),
+            access: Access::ReadWrite,
+            byte_order,
+            unit_id: None,
+        }
+    }
+
+    #[test]
+    fn decodes_tags_in_their_byte_order() {
+        let pi = std::f32::consts::PI;
+        assert_eq!(ByteOrder::Abcd.float(0x4049, 0x0FDB), pi);
+        assert_eq!(ByteOrder::Cdab.float(0x0FDB, 0x4049), pi);
+        assert_eq!(ByteOrder::Badc.float(0x4940, 0xDB0F), pi);
+        assert_eq!(ByteOrder::Dcba.float(0xDB0F, 0x4940), pi);
+
+        let mut half = tag("half", 12, DataType::Int16, ByteOrder::Abcd);
+        half.scale = 0.5;
+        let mut other_unit = tag("other_unit", 13, DataType::Int16, ByteOrder::Abcd);
+        other_unit.unit_id = Some(2);
+        let tags = [
+            tag("before", 9, DataType::Int16, ByteOrder::Abcd),
+            tag("pi", 10, DataType::F32, ByteOrder::Cdab),
+            half,
+            other_unit,
+            tag("past_the_end", 13, DataType::F32, ByteOrder::Abcd),
+        ];
+        let snapshot = PollSnapshot {
+            function: 3,
+            start_register: 10,
+            data: PollData::Registers(vec![0x0FDB, 0x4049, 7, 8]),
+        };
+        let decoded: Vec<(&str, f64)> = decode_tags(&tags, None, &snapshot)
+            .into_iter()
+            .map(|(tag, value)| (tag.name.as_str(), value))
+            .collect();
+        assert_eq!(decoded, [("pi", pi as f64), ("half", 3.5)]);
+        assert_eq!(
+            history_values(&tags, None, &snapshot),
+            [
+                ("pi".to_string(), pi as f64),
+                ("half".to_string(), 3.5),
+                ("HR13".to_string(), 8.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn float_blocks_only_name_abcd_tags() {
+        let mut tags = [
+            tag("swapped", 0, DataType::F32, ByteOrder::Cdab),
+            tag("word", 2, DataType::Int16, ByteOrder::Abcd),
+            tag("float", 4, DataType::F32, ByteOrder::Abcd),
+        ];
+        let snapshot = PollSnapshot {
+            function: 4,
+            start_register: 0,
+            data: PollData::Floats(vec![1.0, 2.0, 3.0]),
+        };
+        for tag in &mut tags {
+            tag.function = 4;
+        }
+        assert_eq!(
+            history_values(&tags, None, &snapshot),
+            [
+                ("float".to_string(), 3.0),
+                ("IR0".to_string(), 1.0),
+                ("IR2".to_string(), 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn write_requests_follow_the_tag() {
+        let mut level = tag("level", 5, DataType::Int16, ByteOrder::Abcd);
+        level.scale = 0.1;
+        let request = level.write_request(12.3).unwrap();
+        assert_eq!((request.function, request.register), (6, 5));
+        assert_eq!(request.value, 123.0);
+        // Left for the write to reject rather than rounded away.
+        assert_ne!(level.write_request(12.34).unwrap().value.fract(), 0.0);
+
+        level.scale = 0.0;
+        assert_eq!(
+            level.write_request(1.0),
+            Err("level has a scale of 0.".to_string())
+        );
+
+        let float = tag("float", 0, DataType::F32, ByteOrder::Abcd);
+        assert_eq!(float.write_request(2.5).unwrap().function, 16);
+        let swapped = tag("swapped", 0, DataType::F32, ByteOrder::Dcba);
+        assert!(swapped.write_request(2.5).is_err());
+        let mut coil = tag("pump", 1, DataType::Int16, ByteOrder::Abcd);
+        coil.function = 1;
+        assert_eq!(coil.write_request(1.0).unwrap().function, 5);
+        coil.access = Access::ReadOnly;
+        assert_eq!(
+            coil.write_request(1.0),
+            Err("pump is read only.".to_string())
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, Error, RootCertStore,
+    SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+use utoipa::ToSchema;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Modbus/TCP Security (port 802) certificates, as PEM files on this machine.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct TlsSettings {
+    /// CA bundle the slave's certificate has to chain to.
+    pub ca_file: String,
+    /// Client certificate chain, empty when the slave doesn't ask for one.
+    pub cert_file: String,
+    pub key_file: String,
+    /// Name the slave's certificate is issued for, the address when empty.
+    pub server_name: String,
+    /// Accept a certificate issued for another name, the chain is still checked.
+    pub skip_server_name: bool,
+}
+
+/// What was negotiated with the slave.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct TlsPeer {
+    pub protocol: String,
+    pub cipher_suite: String,
+    pub subject: String,
+    pub issuer: String,
+    pub not_after: String,
+    pub serial: String,
+}
+
+impl fmt::Display for TlsPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}, issued by {}, valid until {}",
+            self.protocol, self.subject, self.issuer, self.not_after
+        )
+    }
+}
+
+impl TlsPeer {
+    fn from_connection(connection: &ClientConnection) -> Option<Self> {
+        let der = connection.peer_certificates()?.first()?;
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        Some(TlsPeer {
+            protocol: connection
+                .protocol_version()
+                .map(|version| format!("{:?}", version))
+                .unwrap_or_default(),
+            cipher_suite: connection
+                .negotiated_cipher_suite()
+                .map(|suite| format!("{:?}", suite.suite()))
+                .unwrap_or_default(),
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            not_after: cert.validity().not_after.to_string(),
+            serial: cert.raw_serial_as_string(),
+        })
+    }
+}
+
+/// Checks the chain like the default verifier but ignores a name mismatch.
+#[derive(Debug)]
+struct IgnoreServerName(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for IgnoreServerName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let result =
+            self.0
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now);
+        match result {
+            Err(Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+pub(crate) fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: No certificates found.", path));
+    }
+    Ok(certs)
+}
+
+pub(crate) fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path, e))?
+        .ok_or_else(|| format!("{}: No private key found.", path))
+}
+
+pub fn client_config(settings: &TlsSettings) -> Result<ClientConfig, String> {
+    if settings.ca_file.is_empty() {
+        return Err("A CA bundle is needed to check the slave's certificate.".to_string());
+    }
+    let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&settings.ca_file)? {
+        roots.add(cert).map_err(|e| e.to_string())?;
+    }
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = if settings.skip_server_name {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(IgnoreServerName(verifier)))
+    } else {
+        builder.with_webpki_verifier(verifier)
+    };
+    if settings.cert_file.is_empty() {
+        return Ok(builder.with_no_client_auth());
+    }
+    let certs = load_certs(&settings.cert_file)?;
+    let key = load_key(&settings.key_file)?;
+    builder
+        .with_client_auth_cert(certs, key)
+        .map_err(|e| e.to_string())
+}
+
+/// Opens the TLS session, `host` is the name the user connected to.
+pub async fn tls_handshake(
+    sock_address: SocketAddr,
+    host: &str,
+    settings: &TlsSettings,
+) -> Result<(TlsStream<TcpStream>, TlsPeer), String> {
+    let config = client_config(settings)?;
+    let name = match settings.server_name.trim() {
+        "" => host,
+        name => name,
+    };
+    let server_name =
+        ServerName::try_from(name.to_string()).map_err(|_| format!("Bad server name: {}", name))?;
+    let stream = TcpStream::connect(sock_address)
+        .await
+        .map_err(|_| "Could not connect to slave!".to_string())?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| format!("TLS handshake failed: {}", e))?;
+    let peer = TlsPeer::from_connection(stream.get_ref().1)
+        .ok_or("The slave sent no readable certificate.")?;
+    Ok((stream, peer))
+}
+
+pub async fn connect_tls(
+    sock_address: SocketAddr,
+    host: &str,
+    settings: &TlsSettings,
+) -> Result<(Context, TlsPeer), String> {
+    let (stream, peer) = tls_handshake(sock_address, host, settings).await?;
+    Ok((tcp::attach_slave(stream, Slave::tcp_device()), peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::{decode_datagram, encode_datagram};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    struct Pki {
+        dir: PathBuf,
+        server: ServerConfig,
+    }
+
+    /// A CA, a slave certificate for `plc1.local` and a client certificate.
+    fn pki(name: &str) -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Plant CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let issue = |san: &str, common_name: &str| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+            (params.signed_by(&key, &ca, &ca_key).unwrap(), key)
+        };
+        let (server_cert, server_key) = issue("plc1.local", "plc1");
+        let (client_cert, client_key) = issue("mptt", "mptt");
+
+        let dir = std::env::temp_dir().join(format!("mptt_tls_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("client.pem"), client_cert.pem()).unwrap();
+        std::fs::write(dir.join("client.key"), client_key.serialize_pem()).unwrap();
+
+        let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client_verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(
+                vec![server_cert.der().clone()],
+                PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+            )
+            .unwrap();
+        Pki { dir, server }
+    }
+
+    impl Pki {
+        fn settings(&self) -> TlsSettings {
+            TlsSettings {
+                ca_file: self.dir.join("ca.pem").to_string_lossy().into(),
+                cert_file: self.dir.join("client.pem").to_string_lossy().into(),
+                key_file: self.dir.join("client.key").to_string_lossy().into(),
+                server_name: String::new(),
+                skip_server_name: false,
+            }
+        }
+    }
+
+    /// Stand-in slave answering every read with a single register holding 42.
+    async fn stand_in(server: ServerConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut request = [0; 12];
+                    if stream.read_exact(&mut request).await.is_err() {
+                        return;
+                    }
+                    let (id, unit, _) = decode_datagram(&request).unwrap();
+                    let response = encode_datagram(id, unit, &[0x03, 0x02, 0x00, 0x2A]);
+                    let _ = stream.write_all(&response).await;
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_with_stand_in_slave() {
+        let pki = pki("mutual");
+        let address = stand_in(pki.server.clone()).await;
+        let settings = TlsSettings {
+            server_name: "plc1.local".to_string(),
+            ..pki.settings()
+        };
+        let (mut stream, peer) = tls_handshake(address, "127.0.0.1", &settings)
+            .await
+            .unwrap();
+        assert_eq!(peer.subject, "CN=plc1");
+        assert_eq!(peer.issuer, "CN=Plant CA");
+        assert!(peer.protocol.starts_with("TLS"));
+
+        let request = encode_datagram(7, 0xFF, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+        stream.write_all(&request).await.unwrap();
+        let mut response = [0; 11];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(
+            decode_datagram(&response),
+            Some((7, 0xFF, &[0x03, 0x02, 0x00, 0x2A][..]))
+        );
+        let _ = std::fs::remove_dir_all(&pki.dir);
+    }
+
+    #[tokio::test]
+    async fn server_name_verification_can_be_skipped() {
+        let pki = pki("name");
+        let address = stand_in(pki.server.clone()).await;
+        // The certificate is issued for plc1.local, not the IP address.
+        let result = tls_handshake(address, "127.0.0.1", &pki.settings()).await;
+        assert!(result.is_err());
+
+        let settings = TlsSettings {
+            skip_server_name: true,
+            ..pki.settings()
+        };
+        let (_, peer) = tls_handshake(address, "127.0.0.1", &settings)
+            .await
+            .unwrap();
+        assert_eq!(peer.subject, "CN=plc1");
+
+        // The chain is still checked.
+        let other = self::pki("other");
+        let settings = TlsSettings {
+            ca_file: other.settings().ca_file,
+            ..settings
+        };
+        assert!(tls_handshake(address, "127.0.0.1", &settings)
+            .await
+            .is_err());
+        let _ = std::fs::remove_dir_all(&pki.dir);
+        let _ = std::fs::remove_dir_all(&other.dir);
+    }
+}
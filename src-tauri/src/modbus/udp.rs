@@ -0,0 +1,192 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::prelude::*;
+
+use super::pdu::{parse_response, request_pdu};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRIES: usize = 2;
+const MAX_DATAGRAM: usize = 260; // MBAP header + 253 byte PDU
+
+/// Modbus TCP frames (MBAP header and PDU) sent one per datagram.
+pub struct UdpClient {
+    socket: UdpSocket,
+    slave: Slave,
+    transaction_id: u16,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl fmt::Debug for UdpClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UdpClient({:?}, {:?})",
+            self.socket.peer_addr(),
+            self.slave
+        )
+    }
+}
+
+impl SlaveContext for UdpClient {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+/// MBAP header followed by the PDU.
+pub fn encode_datagram(transaction_id: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(pdu.len() + 7);
+    datagram.extend_from_slice(&transaction_id.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    datagram.push(unit);
+    datagram.extend_from_slice(pdu);
+    datagram
+}
+
+/// Transaction id, unit id and PDU of a datagram, `None` when it isn't a Modbus frame.
+pub fn decode_datagram(datagram: &[u8]) -> Option<(u16, u8, &[u8])> {
+    let [t0, t1, 0, 0, l0, l1, unit, pdu @ ..] = datagram else {
+        return None;
+    };
+    let length = u16::from_be_bytes([*l0, *l1]) as usize;
+    if pdu.is_empty() || length != pdu.len() + 1 {
+        return None;
+    }
+    Some((u16::from_be_bytes([*t0, *t1]), *unit, pdu))
+}
+
+impl UdpClient {
+    /// Waits for the answer to `transaction_id`, dropping stale and foreign datagrams.
+    async fn receive(&self, transaction_id: u16, deadline: Instant) -> Result<Vec<u8>, Error> {
+        let mut buffer = [0; MAX_DATAGRAM];
+        loop {
+            let received = tokio::time::timeout_at(deadline, self.socket.recv(&mut buffer))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "No response."))??;
+            match decode_datagram(&buffer[..received]) {
+                Some((id, unit, pdu)) if id == transaction_id && unit == self.slave.0 => {
+                    return Ok(pdu.to_vec())
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Sends the request, resending it with the same transaction id after each timeout.
+    async fn exchange(&mut self, pdu: &[u8]) -> Result<Vec<u8>, Error> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let datagram = encode_datagram(self.transaction_id, self.slave.0, pdu);
+        for _ in 0..=self.retries {
+            self.socket.send(&datagram).await?;
+            let deadline = Instant::now() + self.timeout;
+            match self.receive(self.transaction_id, deadline).await {
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                result => return result,
+            }
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("No response after {} attempts.", self.retries + 1),
+        ))
+    }
+}
+
+#[async_trait]
+impl Client for UdpClient {
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let pdu = request_pdu(&request)?;
+        let response = self.exchange(&pdu).await?;
+        Ok(parse_response(&request, &response)?)
+    }
+
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub async fn connect_udp(address: SocketAddr) -> Result<Context, Error> {
+    let local: SocketAddr = match address {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    // Only datagrams from the slave are received on a connected socket.
+    socket.connect(address).await?;
+    let client: Box<dyn Client> = Box::new(UdpClient {
+        socket,
+        slave: Slave::tcp_device(),
+        transaction_id: 0,
+        timeout: RESPONSE_TIMEOUT,
+        retries: RETRIES,
+    });
+    Ok(Context::from(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagram_round_trip() {
+        let datagram = encode_datagram(0x1234, 0xFF, &[0x03, 0x00, 0x00, 0x00, 0x02]);
+        assert_eq!(
+            datagram,
+            [0x12, 0x34, 0x00, 0x00, 0x00, 0x06, 0xFF, 0x03, 0x00, 0x00, 0x00, 0x02]
+        );
+        assert_eq!(
+            decode_datagram(&datagram),
+            Some((0x1234, 0xFF, &[0x03, 0x00, 0x00, 0x00, 0x02][..]))
+        );
+        // Wrong length and protocol id.
+        assert_eq!(decode_datagram(&datagram[..11]), None);
+        assert_eq!(decode_datagram(&[0, 1, 0, 1, 0, 2, 0xFF, 0x03]), None);
+    }
+
+    #[tokio::test]
+    async fn retries_and_matches_transaction_id() {
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(device.local_addr().unwrap()).await.unwrap();
+        let mut client = UdpClient {
+            socket,
+            slave: Slave::tcp_device(),
+            transaction_id: 0,
+            timeout: Duration::from_millis(100),
+            retries: 1,
+        };
+        let device = tokio::spawn(async move {
+            let mut buffer = [0; MAX_DATAGRAM];
+            // The first attempt is lost.
+            device.recv_from(&mut buffer).await.unwrap();
+            let (received, peer) = device.recv_from(&mut buffer).await.unwrap();
+            let (id, unit, pdu) = decode_datagram(&buffer[..received]).unwrap();
+            assert_eq!(
+                (id, unit, pdu),
+                (1, 0xFF, &[0x03, 0x00, 0x0A, 0x00, 0x01][..])
+            );
+            // A stale answer comes first.
+            let stale = encode_datagram(0, 0xFF, &[0x03, 0x02, 0x00, 0x07]);
+            device.send_to(&stale, peer).await.unwrap();
+            let answer = encode_datagram(1, 0xFF, &[0x03, 0x02, 0x00, 0x2A]);
+            device.send_to(&answer, peer).await.unwrap();
+        });
+        let response = client.call(Request::ReadHoldingRegisters(10, 1)).await;
+        assert_eq!(
+            response.unwrap().unwrap(),
+            Response::ReadHoldingRegisters(vec![42])
+        );
+        device.await.unwrap();
+
+        // Nobody answers now.
+        let response = client.call(Request::ReadHoldingRegisters(10, 1)).await;
+        assert!(response.is_err());
+    }
+}
@@ -0,0 +1,722 @@
+use axum::extract::{Form, State};
+use maud::{html, Markup};
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use utoipa::ToSchema;
+
+use crate::modbus::{
+    modbus_status_bar, write_value, ModbusState, Tag, WriteRequest, MARGIN, WINDOW_WIDTH,
+};
+use crate::server::Shutdown;
+
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const REQUEST_CAPACITY: usize = 256;
+/// How long the disconnect gets to reach the broker on shutdown.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    /// `{"tag", "value", "timestamp", "connection"}`
+    #[default]
+    Json,
+    /// Just the value.
+    Plain,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishMode {
+    #[default]
+    OnChange,
+    /// Every tag each `interval_secs`, changed or not.
+    Periodic,
+}
+
+/// Broker connection and publishing options, saved with the project.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// No authentication when empty.
+    pub username: String,
+    /// Never sent back by the API, an empty one keeps the saved password for the same user.
+    #[schema(write_only)]
+    pub password: String,
+    /// `{connection}`, `{project}` and `{tag}` are replaced.
+    pub topic: String,
+    pub payload: PayloadFormat,
+    pub qos: u8,
+    pub retain: bool,
+    pub mode: PublishMode,
+    pub interval_secs: u64,
+    /// Writes are accepted here when set, results go to `<command_topic>/result`.
+    pub command_topic: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "mptt".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic: "site/{connection}/{tag}".to_string(),
+            payload: PayloadFormat::Json,
+            qos: 0,
+            retain: false,
+            mode: PublishMode::OnChange,
+            interval_secs: 10,
+            command_topic: String::new(),
+        }
+    }
+}
+
+fn quality_of_service(qos: u8) -> Result<QoS, String> {
+    match qos {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => Err("QoS must be 0, 1 or 2.".to_string()),
+    }
+}
+
+impl MqttSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        quality_of_service(self.qos)?;
+        if self.host.trim().is_empty() || self.client_id.trim().is_empty() {
+            return Err("The broker needs a host and a client id.".to_string());
+        }
+        if self.topic.trim().is_empty() || self.topic.contains(['+', '#']) {
+            return Err("The topic can't be empty or contain wildcards.".to_string());
+        }
+        if self.mode == PublishMode::Periodic && self.interval_secs == 0 {
+            return Err("Periodic publishing needs an interval.".to_string());
+        }
+        Ok(())
+    }
+
+    /// A copy without the password, for anything a viewer can see.
+    pub fn redacted(&self) -> MqttSettings {
+        MqttSettings {
+            password: String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Takes the password of `saved` when none was entered and the user is the same.
+    pub fn keep_password(&mut self, saved: &MqttSettings) {
+        if self.password.is_empty() && self.username == saved.username {
+            self.password = saved.password.clone();
+        }
+    }
+}
+
+/// Topic levels can't contain separators or wildcards.
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#', ' '], "_")
+}
+
+pub fn render_topic(template: &str, connection: &str, project: &str, tag: &str) -> String {
+    template
+        .replace("{connection}", &topic_level(connection))
+        .replace("{project}", &topic_level(project))
+        .replace("{tag}", &topic_level(tag))
+}
+
+/// Values of one poll, see `record_values`.
+#[derive(Clone, Debug)]
+pub struct Batch {
+    pub timestamp: i64,
+    pub connection: String,
+    pub project: String,
+    pub values: Vec<(String, f64)>,
+}
+
+/// Turns batches into messages according to the publish mode.
+struct Publisher {
+    settings: MqttSettings,
+    published: HashMap<String, f64>,
+    /// Latest message per topic for periodic publishing.
+    latest: HashMap<String, Vec<u8>>,
+}
+
+impl Publisher {
+    fn new(settings: MqttSettings) -> Self {
+        Publisher {
+            settings,
+            published: HashMap::new(),
+            latest: HashMap::new(),
+        }
+    }
+
+    fn payload(&self, batch: &Batch, tag: &str, value: f64) -> Vec<u8> {
+        match self.settings.payload {
+            PayloadFormat::Plain => value.to_string().into_bytes(),
+            PayloadFormat::Json => serde_json::json!({
+                "tag": tag,
+                "value": value,
+                "timestamp": batch.timestamp,
+                "connection": batch.connection,
+            })
+            .to_string()
+            .into_bytes(),
+        }
+    }
+
+    /// Messages to publish now.
+    fn on_batch(&mut self, batch: &Batch) -> Vec<(String, Vec<u8>)> {
+        let mut messages = Vec::new();
+        for (tag, value) in &batch.values {
+            let topic = render_topic(&self.settings.topic, &batch.connection, &batch.project, tag);
+            let payload = self.payload(batch, tag, *value);
+            match self.settings.mode {
+                PublishMode::Periodic => {
+                    self.latest.insert(topic, payload);
+                }
+                PublishMode::OnChange => {
+                    if self.published.insert(topic.clone(), *value) != Some(*value) {
+                        messages.push((topic, payload));
+                    }
+                }
+            }
+        }
+        messages
+    }
+
+    fn on_interval(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.latest
+            .iter()
+            .map(|(topic, payload)| (topic.clone(), payload.clone()))
+            .collect()
+    }
+}
+
+/// A write received on the command topic.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum MqttCommand {
+    /// Scaled value of a writable tag.
+    Tag {
+        tag: String,
+        value: f64,
+    },
+    Register(WriteRequest),
+}
+
+pub fn parse_command(payload: &[u8], tags: &[Tag]) -> Result<WriteRequest, String> {
+    let command: MqttCommand =
+        serde_json::from_slice(payload).map_err(|e| format!("Bad command: {}", e))?;
+    match command {
+        MqttCommand::Register(request) => Ok(request),
+        MqttCommand::Tag { tag, value } => tags
+            .iter()
+            .find(|t| t.name == tag)
+            .ok_or_else(|| format!("Unknown tag: {}", tag))?
+            .write_request(value),
+    }
+}
+
+pub type MqttStatus = Arc<std::sync::Mutex<String>>;
+
+/// Handed to the poller's values, empty while the bridge is stopped.
+#[derive(Default)]
+pub struct MqttFeed {
+    values: Option<mpsc::UnboundedSender<Batch>>,
+    pub status: MqttStatus,
+}
+
+impl MqttFeed {
+    pub fn is_running(&self) -> bool {
+        self.values.is_some()
+    }
+
+    pub fn publish(&self, batch: Batch) {
+        if let Some(values) = &self.values {
+            let _ = values.send(batch);
+        }
+    }
+}
+
+fn set_status(status: &MqttStatus, text: String) {
+    if let Ok(mut status) = status.lock() {
+        *status = text;
+    }
+}
+
+/// A connected client, dropping it stops publishing.
+struct Bridge {
+    settings: MqttSettings,
+    client: AsyncClient,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = self.client.try_disconnect();
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Bridge {
+    fn start(
+        settings: MqttSettings,
+        commands: mpsc::UnboundedSender<Vec<u8>>,
+        status: MqttStatus,
+    ) -> Result<(Bridge, mpsc::UnboundedSender<Batch>), String> {
+        let qos = quality_of_service(settings.qos)?;
+        let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if !settings.username.is_empty() {
+            options.set_credentials(&settings.username, &settings.password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+
+        let events = {
+            let (client, command_topic) = (client.clone(), settings.command_topic.clone());
+            let broker = format!("{}:{}", settings.host, settings.port);
+            let status = status.clone();
+            tokio::spawn(async move {
+                loop {
+                    match eventloop.poll().await {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            set_status(&status, format!("Connected to {}", broker));
+                            // Subscriptions don't survive a reconnect with a clean session.
+                            if !command_topic.is_empty() {
+                                let _ = client.try_subscribe(&command_topic, qos);
+                            }
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish)))
+                            if publish.topic == command_topic =>
+                        {
+                            let _ = commands.send(publish.payload.to_vec());
+                        }
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+                        Ok(_) => {}
+                        Err(e) => {
+                            set_status(&status, format!("Error: {}", e));
+                            tokio::time::sleep(RETRY_DELAY).await;
+                        }
+                    }
+                }
+            })
+        };
+
+        let (values, mut batches) = mpsc::unbounded_channel::<Batch>();
+        let publisher = {
+            let client = client.clone();
+            let (retain, mode) = (settings.retain, settings.mode);
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(settings.interval_secs.max(1)));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut publisher = Publisher::new(settings.clone());
+            let status = status.clone();
+            tokio::spawn(async move {
+                loop {
+                    let messages = tokio::select! {
+                        batch = batches.recv() => match batch {
+                            Some(batch) => publisher.on_batch(&batch),
+                            None => return,
+                        },
+                        _ = interval.tick(), if mode == PublishMode::Periodic => publisher.on_interval(),
+                    };
+                    for (topic, payload) in messages {
+                        // Requests queue up while the broker is away, the newest are dropped when full.
+                        if let Err(e) = client.try_publish(topic, qos, retain, payload) {
+                            set_status(&status, format!("Could not publish: {}", e));
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok((
+            Bridge {
+                settings,
+                client,
+                tasks: vec![events, publisher],
+            },
+            values,
+        ))
+    }
+
+    /// Disconnects from the broker, waiting for the event loop to send it.
+    async fn stop(mut self) {
+        if self.client.disconnect().await.is_ok() {
+            let events = self.tasks.remove(0);
+            let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, events).await;
+        }
+    }
+
+    fn reply(&self, result: &Result<String, String>) {
+        let topic = format!("{}/result", self.settings.command_topic);
+        let payload = match result {
+            Ok(message) => serde_json::json!({ "ok": true, "message": message }),
+            Err(error) => serde_json::json!({ "ok": false, "message": error }),
+        };
+        let _ = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, false, payload.to_string());
+    }
+}
+
+/// Runs the bridge configured in the project and carries out its write commands.
+pub async fn run_mqtt(mtx: Arc<Mutex<ModbusState>>, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(SUPERVISE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let (commands_sender, mut commands) = mpsc::unbounded_channel::<Vec<u8>>();
+    let mut bridge: Option<Bridge> = None;
+    // Settings that could not start, not retried until they change.
+    let mut failed: Option<MqttSettings> = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut state = mtx.lock().await;
+                let settings = &state.project.mqtt;
+                let wanted = settings.enabled.then(|| settings.clone());
+                if bridge.as_ref().map(|b| &b.settings) == wanted.as_ref()
+                    || failed.is_some() && failed == wanted
+                {
+                    continue;
+                }
+                bridge = None;
+                failed = None;
+                state.mqtt.values = None;
+                let status = state.mqtt.status.clone();
+                let Some(settings) = wanted else {
+                    set_status(&status, "Stopped".to_string());
+                    continue;
+                };
+                set_status(&status, "Connecting...".to_string());
+                match Bridge::start(settings.clone(), commands_sender.clone(), status.clone()) {
+                    Ok((started, values)) => {
+                        bridge = Some(started);
+                        state.mqtt.values = Some(values);
+                    }
+                    Err(e) => {
+                        set_status(&status, format!("Error: {}", e));
+                        failed = Some(settings);
+                    }
+                }
+            }
+            Some(payload) = commands.recv() => {
+                let result = {
+                    let mut state = mtx.lock().await;
+                    match parse_command(&payload, &state.project.tags) {
+                        Ok(request) => write_value(&mut state, &request, "mqtt").await,
+                        Err(e) => Err(e),
+                    }
+                };
+                if let Some(bridge) = &bridge {
+                    bridge.reply(&result);
+                }
+            }
+            _ = shutdown.requested() => {
+                if let Some(bridge) = bridge.take() {
+                    bridge.stop().await;
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MqttForm {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    pub topic: String,
+    pub payload: PayloadFormat,
+    pub qos: u8,
+    pub retain: Option<String>,
+    pub mode: PublishMode,
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub command_topic: String,
+}
+
+impl From<MqttForm> for MqttSettings {
+    fn from(form: MqttForm) -> Self {
+        MqttSettings {
+            enabled: true,
+            host: form.host.trim().to_string(),
+            port: form.port,
+            client_id: form.client_id.trim().to_string(),
+            username: form.username,
+            password: form.password,
+            topic: form.topic.trim().to_string(),
+            payload: form.payload,
+            qos: form.qos,
+            retain: form.retain.is_some(),
+            mode: form.mode,
+            interval_secs: form.interval_secs,
+            command_topic: form.command_topic.trim().to_string(),
+        }
+    }
+}
+
+fn mqtt_status(status: &str) -> Markup {
+    html! {
+        #mqtt_status {
+            div hx-get="/mqtt_status" hx-trigger="load delay:2s" hx-target="#mqtt_status" hx-swap="outerHTML" {}
+            p { (format!("MQTT: {}", status)) }
+        }
+    }
+}
+
+fn status_text(state: &ModbusState) -> String {
+    state
+        .mqtt
+        .status
+        .lock()
+        .map(|status| status.clone())
+        .ok()
+        .filter(|status| !status.is_empty())
+        .unwrap_or_else(|| "Stopped".to_string())
+}
+
+pub async fn mqtt_status_fragment(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    mqtt_status(&status_text(&*mtx.lock().await))
+}
+
+pub async fn start_mqtt(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<MqttForm>,
+) -> Markup {
+    let mut settings = MqttSettings::from(form_input);
+    if let Err(e) = settings.validate() {
+        return mqtt_status(&format!("Error: {}", e));
+    }
+    let mut mtx = mtx.lock().await;
+    settings.keep_password(&mtx.project.mqtt);
+    mtx.project.mqtt = settings;
+    mqtt_status("Starting...")
+}
+
+pub async fn stop_mqtt(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let mut mtx = mtx.lock().await;
+    mtx.project.mqtt.enabled = false;
+    mqtt_status("Stopping...")
+}
+
+pub fn mqtt_body(state: &ModbusState) -> Markup {
+    let settings = &state.project.mqtt;
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "MQTT Bridge" }
+                    }
+                    div class="window-body" {
+                        form hx-post="/start_mqtt" hx-target="#mqtt_status" hx-swap="outerHTML" {
+                            fieldset {
+                                legend { "Broker" }
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="host" { "Host: " }
+                                    input type="text" id="host" name="host" value=(settings.host) {}
+                                    label for="port" { "Port: " }
+                                    input type="number" id="port" name="port" value=(settings.port) {}
+                                    label for="client_id" { "Client ID: " }
+                                    input type="text" id="client_id" name="client_id" value=(settings.client_id) {}
+                                    label for="username" { "Username: (optional)" }
+                                    input type="text" id="username" name="username" value=(settings.username) {}
+                                    label for="password" { "Password: (empty keeps the saved one)" }
+                                    input type="password" id="password" name="password" {}
+                                }
+                            }
+                            fieldset {
+                                legend { "Publishing" }
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="topic" { "Topic: ({connection}, {project}, {tag})" }
+                                    input type="text" id="topic" name="topic" value=(settings.topic) {}
+                                    label for="payload" { "Payload: " }
+                                    select id="payload" name="payload" {
+                                        option value="json" selected[settings.payload == PayloadFormat::Json] { "JSON" }
+                                        option value="plain" selected[settings.payload == PayloadFormat::Plain] { "Plain value" }
+                                    }
+                                    label for="qos" { "QoS: " }
+                                    select id="qos" name="qos" {
+                                        @for qos in 0..=2u8 {
+                                            option value=(qos) selected[settings.qos == qos] { (qos) }
+                                        }
+                                    }
+                                    label for="mode" { "Publish: " }
+                                    select id="mode" name="mode" {
+                                        option value="on_change" selected[settings.mode == PublishMode::OnChange] { "On change" }
+                                        option value="periodic" selected[settings.mode == PublishMode::Periodic] { "Periodic" }
+                                    }
+                                    label for="interval_secs" { "Interval: (s, periodic only)" }
+                                    input type="number" id="interval_secs" name="interval_secs" value=(settings.interval_secs) {}
+                                    label for="command_topic" { "Command topic: (empty for no writes)" }
+                                    input type="text" id="command_topic" name="command_topic" value=(settings.command_topic) {}
+                                }
+                                div class="field-row" {
+                                    input type="checkbox" id="retain" name="retain" checked[settings.retain] {}
+                                    label for="retain" { "Retain" }
+                                }
+                            }
+                            div class="field-row" {
+                                button { "Start" }
+                                button type="button" hx-get="/stop_mqtt" hx-target="#mqtt_status" hx-swap="outerHTML" { "Stop" }
+                            }
+                        }
+                        (mqtt_status(&status_text(state)))
+                        p { "Commands are JSON, {\"tag\": \"Setpoint\", \"value\": 12.5} for a writable tag or {\"function\": 6, \"register\": 10, \"value\": 42}." }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::{Access, ByteOrder, DataType, ProtocolOpts};
+
+    fn batch(values: &[(&str, f64)]) -> Batch {
+        Batch {
+            timestamp: 1000,
+            connection: "TCP 10.0.0.5:502".to_string(),
+            project: "Pump station".to_string(),
+            values: values.iter().map(|(t, v)| (t.to_string(), *v)).collect(),
+        }
+    }
+
+    #[test]
+    fn publishes_on_change_or_periodically() {
+        let mut publisher = Publisher::new(MqttSettings {
+            payload: PayloadFormat::Plain,
+            ..MqttSettings::default()
+        });
+        let messages = publisher.on_batch(&batch(&[("Level", 1.5), ("Flow/In", 2.0)]));
+        assert_eq!(
+            messages,
+            [
+                ("site/TCP_10.0.0.5:502/Level".to_string(), b"1.5".to_vec()),
+                ("site/TCP_10.0.0.5:502/Flow_In".to_string(), b"2".to_vec())
+            ]
+        );
+        let messages = publisher.on_batch(&batch(&[("Level", 1.5), ("Flow/In", 3.0)]));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "site/TCP_10.0.0.5:502/Flow_In");
+
+        let mut publisher = Publisher::new(MqttSettings {
+            topic: "{project}/{tag}".to_string(),
+            mode: PublishMode::Periodic,
+            ..MqttSettings::default()
+        });
+        assert!(publisher.on_batch(&batch(&[("Level", 1.5)])).is_empty());
+        publisher.on_batch(&batch(&[("Level", 1.5)]));
+        let messages = publisher.on_interval();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "Pump_station/Level");
+        let payload: serde_json::Value = serde_json::from_slice(&messages[0].1).unwrap();
+        assert_eq!(payload["value"], 1.5);
+        assert_eq!(payload["connection"], "TCP 10.0.0.5:502");
+    }
+
+    #[test]
+    fn commands_write_registers_or_writable_tags() {
+        let setpoint = Tag {
+            name: "Setpoint".to_string(),
+            function: 3,
+            register: 20,
+            data_type: DataType::Int16,
+            scale: 0.1,
+            unit: String::new(),
+            access: Access::ReadWrite,
+            byte_order: ByteOrder::Abcd,
+            unit_id: None,
+        };
+        let level = Tag {
+            name: "Level".to_string(),
+            access: Access::ReadOnly,
+            ..setpoint.clone()
+        };
+        let tags = [setpoint, level];
+
+        let request = parse_command(br#"{"tag": "Setpoint", "value": 12.5}"#, &tags).unwrap();
+        assert_eq!((request.function, request.register), (6, 20));
+        assert!((request.value - 125.0).abs() < 1e-9);
+        let request =
+            parse_command(br#"{"function": 5, "register": 3, "value": 1}"#, &tags).unwrap();
+        assert_eq!((request.function, request.register), (5, 3));
+
+        assert!(parse_command(br#"{"tag": "Level", "value": 1}"#, &tags).is_err());
+        assert!(parse_command(br#"{"tag": "Missing", "value": 1}"#, &tags).is_err());
+        assert!(parse_command(b"42", &tags).is_err());
+    }
+
+    #[test]
+    fn keeps_the_password_out_of_sight() {
+        let saved = MqttSettings {
+            username: "plant".to_string(),
+            password: "s3cret".to_string(),
+            ..MqttSettings::default()
+        };
+        let mut state = ModbusState::new(ProtocolOpts::default(), None);
+        state.project.mqtt = saved.clone();
+        assert!(!mqtt_body(&state).into_string().contains("s3cret"));
+        let json = serde_json::to_string(&saved.redacted()).unwrap();
+        assert!(!json.contains("s3cret"));
+
+        let mut edited = saved.redacted();
+        edited.keep_password(&saved);
+        assert_eq!(edited, saved);
+        let mut other_user = MqttSettings {
+            username: "ops".to_string(),
+            ..saved.redacted()
+        };
+        other_user.keep_password(&saved);
+        assert_eq!(other_user.password, "");
+    }
+
+    #[tokio::test]
+    async fn a_failed_start_leaves_the_project_alone() {
+        let mut state = ModbusState::new(ProtocolOpts::default(), None);
+        state.project.mqtt = MqttSettings {
+            enabled: true,
+            qos: 3,
+            ..MqttSettings::default()
+        };
+        let status = state.mqtt.status.clone();
+        let mtx = Arc::new(Mutex::new(state));
+        let (stop, shutdown) = Shutdown::channel();
+        let supervisor = tokio::spawn(run_mqtt(mtx.clone(), shutdown));
+        for _ in 0..100 {
+            if status.lock().unwrap().starts_with("Error") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*status.lock().unwrap(), "Error: QoS must be 0, 1 or 2.");
+        assert!(mtx.lock().await.project.mqtt.enabled);
+        assert!(!mtx.lock().await.mqtt.is_running());
+        stop.send(true).unwrap();
+        supervisor.await.unwrap();
+    }
+}
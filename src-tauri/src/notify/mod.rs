@@ -0,0 +1,646 @@
+use axum::extract::{Form, State};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::{mpsc, Mutex};
+use utoipa::ToSchema;
+
+use crate::alarms::AlarmEvent;
+use crate::audit::AuditEntry;
+use crate::historian::now_millis;
+use crate::modbus::{modbus_status_bar, LinkState, ModbusState, MARGIN, TABLE_WIDTH, WINDOW_WIDTH};
+use crate::server::Shutdown;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const LOG_CAPACITY: usize = 200;
+
+/// What a notification is about, posted as the webhook's JSON body.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum NotificationEvent {
+    Alarm(AlarmEvent),
+    Connection {
+        timestamp: i64,
+        /// The connection it happened to, e.g. `TCP 10.0.0.5:502`.
+        connection: String,
+        state: LinkState,
+    },
+    /// Sent from the notifications page to check the sinks.
+    Test {
+        timestamp: i64,
+    },
+    /// A write went out but the audit log could not record it.
+    Audit {
+        error: String,
+        entry: AuditEntry,
+    },
+}
+
+impl NotificationEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            NotificationEvent::Alarm(_) => "alarm",
+            NotificationEvent::Connection { .. } => "connection",
+            NotificationEvent::Test { .. } => "test",
+            NotificationEvent::Audit { .. } => "audit",
+        }
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            NotificationEvent::Alarm(alarm) => format!("{} {:?}", alarm.name, alarm.kind),
+            NotificationEvent::Connection { state, .. } => state.to_string(),
+            NotificationEvent::Test { .. } => "Test".to_string(),
+            NotificationEvent::Audit { error, .. } => format!("Unaudited write: {}", error),
+        }
+    }
+
+    /// Environment of a command sink, every name starts with `MPTT_`.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        let payload = serde_json::to_string(self).unwrap_or_default();
+        let mut variables = vec![
+            ("MPTT_EVENT", self.name().to_string()),
+            ("MPTT_PAYLOAD", payload),
+        ];
+        match self {
+            NotificationEvent::Alarm(alarm) => variables.extend([
+                ("MPTT_TIMESTAMP", alarm.timestamp.to_string()),
+                ("MPTT_ALARM", alarm.name.clone()),
+                ("MPTT_TAG", alarm.tag.clone()),
+                ("MPTT_KIND", format!("{:?}", alarm.kind).to_lowercase()),
+                (
+                    "MPTT_VALUE",
+                    alarm.value.map(|v| v.to_string()).unwrap_or_default(),
+                ),
+                ("MPTT_MESSAGE", alarm.message.clone()),
+            ]),
+            NotificationEvent::Connection {
+                timestamp,
+                connection,
+                state,
+            } => variables.extend([
+                ("MPTT_TIMESTAMP", timestamp.to_string()),
+                ("MPTT_CONNECTION", connection.clone()),
+                ("MPTT_STATE", state.to_string()),
+            ]),
+            NotificationEvent::Test { timestamp } => {
+                variables.push(("MPTT_TIMESTAMP", timestamp.to_string()))
+            }
+            NotificationEvent::Audit { error, entry } => variables.extend([
+                ("MPTT_TIMESTAMP", entry.timestamp.to_string()),
+                ("MPTT_CONNECTION", entry.connection.clone()),
+                ("MPTT_MESSAGE", entry.result.clone()),
+                ("MPTT_ERROR", error.clone()),
+            ]),
+        }
+        variables
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkTarget {
+    /// JSON `POST` of the event.
+    Webhook { url: String },
+    /// Runs `program` with the event in `MPTT_*` environment variables.
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Where events are delivered, saved with the project.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct NotificationSink {
+    pub name: String,
+    pub target: SinkTarget,
+    #[serde(default = "default_true")]
+    pub alarms: bool,
+    #[serde(default = "default_true")]
+    pub connection: bool,
+}
+
+impl NotificationSink {
+    fn wants(&self, event: &NotificationEvent) -> bool {
+        match event {
+            NotificationEvent::Alarm(_) => self.alarms,
+            NotificationEvent::Connection { .. } => self.connection,
+            // Every sink hears of a write missing from the audit log.
+            NotificationEvent::Test { .. } | NotificationEvent::Audit { .. } => true,
+        }
+    }
+}
+
+/// One delivered or failed notification.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Delivery {
+    pub timestamp: i64,
+    pub sink: String,
+    pub event: String,
+    pub attempts: u32,
+    /// The last error, none when the notification was delivered.
+    pub error: Option<String>,
+}
+
+pub type DeliveryLog = Arc<std::sync::Mutex<VecDeque<Delivery>>>;
+
+/// Hands events to the notification task, does nothing until `run_notifier` connects it.
+#[derive(Clone, Debug, Default)]
+pub struct Notifier(Option<mpsc::UnboundedSender<NotificationEvent>>);
+
+impl Notifier {
+    pub fn new(events: mpsc::UnboundedSender<NotificationEvent>) -> Self {
+        Notifier(Some(events))
+    }
+
+    pub fn notify(&self, event: NotificationEvent) {
+        if let Some(sender) = &self.0 {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+async fn send_webhook(
+    client: &reqwest::Client,
+    url: &str,
+    event: &NotificationEvent,
+) -> Result<(), String> {
+    let response = client
+        .post(url)
+        .json(event)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("HTTP {}", response.status()))
+    }
+}
+
+async fn run_command(
+    program: &str,
+    args: &[String],
+    event: &NotificationEvent,
+) -> Result<(), String> {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .envs(event.variables())
+        .kill_on_drop(true);
+    let status = tokio::time::timeout(DELIVERY_TIMEOUT, command.status())
+        .await
+        .map_err(|_| "Command timed out.".to_string())?
+        .map_err(|e| e.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Command failed: {}", status))
+    }
+}
+
+/// Tries `ATTEMPTS` times, waiting `retry_delay` longer after each failure.
+pub async fn deliver(
+    client: &reqwest::Client,
+    sink: &NotificationSink,
+    event: &NotificationEvent,
+    retry_delay: Duration,
+) -> Delivery {
+    let mut delivery = Delivery {
+        timestamp: now_millis(),
+        sink: sink.name.clone(),
+        event: event.summary(),
+        attempts: 0,
+        error: None,
+    };
+    while delivery.attempts < ATTEMPTS {
+        if delivery.attempts > 0 {
+            tokio::time::sleep(retry_delay * delivery.attempts).await;
+        }
+        delivery.attempts += 1;
+        let result = match &sink.target {
+            SinkTarget::Webhook { url } => send_webhook(client, url, event).await,
+            SinkTarget::Command { program, args } => run_command(program, args, event).await,
+        };
+        match result {
+            Ok(()) => {
+                delivery.error = None;
+                break;
+            }
+            Err(e) => {
+                println!(
+                    "Notification to {} failed (attempt {}): {}",
+                    sink.name, delivery.attempts, e
+                );
+                delivery.error = Some(e);
+            }
+        }
+    }
+    delivery
+}
+
+/// Delivers alarm and connection events to the project's sinks.
+pub async fn run_notifier(mtx: Arc<Mutex<ModbusState>>, mut shutdown: Shutdown) {
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let log = {
+        let mut state = mtx.lock().await;
+        state.link.notifier = Notifier::new(sender.clone());
+        state.alarms.notifier = Notifier::new(sender.clone());
+        state.notifier = Notifier::new(sender);
+        state.deliveries.clone()
+    };
+    let client = match reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            println!("Notifications are disabled: {}", e);
+            return;
+        }
+    };
+    loop {
+        let event = tokio::select! {
+            Some(event) = events.recv() => event,
+            _ = shutdown.requested() => return,
+        };
+        let sinks: Vec<NotificationSink> = {
+            let state = mtx.lock().await;
+            state
+                .project
+                .notifications
+                .iter()
+                .filter(|sink| sink.wants(&event))
+                .cloned()
+                .collect()
+        };
+        // A slow or unreachable sink doesn't hold up the others.
+        for sink in sinks {
+            let (client, event, log) = (client.clone(), event.clone(), log.clone());
+            tokio::spawn(async move {
+                let delivery = deliver(&client, &sink, &event, RETRY_DELAY).await;
+                if let Ok(mut log) = log.lock() {
+                    if log.len() == LOG_CAPACITY {
+                        log.pop_front();
+                    }
+                    log.push_back(delivery);
+                }
+            });
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationForm {
+    pub name: String,
+    /// `webhook` or `command`.
+    pub kind: String,
+    /// URL of a webhook, or the program and its arguments.
+    pub target: String,
+    pub alarms: Option<String>,
+    pub connection: Option<String>,
+}
+
+impl TryFrom<NotificationForm> for NotificationSink {
+    type Error = String;
+
+    fn try_from(form: NotificationForm) -> Result<Self, String> {
+        let name = form.name.trim().to_string();
+        if name.is_empty() {
+            return Err("A notification needs a name.".to_string());
+        }
+        let target = match form.kind.as_str() {
+            "webhook" => {
+                let url = form.target.trim().to_string();
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err("The webhook URL must start with http:// or https://".to_string());
+                }
+                SinkTarget::Webhook { url }
+            }
+            "command" => {
+                let mut words = form.target.split_whitespace().map(str::to_string);
+                let Some(program) = words.next() else {
+                    return Err("The command is empty.".to_string());
+                };
+                SinkTarget::Command {
+                    program,
+                    args: words.collect(),
+                }
+            }
+            other => return Err(format!("Unknown notification type: {}", other)),
+        };
+        Ok(NotificationSink {
+            name,
+            target,
+            alarms: form.alarms.is_some(),
+            connection: form.connection.is_some(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationNameForm {
+    pub name: String,
+}
+
+fn sinks_fragment(sinks: &[NotificationSink], status: &str) -> Markup {
+    html! {
+        #notification_sinks {
+            table class="interactive" {
+                thead {
+                    tr {
+                        th { "Name" }
+                        th { "Target" }
+                        th { "Alarms" }
+                        th { "Connection" }
+                        th { "" }
+                    }
+                }
+                tbody {
+                    @for sink in sinks {
+                        tr {
+                            td { (sink.name) }
+                            td {
+                                @match &sink.target {
+                                    SinkTarget::Webhook { url } => (format!("POST {}", url)),
+                                    SinkTarget::Command { program, args } => (format!("{} {}", program, args.join(" "))),
+                                }
+                            }
+                            td { (if sink.alarms { "Yes" } else { "No" }) }
+                            td { (if sink.connection { "Yes" } else { "No" }) }
+                            td {
+                                button hx-post="/delete_notification" hx-vals=(serde_json::json!({ "name": sink.name }).to_string()) hx-target="#notification_sinks" hx-swap="outerHTML" { "Delete" }
+                            }
+                        }
+                    }
+                }
+            }
+            p { (status) }
+        }
+    }
+}
+
+fn deliveries_fragment(log: &DeliveryLog) -> Markup {
+    let deliveries: Vec<Delivery> = log
+        .lock()
+        .map(|log| log.iter().rev().cloned().collect())
+        .unwrap_or_default();
+    html! {
+        #notification_log {
+            div hx-get="/notification_log" hx-trigger="load delay:2s" hx-target="#notification_log" hx-swap="outerHTML" {}
+            table class="interactive" {
+                thead {
+                    tr {
+                        th { "Sink" }
+                        th { "Event" }
+                        th { "Attempts" }
+                        th { "Result" }
+                    }
+                }
+                tbody {
+                    @for delivery in &deliveries {
+                        tr {
+                            td { (delivery.sink) }
+                            td { (delivery.event) }
+                            td { (delivery.attempts) }
+                            td { (delivery.error.as_deref().unwrap_or("Delivered")) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub async fn add_notification(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<NotificationForm>,
+) -> Markup {
+    let mut mtx = mtx.lock().await;
+    let status = match NotificationSink::try_from(form_input) {
+        Ok(sink) => {
+            let status = format!("Saved {}, save the project to keep it.", sink.name);
+            let sinks = &mut mtx.project.notifications;
+            match sinks.iter_mut().find(|s| s.name == sink.name) {
+                Some(existing) => *existing = sink,
+                None => sinks.push(sink),
+            }
+            status
+        }
+        Err(e) => e,
+    };
+    sinks_fragment(&mtx.project.notifications, &status)
+}
+
+pub async fn delete_notification(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<NotificationNameForm>,
+) -> Markup {
+    let mut mtx = mtx.lock().await;
+    mtx.project
+        .notifications
+        .retain(|sink| sink.name != form_input.name);
+    sinks_fragment(
+        &mtx.project.notifications,
+        &format!("Deleted {}.", form_input.name),
+    )
+}
+
+pub async fn test_notification(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    mtx.notifier.notify(NotificationEvent::Test {
+        timestamp: now_millis(),
+    });
+    sinks_fragment(
+        &mtx.project.notifications,
+        "Test event sent, see the delivery log.",
+    )
+}
+
+pub async fn notification_log(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    deliveries_fragment(&mtx.lock().await.deliveries)
+}
+
+pub fn notifications_body(state: &ModbusState) -> Markup {
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Notifications" }
+                    }
+                    div class="window-body" {
+                        fieldset {
+                            legend { "Sinks" }
+                            (sinks_fragment(&state.project.notifications, ""))
+                            form hx-post="/add_notification" hx-target="#notification_sinks" hx-swap="outerHTML" {
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="name" { "Name: " }
+                                    input type="text" id="name" name="name" {}
+                                    label for="kind" { "Type: " }
+                                    select id="kind" name="kind" {
+                                        option value="webhook" { "Webhook (JSON POST)" }
+                                        option value="command" { "Command (MPTT_* variables)" }
+                                    }
+                                    label for="target" { "URL or command line: " }
+                                    input type="text" id="target" name="target" {}
+                                }
+                                div class="field-row" {
+                                    input type="checkbox" id="alarms" name="alarms" checked {}
+                                    label for="alarms" { "Alarm events" }
+                                    input type="checkbox" id="connection" name="connection" checked {}
+                                    label for="connection" { "Connection events" }
+                                }
+                                div class="field-row" {
+                                    button { "Save" }
+                                    button type="button" hx-post="/test_notification" hx-target="#notification_sinks" hx-swap="outerHTML" { "Send Test" }
+                                }
+                            }
+                        }
+                        fieldset {
+                            legend { "Delivery Log" }
+                            div class="sunken-panel" style=(format!("height: 200px; width: {}px", TABLE_WIDTH)) {
+                                (deliveries_fragment(&state.deliveries))
+                            }
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarms::AlarmEventKind;
+    use crate::modbus::Link;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn alarm_event() -> NotificationEvent {
+        NotificationEvent::Alarm(AlarmEvent {
+            timestamp: 1000,
+            name: "High level".to_string(),
+            tag: "Level".to_string(),
+            kind: AlarmEventKind::Raised,
+            value: Some(81.5),
+            message: "Tank almost full".to_string(),
+        })
+    }
+
+    #[test]
+    fn connection_events_name_their_connection() {
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let mut link = Link::default();
+        link.notifier = Notifier(Some(sender));
+        link.connecting("127.0.0.1:502".to_string());
+        // Disconnecting clears the state's settings before the notifier sees the event.
+        link.disconnected();
+        let Ok(NotificationEvent::Connection {
+            connection, state, ..
+        }) = events.try_recv()
+        else {
+            panic!("no connection event");
+        };
+        assert_eq!(connection, "127.0.0.1:502");
+        assert_eq!(state, LinkState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn webhook_retries_until_delivered() {
+        type Received = Arc<std::sync::Mutex<Vec<serde_json::Value>>>;
+        let received: Received = Default::default();
+        let handler = {
+            let received = received.clone();
+            let calls = Arc::new(AtomicUsize::new(0));
+            move |Json(body): Json<serde_json::Value>| {
+                let (received, calls) = (received.clone(), calls.clone());
+                async move {
+                    // The first attempt fails.
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    received.lock().unwrap().push(body);
+                    StatusCode::OK
+                }
+            }
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route("/hook", post(handler));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let sink = NotificationSink {
+            name: "Stand-in".to_string(),
+            target: SinkTarget::Webhook { url },
+            alarms: true,
+            connection: true,
+        };
+        let client = reqwest::Client::new();
+        let delivery = deliver(&client, &sink, &alarm_event(), Duration::from_millis(10)).await;
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.error, None);
+        let received = received.lock().unwrap();
+        assert_eq!(received[0]["event"], "alarm");
+        assert_eq!(received[0]["name"], "High level");
+        assert_eq!(received[0]["kind"], "raised");
+        assert_eq!(received[0]["value"], 81.5);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_gets_event_variables() {
+        let dir = std::env::temp_dir().join(format!("mptt-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("event.txt");
+        let sink = NotificationSink {
+            name: "Script".to_string(),
+            target: SinkTarget::Command {
+                program: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    format!(
+                        "echo \"$MPTT_EVENT $MPTT_ALARM $MPTT_KIND $MPTT_VALUE\" > {}",
+                        out.display()
+                    ),
+                ],
+            },
+            alarms: true,
+            connection: false,
+        };
+        assert!(!sink.wants(&NotificationEvent::Connection {
+            timestamp: 0,
+            connection: String::new(),
+            state: LinkState::Connected,
+        }));
+        let client = reqwest::Client::new();
+        let delivery = deliver(&client, &sink, &alarm_event(), Duration::ZERO).await;
+        assert_eq!(delivery.error, None);
+        let text = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(text.trim(), "alarm High level raised 81.5");
+
+        let failing = NotificationSink {
+            target: SinkTarget::Command {
+                program: "false".to_string(),
+                args: Vec::new(),
+            },
+            ..sink
+        };
+        let delivery = deliver(&client, &failing, &alarm_event(), Duration::ZERO).await;
+        assert_eq!(delivery.attempts, ATTEMPTS);
+        assert!(delivery.error.is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,272 @@
+use axum::extract::{Form, State};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::alarms::AlarmRule;
+use crate::modbus::{
+    connect, disconnect, modbus_status_bar, ConnectionSettings, ModbusSerialForm, ModbusState,
+    ModbusTcpForm, PollBlock, Tag, MARGIN, WINDOW_WIDTH,
+};
+use crate::mqtt::MqttSettings;
+use crate::notify::NotificationSink;
+
+const RECENT_PROJECTS: &str = "./recent_projects.json";
+const MAX_RECENT_PROJECTS: usize = 8;
+
+/// Everything needed to bring a site's setup back after a restart.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Project {
+    pub name: String,
+    /// Reopened when the project is loaded.
+    pub connection: Option<ConnectionSettings>,
+    pub tcp: ModbusTcpForm,
+    pub serial: ModbusSerialForm,
+    /// Block shown in the Modbus table.
+    pub polling: PollBlock,
+    /// Additional blocks polled for the historian.
+    pub poll_blocks: Vec<PollBlock>,
+    pub tags: Vec<Tag>,
+    pub display: DisplayOptions,
+    pub alarms: Vec<AlarmRule>,
+    pub notifications: Vec<NotificationSink>,
+    pub mqtt: MqttSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DisplayOptions {
+    /// Comma separated tags shown when the trend page opens.
+    pub trend_tags: String,
+    /// Trend window in seconds.
+    pub trend_window: u64,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        DisplayOptions {
+            trend_tags: "HR1".to_string(),
+            trend_window: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProjectForm {
+    pub path: String,
+    /// Only used when saving.
+    #[serde(default)]
+    pub name: String,
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Project, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&text).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+
+    /// The project as it is running now, including the polled block and connection.
+    pub fn capture(state: &ModbusState) -> Project {
+        Project {
+            connection: state.connection.clone(),
+            polling: PollBlock::from(&state.protocol_options),
+            ..state.project.clone()
+        }
+    }
+}
+
+/// Replaces the running setup with `project` and reopens its connection.
+pub async fn apply_project(state: &mut ModbusState, project: Project) -> Result<(), String> {
+    let Some(protocol_options) = project.polling.protocol_options() else {
+        return Err("Unsupported function code in the polling block.".to_string());
+    };
+    state.protocol_options = protocol_options;
+    let connection = project.connection.clone();
+    state.project = project;
+    state.alarms.retain_rules(&state.project.alarms);
+    disconnect(state).await;
+    match connection {
+        Some(settings) => connect(state, settings).await,
+        None => Ok(()),
+    }
+}
+
+/// Loads the project at `path` into `state` and records it as recent.
+pub async fn open_project(state: &mut ModbusState, path: &Path) -> Result<(), String> {
+    let project = Project::load(path)?;
+    state.project_path = Some(path.to_path_buf());
+    remember_project(path);
+    apply_project(state, project).await
+}
+
+pub fn recent_projects() -> Vec<PathBuf> {
+    std::fs::read_to_string(RECENT_PROJECTS)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn remember_project(path: &Path) {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut recent = recent_projects();
+    recent.retain(|p| p != &path);
+    recent.insert(0, path);
+    recent.truncate(MAX_RECENT_PROJECTS);
+    if let Ok(text) = serde_json::to_string_pretty(&recent) {
+        if let Err(e) = std::fs::write(RECENT_PROJECTS, text) {
+            println!("Could not save the recent projects: {:?}", e);
+        }
+    }
+}
+
+pub async fn save_project(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<ProjectForm>,
+) -> Markup {
+    let path = PathBuf::from(form_input.path.trim());
+    let mut mtx = mtx.lock().await;
+    mtx.project.name = form_input.name.trim().to_string();
+    let result = Project::capture(&mtx).save(&path);
+    if result.is_ok() {
+        mtx.project_path = Some(path.clone());
+        remember_project(&path);
+    }
+    project_status(result.map(|_| format!("Saved {}", path.display())))
+}
+
+pub async fn load_project(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<ProjectForm>,
+) -> Markup {
+    let path = PathBuf::from(form_input.path.trim());
+    let mut mtx = mtx.lock().await;
+    let result = open_project(&mut mtx, &path).await;
+    project_status(result.map(|_| format!("Loaded {}", path.display())))
+}
+
+fn project_status(result: Result<String, String>) -> Markup {
+    html! {
+        #project_status {
+            p {
+                @match result {
+                    Ok(message) => (message),
+                    Err(e) => (format!("Error: {}", e)),
+                }
+            }
+        }
+    }
+}
+
+pub fn project_body(state: &ModbusState) -> Markup {
+    let path = state
+        .project_path
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "./project.toml".to_string());
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Project" }
+                    }
+                    div class="window-body" {
+                        form hx-post="/save_project" hx-target="#project_status" hx-swap="outerHTML" {
+                            fieldset {
+                                legend { "Project File" }
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="name" { "Name: " }
+                                    input type="text" id="name" name="name" value=(state.project.name) {}
+                                    label for="path" { "Path: (TOML)" }
+                                    input type="text" id="path" name="path" value=(path) {}
+                                }
+                                div class="field-row" {
+                                    button hx-post="/save_project" { "Save" }
+                                    button hx-post="/load_project" { "Load" }
+                                }
+                            }
+                        }
+                        fieldset {
+                            legend { "Recent Projects" }
+                            @for recent in recent_projects() {
+                                div class="field-row" {
+                                    button hx-post="/load_project" hx-vals=(serde_json::json!({ "path": recent }).to_string()) hx-target="#project_status" hx-swap="outerHTML" { "Load" }
+                                    label { (recent.display()) }
+                                }
+                            }
+                        }
+                        fieldset {
+                            legend { "Contents" }
+                            p { (format!("{} tags, {} extra poll blocks", state.project.tags.len(), state.project.poll_blocks.len())) }
+                        }
+                        #project_status {
+                            p { "" }
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::{Access, ByteOrder, DataType};
+
+    #[test]
+    fn saves_and_loads_a_project() {
+        let project = Project {
+            name: "Pump station".to_string(),
+            connection: Some(ConnectionSettings::Tcp {
+                address: "10.0.0.5".to_string(),
+                port: 502,
+                read_only: true,
+            }),
+            poll_blocks: vec![PollBlock {
+                name: "levels".to_string(),
+                function: 4,
+                register: 100,
+                count: 4,
+                data_type: DataType::F32,
+                unit_id: Some(3),
+            }],
+            tags: vec![Tag {
+                name: "level".to_string(),
+                function: 4,
+                register: 100,
+                data_type: DataType::F32,
+                scale: 0.1,
+                unit: "m".to_string(),
+                access: Access::ReadWrite,
+                byte_order: ByteOrder::Cdab,
+                unit_id: Some(3),
+            }],
+            ..Project::default()
+        };
+        let path = std::env::temp_dir().join(format!("mptt-project-{}.toml", std::process::id()));
+        project.save(&path).unwrap();
+        let loaded = Project::load(&path);
+        let _ = std::fs::remove_file(&path);
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.name, project.name);
+        assert_eq!(loaded.connection, project.connection);
+        assert_eq!(loaded.poll_blocks, project.poll_blocks);
+        assert_eq!(loaded.tags, project.tags);
+        assert_eq!(
+            toml::to_string(&loaded).unwrap(),
+            toml::to_string(&project).unwrap()
+        );
+        assert!(Project::load(&path).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::historian::DEFAULT_RETENTION_DAYS;
+use crate::modbus::{load_certs, load_key};
+
+/// Optional settings file next to the history database.
+pub const CONFIG_FILE: &str = "./mptt.toml";
+const DEFAULT_PORT: u16 = 3000;
+/// Made on the first HTTPS start without a certificate and reused afterwards.
+pub const SELF_SIGNED_CERT: &str = "./mptt-cert.pem";
+pub const SELF_SIGNED_KEY: &str = "./mptt-key.pem";
+/// How long open HTTPS connections get to finish once the server stops.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `[server]` table of `mptt.toml`, the command line takes precedence.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ServerSettings {
+    /// Loopback by default, `0.0.0.0` lets other machines reach the UI.
+    pub bind: IpAddr,
+    /// Another free port is used when this one is taken.
+    pub port: u16,
+    /// Serve the UI over HTTPS.
+    pub https: bool,
+    /// PEM certificate chain and key, a self-signed certificate is used when empty.
+    pub cert_file: String,
+    pub key_file: String,
+    /// Days of polled values kept in the historian.
+    pub history_days: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            https: false,
+            cert_file: String::new(),
+            key_file: String::new(),
+            history_days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    #[serde(default)]
+    server: ServerSettings,
+}
+
+impl ServerSettings {
+    /// Reads the `[server]` table, the defaults when there is no file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let settings = match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str::<ConfigFile>(&text)
+                .map(|config| config.server)
+                .map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => ServerSettings::default(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        if settings.history_days == 0 {
+            return Err(format!(
+                "{}: history_days must be at least 1.",
+                path.display()
+            ));
+        }
+        Ok(settings)
+    }
+}
+
+/// Cloned into the server and its background tasks, which stop once it is requested.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Sending `true` stops everything holding a clone.
+    pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Shutdown(receiver))
+    }
+
+    /// Never resolves once the sender is gone without asking.
+    pub async fn requested(&mut self) {
+        if self.0.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Binds the configured port, or a free one on the same address when it is in use.
+pub async fn bind(settings: &ServerSettings) -> Result<TcpListener, String> {
+    let address = SocketAddr::new(settings.bind, settings.port);
+    match TcpListener::bind(address).await {
+        Ok(listener) => Ok(listener),
+        Err(e) if e.kind() == ErrorKind::AddrInUse && settings.port != 0 => {
+            println!("Port {} is in use, using a free port.", settings.port);
+            TcpListener::bind(SocketAddr::new(settings.bind, 0))
+                .await
+                .map_err(|e| format!("Could not listen on {}: {}", settings.bind, e))
+        }
+        Err(e) => Err(format!("Could not listen on {}: {}", address, e)),
+    }
+}
+
+/// Address to open in a browser, loopback when bound to every interface.
+pub fn browser_url(address: SocketAddr, https: bool) -> String {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let scheme = if https { "https" } else { "http" };
+    format!("{}://{}", scheme, SocketAddr::new(ip, address.port()))
+}
+
+/// Writes a self-signed certificate for this computer unless both files already exist.
+pub fn self_signed(cert_file: &Path, key_file: &Path, bind: IpAddr) -> Result<(), String> {
+    if cert_file.exists() && key_file.exists() {
+        return Ok(());
+    }
+    let mut names = vec![
+        "localhost".to_string(),
+        Ipv4Addr::LOCALHOST.to_string(),
+        Ipv6Addr::LOCALHOST.to_string(),
+    ];
+    if !bind.is_unspecified() && !bind.is_loopback() {
+        names.push(bind.to_string());
+    }
+    if let Ok(host) = std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")) {
+        names.push(host);
+    }
+    let key = KeyPair::generate().map_err(|e| e.to_string())?;
+    let mut params = CertificateParams::new(names).map_err(|e| e.to_string())?;
+    params.distinguished_name.push(DnType::CommonName, "MPTT");
+    let cert = params.self_signed(&key).map_err(|e| e.to_string())?;
+    write_key(key_file, &key.serialize_pem())
+        .map_err(|e| format!("{}: {}", key_file.display(), e))?;
+    std::fs::write(cert_file, cert.pem()).map_err(|e| format!("{}: {}", cert_file.display(), e))
+}
+
+/// Creates the key readable by its owner only, a leftover one may have wider permissions.
+fn write_key(path: &Path, pem: &str) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
+/// TLS setup for HTTPS, from the configured certificate or the self-signed one.
+pub fn tls_acceptor(settings: &ServerSettings) -> Result<TlsAcceptor, String> {
+    let (cert_file, key_file) = if settings.cert_file.is_empty() {
+        self_signed(
+            Path::new(SELF_SIGNED_CERT),
+            Path::new(SELF_SIGNED_KEY),
+            settings.bind,
+        )?;
+        (SELF_SIGNED_CERT, SELF_SIGNED_KEY)
+    } else if settings.key_file.is_empty() {
+        return Err("A key file is needed with the certificate.".to_string());
+    } else {
+        (settings.cert_file.as_str(), settings.key_file.as_str())
+    };
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_file)?, load_key(key_file)?)
+        .map_err(|e| format!("{}: {}", cert_file, e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves `app` with the client address as connect info, over TLS when there is an acceptor.
+/// Returns once shutdown is requested and the open requests are answered.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    mut shutdown: Shutdown,
+) -> Result<(), String> {
+    let Some(acceptor) = tls else {
+        return axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move { shutdown.requested().await })
+        .await
+        .map_err(|e| e.to_string());
+    };
+    // Every connection holds a receiver, the sender sees them all closed once they are done.
+    let (closed, open) = watch::channel(());
+    loop {
+        let (stream, address) = tokio::select! {
+            connection = listener.accept() => match connection {
+                Ok(connection) => connection,
+                Err(e) => {
+                    println!("Could not accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.requested() => break,
+        };
+        let (acceptor, app) = (acceptor.clone(), app.clone());
+        let (mut shutdown, open) = (shutdown.clone(), open.clone());
+        tokio::spawn(async move {
+            // Browsers hang up here until the self-signed certificate is trusted.
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(address));
+                app.clone().oneshot(request)
+            });
+            let builder = Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            tokio::select! {
+                _ = connection.as_mut() => {}
+                _ = shutdown.requested() => {
+                    connection.as_mut().graceful_shutdown();
+                    let _ = connection.await;
+                }
+            }
+            drop(open);
+        });
+    }
+    drop((listener, open));
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, closed.closed()).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn settings_default_to_loopback() {
+        let path = std::env::temp_dir().join(format!("mptt-config-{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            ServerSettings::load(&path).unwrap(),
+            ServerSettings::default()
+        );
+        std::fs::write(&path, "[server]\nhistory_days = 0\n").unwrap();
+        assert!(ServerSettings::load(&path).is_err());
+        std::fs::write(&path, "[server]\nbind = \"0.0.0.0\"\nhistory_days = 30\n").unwrap();
+        let settings = ServerSettings::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(settings.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(settings.port, 3000);
+        assert_eq!(settings.history_days, 30);
+
+        let address = SocketAddr::new(settings.bind, 3000);
+        assert_eq!(browser_url(address, false), "http://127.0.0.1:3000");
+        assert_eq!(
+            browser_url("[::]:8080".parse().unwrap(), true),
+            "https://[::1]:8080"
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_a_free_port() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let settings = ServerSettings {
+            port,
+            ..ServerSettings::default()
+        };
+        let listener = bind(&settings).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(address.ip().is_loopback());
+        assert_ne!(address.port(), port);
+    }
+
+    #[tokio::test]
+    async fn serves_https_with_a_kept_self_signed_certificate() {
+        let dir = std::env::temp_dir().join(format!("mptt-https-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let bind = ServerSettings::default().bind;
+        self_signed(&cert_file, &key_file, bind).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let first = std::fs::read(&cert_file).unwrap();
+        self_signed(&cert_file, &key_file, bind).unwrap();
+        assert_eq!(std::fs::read(&cert_file).unwrap(), first);
+
+        let settings = ServerSettings {
+            https: true,
+            cert_file: cert_file.to_string_lossy().into(),
+            key_file: key_file.to_string_lossy().into(),
+            ..ServerSettings::default()
+        };
+        let acceptor = tls_acceptor(&settings).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/",
+            axum::routing::get(|ConnectInfo(client): ConnectInfo<SocketAddr>| async move {
+                client.ip().to_string()
+            }),
+        );
+        let (stop, shutdown) = Shutdown::channel();
+        let server = tokio::spawn(serve(listener, app, Some(acceptor), shutdown));
+
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&settings.cert_file).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("127.0.0.1"));
+
+        stop.send(true).unwrap();
+        let stopped = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert_eq!(stopped.unwrap().unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn stops_with_idle_connections_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route("/", axum::routing::get(|| async { "MPTT" }));
+        let (stop, shutdown) = Shutdown::channel();
+        let server = tokio::spawn(serve(listener, app, None, shutdown));
+
+        let mut idle = TcpStream::connect(address).await.unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = [0; 12];
+        idle.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200");
+
+        stop.send(true).unwrap();
+        let stopped = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert_eq!(stopped.unwrap().unwrap(), Ok(()));
+    }
+}
@@ -0,0 +1,409 @@
+use async_trait::async_trait;
+use axum::extract::State;
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::prelude::*;
+use utoipa::ToSchema;
+
+use crate::historian::now_millis;
+use crate::modbus::{modbus_status_bar, ConnectionSettings, ModbusState, MARGIN, WINDOW_WIDTH};
+
+/// Longest wait for a response, so a silent device can't stall the poller.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the latency histogram buckets, slower responses go to one more bucket.
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000, 500_000, 1_000_000,
+    2_000_000,
+];
+
+pub type SharedStats = Arc<std::sync::Mutex<CommStats>>;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default, PartialEq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub min_us: u64,
+    pub max_us: u64,
+    pub avg_us: u64,
+    /// Upper bound of the histogram bucket holding the 99th percentile.
+    pub p99_us: u64,
+    /// Responses per `LATENCY_BUCKETS_US` bucket.
+    pub histogram: Vec<u64>,
+    #[serde(skip)]
+    total_us: u64,
+}
+
+impl LatencyStats {
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        self.min_us = if self.count == 0 {
+            us
+        } else {
+            self.min_us.min(us)
+        };
+        self.max_us = self.max_us.max(us);
+        self.count += 1;
+        self.total_us += us;
+        self.avg_us = self.total_us / self.count;
+
+        self.histogram.resize(LATENCY_BUCKETS_US.len() + 1, 0);
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.histogram[bucket] += 1;
+
+        let target = self.count.saturating_mul(99).div_ceil(100);
+        let mut seen = 0;
+        for (i, count) in self.histogram.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                self.p99_us = LATENCY_BUCKETS_US.get(i).copied().unwrap_or(self.max_us);
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub requests: u64,
+    /// Normal and exception responses.
+    pub responses: u64,
+    /// Exception responses by exception code.
+    pub exceptions: BTreeMap<String, u64>,
+    pub timeouts: u64,
+    pub crc_errors: u64,
+    pub other_errors: u64,
+    pub latency: LatencyStats,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Response,
+    Exception(String),
+    Timeout,
+    Crc,
+    Error,
+}
+
+impl Counters {
+    fn record(&mut self, outcome: &Outcome, latency: Duration) {
+        self.requests += 1;
+        match outcome {
+            Outcome::Response => self.responses += 1,
+            Outcome::Exception(code) => {
+                self.responses += 1;
+                *self.exceptions.entry(code.clone()).or_default() += 1;
+            }
+            Outcome::Timeout => self.timeouts += 1,
+            Outcome::Crc => self.crc_errors += 1,
+            Outcome::Error => self.other_errors += 1,
+        }
+        if matches!(outcome, Outcome::Response | Outcome::Exception(_)) {
+            self.latency.record(latency);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct ConnectionStats {
+    pub connection: String,
+    /// When counting started, in milliseconds since the epoch.
+    pub since: i64,
+    pub reconnects: u64,
+    pub total: Counters,
+    pub units: BTreeMap<u8, Counters>,
+}
+
+/// Counters of every connection since the last reset.
+#[derive(Default)]
+pub struct CommStats {
+    pub connections: Vec<ConnectionStats>,
+}
+
+impl CommStats {
+    pub fn connection(&mut self, connection: &str) -> &mut ConnectionStats {
+        let index = match self
+            .connections
+            .iter()
+            .position(|stats| stats.connection == connection)
+        {
+            Some(index) => index,
+            None => {
+                self.connections.push(ConnectionStats {
+                    connection: connection.to_string(),
+                    since: now_millis(),
+                    reconnects: 0,
+                    total: Counters::default(),
+                    units: BTreeMap::new(),
+                });
+                self.connections.len() - 1
+            }
+        };
+        &mut self.connections[index]
+    }
+
+    pub fn record(&mut self, connection: &str, unit: u8, outcome: &Outcome, latency: Duration) {
+        let stats = self.connection(connection);
+        stats.total.record(outcome, latency);
+        stats
+            .units
+            .entry(unit)
+            .or_default()
+            .record(outcome, latency);
+    }
+
+    pub fn reset(&mut self) {
+        self.connections.clear();
+    }
+}
+
+pub fn classify(result: &tokio_modbus::Result<Response>) -> Outcome {
+    match result {
+        Ok(Ok(_)) => Outcome::Response,
+        Ok(Err(code)) => Outcome::Exception(format!("{:?}", code)),
+        Err(tokio_modbus::Error::Transport(e)) if e.kind() == ErrorKind::TimedOut => {
+            Outcome::Timeout
+        }
+        Err(e) => {
+            let text = format!("{:?}", e).to_ascii_lowercase();
+            // RTU checks a CRC, ASCII an LRC.
+            if text.contains("crc") || text.contains("lrc") {
+                Outcome::Crc
+            } else {
+                Outcome::Error
+            }
+        }
+    }
+}
+
+/// Counts every request of a connection and bounds how long it may take.
+pub struct MonitoredClient {
+    inner: Context,
+    stats: SharedStats,
+    connection: String,
+    slave: Slave,
+}
+
+impl fmt::Debug for MonitoredClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MonitoredClient({})", self.connection)
+    }
+}
+
+impl SlaveContext for MonitoredClient {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+        self.inner.set_slave(slave);
+    }
+}
+
+#[async_trait]
+impl Client for MonitoredClient {
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(REQUEST_TIMEOUT, self.inner.call(request)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::new(ErrorKind::TimedOut, "Request timed out.").into()),
+        };
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.record(
+                &self.connection,
+                self.slave.0,
+                &classify(&result),
+                start.elapsed(),
+            );
+        }
+        result
+    }
+
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.inner.disconnect().await
+    }
+}
+
+pub fn monitor(ctx: Context, stats: &SharedStats, settings: &ConnectionSettings) -> Context {
+    let client: Box<dyn Client> = Box::new(MonitoredClient {
+        inner: ctx,
+        stats: stats.clone(),
+        connection: settings.to_string(),
+        slave: settings.default_slave(),
+    });
+    Context::from(client)
+}
+
+pub fn statistics_snapshot(state: &ModbusState) -> Vec<ConnectionStats> {
+    state
+        .stats
+        .lock()
+        .map(|stats| stats.connections.clone())
+        .unwrap_or_default()
+}
+
+pub async fn statistics_table(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let connections = statistics_snapshot(&*mtx.lock().await);
+    statistics_fragment(&connections)
+}
+
+pub async fn reset_statistics(State(mtx): State<Arc<Mutex<ModbusState>>>) -> Markup {
+    let mtx = mtx.lock().await;
+    if let Ok(mut stats) = mtx.stats.lock() {
+        stats.reset();
+    }
+    statistics_fragment(&[])
+}
+
+fn bucket_label(i: usize) -> String {
+    match LATENCY_BUCKETS_US.get(i) {
+        Some(bound) if *bound < 1000 => format!("<= {} us", bound),
+        Some(bound) => format!("<= {} ms", bound / 1000),
+        None => format!("> {} ms", LATENCY_BUCKETS_US[i - 1] / 1000),
+    }
+}
+
+fn counters_row(label: &str, counters: &Counters) -> Markup {
+    let exceptions: Vec<String> = counters
+        .exceptions
+        .iter()
+        .map(|(code, count)| format!("{}: {}", code, count))
+        .collect();
+    let latency = &counters.latency;
+    html! {
+        tr {
+            td { (label) }
+            td { (counters.requests) }
+            td { (counters.responses) }
+            td { (exceptions.join(", ")) }
+            td { (counters.timeouts) }
+            td { (counters.crc_errors) }
+            td { (counters.other_errors) }
+            td { (format!("{} / {} / {} / {}", latency.min_us, latency.avg_us, latency.max_us, latency.p99_us)) }
+        }
+    }
+}
+
+fn statistics_fragment(connections: &[ConnectionStats]) -> Markup {
+    html! {
+        #statistics {
+            div hx-get="/statistics_table" hx-trigger="load delay:2s" hx-target="#statistics" hx-swap="outerHTML" {}
+            @if connections.is_empty() {
+                p { "No requests since the last reset." }
+            }
+            @for stats in connections {
+                fieldset {
+                    legend { (format!("{} (reconnects: {})", stats.connection, stats.reconnects)) }
+                    table class="interactive" {
+                        thead {
+                            tr {
+                                th { "Unit" }
+                                th { "Requests" }
+                                th { "Responses" }
+                                th { "Exceptions" }
+                                th { "Timeouts" }
+                                th { "CRC" }
+                                th { "Other" }
+                                th { "Min / Avg / Max / P99 (us)" }
+                            }
+                        }
+                        tbody {
+                            (counters_row("All", &stats.total))
+                            @for (unit, counters) in &stats.units {
+                                (counters_row(&unit.to_string(), counters))
+                            }
+                        }
+                    }
+                    @let histogram = &stats.total.latency.histogram;
+                    @let largest = histogram.iter().copied().max().unwrap_or(0).max(1);
+                    table {
+                        @for (i, count) in histogram.iter().enumerate() {
+                            tr {
+                                td { (bucket_label(i)) }
+                                td style="width: 300px" {
+                                    div style=(format!("background: navy; height: 10px; width: {}%", count * 100 / largest)) {}
+                                }
+                                td { (count) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn statistics_body(state: &ModbusState) -> Markup {
+    let connections = statistics_snapshot(state);
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Communication Statistics" }
+                    }
+                    div class="window-body" {
+                        div class="field-row" {
+                            button hx-post="/reset_statistics" hx-target="#statistics" hx-swap="outerHTML" { "Reset" }
+                        }
+                        (statistics_fragment(&connections))
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_outcomes_per_unit() {
+        let mut stats = CommStats::default();
+        let ms = Duration::from_millis;
+        stats.record("TCP 10.0.0.5:502", 1, &Outcome::Response, ms(3));
+        stats.record("TCP 10.0.0.5:502", 1, &Outcome::Timeout, ms(5000));
+        stats.record(
+            "TCP 10.0.0.5:502",
+            2,
+            &Outcome::Exception("IllegalDataAddress".to_string()),
+            ms(1),
+        );
+        stats.record("TCP 10.0.0.5:502", 2, &Outcome::Crc, ms(1));
+
+        let connection = &stats.connections[0];
+        assert_eq!(connection.total.requests, 4);
+        assert_eq!(connection.total.responses, 2);
+        assert_eq!(connection.total.timeouts, 1);
+        assert_eq!(connection.total.crc_errors, 1);
+        assert_eq!(connection.units[&2].exceptions["IllegalDataAddress"], 1);
+        // Only responses have a latency.
+        assert_eq!(connection.total.latency.count, 2);
+        assert_eq!(connection.total.latency.min_us, 1000);
+        assert_eq!(connection.total.latency.max_us, 3000);
+        assert_eq!(connection.total.latency.avg_us, 2000);
+    }
+
+    #[test]
+    fn p99_comes_from_the_histogram() {
+        let mut latency = LatencyStats::default();
+        for _ in 0..99 {
+            latency.record(Duration::from_micros(800));
+        }
+        assert_eq!(latency.p99_us, 1_000);
+        latency.record(Duration::from_millis(30));
+        latency.record(Duration::from_secs(3));
+        assert_eq!(latency.p99_us, 50_000);
+        assert_eq!(latency.histogram[1], 99);
+        assert_eq!(latency.histogram[LATENCY_BUCKETS_US.len()], 1);
+    }
+}
@@ -0,0 +1,420 @@
+use axum::extract::{Form, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::import::poll_blocks_for;
+use crate::modbus::{
+    modbus_status_bar, ByteOrder, DataType, ModbusState, PollBlock, Tag, MARGIN, WINDOW_WIDTH,
+};
+
+const TEMPLATE_DIR: &str = "./templates";
+
+/// A device's register map, shared between projects as a TOML file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DeviceTemplate {
+    pub name: String,
+    pub description: String,
+    /// Used for every 32 bit tag of the template.
+    pub byte_order: ByteOrder,
+    pub tags: Vec<Tag>,
+    /// Recommended reads, derived from the tags when empty.
+    pub poll_blocks: Vec<PollBlock>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApplyTemplateForm {
+    pub file: String,
+    /// Empty for the connection's own slave.
+    pub unit_id: String,
+    /// Prepended to tag and block names, e.g. `meter1` gives `meter1.Voltage`.
+    pub prefix: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SaveTemplateForm {
+    pub name: String,
+    pub description: String,
+    pub byte_order: ByteOrder,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportTemplateForm {
+    pub template: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateQuery {
+    pub file: String,
+}
+
+impl DeviceTemplate {
+    /// Tags and blocks of the template bound to `unit_id`.
+    pub fn instantiate(&self, unit_id: Option<u8>, prefix: &str) -> (Vec<Tag>, Vec<PollBlock>) {
+        let name = |name: &str| match prefix {
+            "" => name.to_string(),
+            prefix => format!("{}.{}", prefix, name),
+        };
+        let tags: Vec<Tag> = self
+            .tags
+            .iter()
+            .map(|tag| Tag {
+                name: name(&tag.name),
+                unit_id,
+                byte_order: match tag.data_type {
+                    DataType::F32 => self.byte_order,
+                    DataType::Int16 => tag.byte_order,
+                },
+                ..tag.clone()
+            })
+            .collect();
+        let blocks = if self.poll_blocks.is_empty() {
+            poll_blocks_for(&tags)
+        } else {
+            self.poll_blocks.clone()
+        };
+        let blocks = blocks
+            .into_iter()
+            .map(|block| PollBlock {
+                name: name(&block.name),
+                unit_id,
+                ..block
+            })
+            .collect();
+        (tags, blocks)
+    }
+}
+
+/// Path of a template file, `None` for names that would leave the template directory.
+fn template_path(file: &str) -> Option<PathBuf> {
+    let valid = !file.is_empty()
+        && file
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| Path::new(TEMPLATE_DIR).join(format!("{}.toml", file)))
+}
+
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+pub fn load_template(file: &str) -> Result<DeviceTemplate, String> {
+    let path = template_path(file).ok_or("Bad template name.")?;
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    toml::from_str(&text).map_err(|e| e.to_string())
+}
+
+/// Writes the template to the template directory and returns its file name.
+pub fn save_template(template: &DeviceTemplate) -> Result<String, String> {
+    let file = file_name(&template.name);
+    let path = template_path(&file).ok_or("The template needs a name.")?;
+    std::fs::create_dir_all(TEMPLATE_DIR).map_err(|e| e.to_string())?;
+    let text = toml::to_string_pretty(template).map_err(|e| e.to_string())?;
+    std::fs::write(path, text).map_err(|e| e.to_string())?;
+    Ok(file)
+}
+
+/// Templates found in the template directory, by file name.
+pub fn list_templates() -> Vec<(String, DeviceTemplate)> {
+    let Ok(entries) = std::fs::read_dir(TEMPLATE_DIR) else {
+        return Vec::new();
+    };
+    let mut templates: Vec<(String, DeviceTemplate)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "toml" {
+                return None;
+            }
+            let file = path.file_stem()?.to_str()?.to_string();
+            match load_template(&file) {
+                Ok(template) => Some((file, template)),
+                Err(e) => {
+                    println!("Could not load the template {}: {}", file, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    templates.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    templates
+}
+
+/// Adds the template's tags and blocks to the project, replacing ones of the same name.
+pub fn apply_template(
+    state: &mut ModbusState,
+    template: &DeviceTemplate,
+    unit_id: Option<u8>,
+    prefix: &str,
+) -> usize {
+    let (tags, blocks) = template.instantiate(unit_id, prefix);
+    let project = &mut state.project;
+    project
+        .tags
+        .retain(|tag| tags.iter().all(|t| t.name != tag.name));
+    project
+        .poll_blocks
+        .retain(|block| blocks.iter().all(|b| b.name != block.name));
+    let count = tags.len();
+    project.tags.extend(tags);
+    project.poll_blocks.extend(blocks);
+    count
+}
+
+pub async fn apply_template_form(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<ApplyTemplateForm>,
+) -> Markup {
+    let unit_id = match form_input.unit_id.trim() {
+        "" => None,
+        unit_id => match unit_id.parse() {
+            Ok(unit_id) => Some(unit_id),
+            Err(_) => return template_status(Err("Bad unit id.".to_string())),
+        },
+    };
+    let template = match load_template(&form_input.file) {
+        Ok(template) => template,
+        Err(e) => return template_status(Err(e)),
+    };
+    let mut mtx = mtx.lock().await;
+    let count = apply_template(&mut mtx, &template, unit_id, form_input.prefix.trim());
+    template_status(Ok(format!("Added {} tags from {}.", count, template.name)))
+}
+
+/// Saves the project's tags and extra poll blocks as a template.
+pub async fn save_template_form(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Form(form_input): Form<SaveTemplateForm>,
+) -> Markup {
+    let mtx = mtx.lock().await;
+    let template = DeviceTemplate {
+        name: form_input.name.trim().to_string(),
+        description: form_input.description,
+        byte_order: form_input.byte_order,
+        tags: mtx
+            .project
+            .tags
+            .iter()
+            .map(|tag| Tag {
+                unit_id: None,
+                ..tag.clone()
+            })
+            .collect(),
+        poll_blocks: mtx
+            .project
+            .poll_blocks
+            .iter()
+            .map(|block| PollBlock {
+                unit_id: None,
+                ..block.clone()
+            })
+            .collect(),
+    };
+    template_status(save_template(&template).map(|file| format!("Saved {}.toml", file)))
+}
+
+/// Adds a template shared by someone else.
+pub async fn import_template(Form(form_input): Form<ImportTemplateForm>) -> Markup {
+    let result = toml::from_str::<DeviceTemplate>(&form_input.template)
+        .map_err(|e| e.to_string())
+        .and_then(|template| save_template(&template));
+    template_status(result.map(|file| format!("Imported {}.toml", file)))
+}
+
+pub async fn export_template(Query(query): Query<TemplateQuery>) -> Response {
+    let Some(path) = template_path(&query.file) else {
+        return (StatusCode::BAD_REQUEST, "Bad template name.").into_response();
+    };
+    match std::fs::read_to_string(path) {
+        Ok(text) => (
+            [
+                (header::CONTENT_TYPE, "application/toml".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.toml\"", query.file),
+                ),
+            ],
+            text,
+        )
+            .into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, "No such template.").into_response(),
+    }
+}
+
+fn template_status(result: Result<String, String>) -> Markup {
+    html! {
+        #template_status {
+            p {
+                @match result {
+                    Ok(message) => (message),
+                    Err(e) => (format!("Error: {}", e)),
+                }
+            }
+        }
+    }
+}
+
+pub fn templates_body() -> Markup {
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Device Templates" }
+                    }
+                    div class="window-body" {
+                        @for (file, template) in list_templates() {
+                            form hx-post="/apply_template" hx-target="#template_status" hx-swap="outerHTML" {
+                                fieldset {
+                                    legend { (template.name) }
+                                    p { (format!("{} ({} tags)", template.description, template.tags.len())) }
+                                    input type="hidden" name="file" value=(file) {}
+                                    div class="field-row-stacked" style="width: 200px" {
+                                        label for=(format!("unit_id_{}", file)) { "Unit ID: (empty for the connection's)" }
+                                        input type="number" id=(format!("unit_id_{}", file)) name="unit_id" value="" {}
+                                        label for=(format!("prefix_{}", file)) { "Tag Prefix: " }
+                                        input type="text" id=(format!("prefix_{}", file)) name="prefix" value=(file) {}
+                                    }
+                                    div class="field-row" {
+                                        button type="submit" { "Apply" }
+                                        a href=(format!("/export_template?file={}", file)) { "Export" }
+                                    }
+                                }
+                            }
+                        }
+                        form hx-post="/save_template" hx-target="#template_status" hx-swap="outerHTML" {
+                            fieldset {
+                                legend { "Save Project Tags as Template" }
+                                div class="field-row-stacked" style="width: 200px" {
+                                    label for="name" { "Name: " }
+                                    input type="text" id="name" name="name" value="" {}
+                                    label for="description" { "Description: " }
+                                    input type="text" id="description" name="description" value="" {}
+                                    label for="byte_order" { "32 bit byte order: " }
+                                    select name="byte_order" id="byte_order" {
+                                        option value="abcd" { "ABCD (high word first)" }
+                                        option value="cdab" { "CDAB (word swapped)" }
+                                        option value="badc" { "BADC (byte swapped)" }
+                                        option value="dcba" { "DCBA (little endian)" }
+                                    }
+                                    button type="submit" { "Save" }
+                                }
+                            }
+                        }
+                        form hx-post="/import_template" hx-target="#template_status" hx-swap="outerHTML" {
+                            fieldset {
+                                legend { "Import Template" }
+                                div class="field-row-stacked" style="width: 300px" {
+                                    label for="template" { "Template: (TOML)" }
+                                    textarea id="template" name="template" rows="6" {}
+                                    button type="submit" { "Import" }
+                                }
+                            }
+                        }
+                        #template_status {
+                            p { "" }
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::{Access, ProtocolOpts};
+
+    fn meter() -> DeviceTemplate {
+        let tag = |name: &str, register, data_type| Tag {
+            name: name.to_string(),
+            function: 3,
+            register,
+            data_type,
+            scale: 1.0,
+            unit: String::new(),
+            access: Access::ReadOnly,
+            byte_order: ByteOrder::Abcd,
+            unit_id: None,
+        };
+        DeviceTemplate {
+            name: "Power meter".to_string(),
+            byte_order: ByteOrder::Cdab,
+            tags: vec![
+                tag("Voltage", 0, DataType::F32),
+                tag("Status", 2, DataType::Int16),
+            ],
+            ..DeviceTemplate::default()
+        }
+    }
+
+    #[test]
+    fn instantiates_for_a_unit() {
+        let (tags, blocks) = meter().instantiate(Some(4), "meter1");
+        let names: Vec<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["meter1.Voltage", "meter1.Status"]);
+        assert!(tags.iter().all(|tag| tag.unit_id == Some(4)));
+        // Only the 32 bit tags take the template's byte order.
+        assert_eq!(tags[0].byte_order, ByteOrder::Cdab);
+        assert_eq!(tags[1].byte_order, ByteOrder::Abcd);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            (blocks[0].name.as_str(), blocks[0].count, blocks[0].unit_id),
+            ("meter1.HR0", 3, Some(4))
+        );
+
+        let mut template = meter();
+        template.poll_blocks = vec![PollBlock {
+            name: "all".to_string(),
+            count: 10,
+            ..PollBlock::default()
+        }];
+        let (tags, blocks) = template.instantiate(None, "");
+        assert_eq!(tags[0].name, "Voltage");
+        assert_eq!((blocks[0].name.as_str(), blocks[0].count), ("all", 10));
+
+        // Applying again replaces the tags and blocks of the same name.
+        let mut state = ModbusState::new(ProtocolOpts::default(), None);
+        assert_eq!(apply_template(&mut state, &meter(), Some(4), "meter1"), 2);
+        assert_eq!(apply_template(&mut state, &meter(), Some(4), "meter1"), 2);
+        assert_eq!(apply_template(&mut state, &meter(), Some(5), "meter2"), 2);
+        assert_eq!(state.project.tags.len(), 4);
+        assert_eq!(state.project.poll_blocks.len(), 2);
+    }
+
+    #[test]
+    fn keeps_template_files_in_their_directory() {
+        assert_eq!(
+            template_path("power-meter_2"),
+            Some(Path::new(TEMPLATE_DIR).join("power-meter_2.toml"))
+        );
+        for file in [
+            "",
+            "../users",
+            "..",
+            "a/b",
+            "a\\b",
+            "/etc/passwd",
+            "meter.toml",
+            "C:x",
+        ] {
+            assert_eq!(template_path(file), None, "{:?}", file);
+        }
+        assert!(load_template("../mptt").is_err());
+
+        assert_eq!(file_name("Power meter 2.0"), "Power_meter_2_0");
+        assert_eq!(file_name("../../x"), "______x");
+        assert!(template_path(&file_name("../../x")).is_some());
+        assert!(template_path(&file_name("")).is_none());
+    }
+}
@@ -0,0 +1,336 @@
+use axum::extract::{Query, State};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::historian::{self, now_millis, Bucket};
+use crate::modbus::{modbus_status_bar, ModbusState, MARGIN, TABLE_WIDTH, WINDOW_WIDTH};
+use crate::project::DisplayOptions;
+
+const CHART_WIDTH: usize = TABLE_WIDTH - 10;
+const CHART_HEIGHT: usize = 200;
+const PLOT_LEFT: f64 = 50.0;
+const PLOT_RIGHT: f64 = 10.0;
+const PLOT_TOP: f64 = 10.0;
+const PLOT_BOTTOM: f64 = 20.0;
+const CHART_POINTS: i64 = 300; // Maximum number of points drawn per tag.
+const DEFAULT_WINDOW: u64 = 60;
+const MAX_WINDOW: u64 = 24 * 60 * 60;
+const BUFFER_WINDOW: u64 = 60 * 60; // Seconds of polled values kept in memory, longer windows read the historian.
+
+const TREND_COLORS: [&str; 6] = [
+    "#000080", "#800000", "#008000", "#808000", "#800080", "#008080",
+];
+
+#[derive(Serialize, Deserialize)]
+pub struct TrendQuery {
+    /// Comma separated tags, e.g. `HR1,HR2`.
+    pub tags: String,
+    /// Rolling window in seconds.
+    pub window: Option<u64>,
+    /// Zoom: fixed Y axis bounds, empty means auto scale.
+    pub y_min: Option<String>,
+    pub y_max: Option<String>,
+    pub paused: Option<String>,
+}
+
+/// The poller's values of the last `BUFFER_WINDOW` seconds, by tag.
+#[derive(Default)]
+pub struct TrendBuffer {
+    samples: HashMap<String, VecDeque<(i64, f64)>>,
+}
+
+impl TrendBuffer {
+    pub fn record(&mut self, timestamp: i64, values: &[(String, f64)]) {
+        for (tag, value) in values {
+            let samples = self.samples.entry(tag.clone()).or_default();
+            samples.push_back((timestamp, *value));
+        }
+        // Tags that are no longer polled age out with the rest.
+        let oldest = timestamp - (BUFFER_WINDOW * 1000) as i64;
+        for samples in self.samples.values_mut() {
+            while samples.front().is_some_and(|(time, _)| *time < oldest) {
+                samples.pop_front();
+            }
+        }
+        self.samples.retain(|_, samples| !samples.is_empty());
+    }
+
+    /// Same as the historian's buckets, `bucket` milliseconds wide.
+    pub fn buckets(&self, tag: &str, from: i64, to: i64, bucket: i64) -> Vec<Bucket> {
+        let mut buckets: Vec<Bucket> = Vec::new();
+        let Some(samples) = self.samples.get(tag) else {
+            return buckets;
+        };
+        for &(timestamp, value) in samples
+            .iter()
+            .filter(|(time, _)| (from..=to).contains(time))
+        {
+            let start = timestamp / bucket * bucket;
+            match buckets.last_mut() {
+                Some(last) if last.start == start => {
+                    last.count += 1;
+                    last.min = last.min.min(value);
+                    last.max = last.max.max(value);
+                    last.avg += (value - last.avg) / last.count as f64;
+                }
+                _ => buckets.push(Bucket {
+                    start,
+                    min: value,
+                    max: value,
+                    avg: value,
+                    count: 1,
+                }),
+            }
+        }
+        buckets
+    }
+}
+
+struct Series {
+    tag: String,
+    color: &'static str,
+    buckets: Vec<Bucket>,
+}
+
+pub fn trend_body(display: &DisplayOptions) -> Markup {
+    html! {
+        body {
+            main {
+                div class="window" style=(format!("margin: {}px; width: {}px", MARGIN, WINDOW_WIDTH)) {
+                    div class="title-bar" {
+                       div class="title-bar-text" { "Trend" }
+                    }
+                    div class="window-body" {
+                        form #trend_form hx-get="/trend_chart" hx-target="#trend_chart" hx-swap="innerHTML" {
+                            fieldset {
+                                legend { "Trend Options" }
+                                div class="field-row-stacked" style="width: 200px" {
+                                    label for="tags" { "Tags: (e.g. HR1,HR2)" }
+                                    input type="text" id="tags" name="tags" value=(display.trend_tags) {}
+                                    label for="window" { "Window: (seconds)" }
+                                    input type="number" id="window" name="window" value=(display.trend_window) {}
+                                    label for="y_min" { "Y min: (empty for auto)" }
+                                    input type="text" id="y_min" name="y_min" value="" {}
+                                    label for="y_max" { "Y max: (empty for auto)" }
+                                    input type="text" id="y_max" name="y_max" value="" {}
+                                }
+                                div class="field-row" {
+                                    input type="checkbox" id="paused" name="paused" value="on" {}
+                                    label for="paused" { "Pause" }
+                                }
+                                div class="field-row" {
+                                    button type="submit" { "Apply" }
+                                }
+                            }
+                        }
+                        div class="sunken-panel" style=(format!("height: {}px; width: {}px", CHART_HEIGHT + 40, TABLE_WIDTH)) {
+                            #trend_chart {
+                                div hx-get="/trend_chart" hx-include="#trend_form" hx-trigger="load" hx-target="#trend_chart" hx-swap="innerHTML" {}
+                            }
+                        }
+                    }
+                    // Status bar
+                    (modbus_status_bar())
+                }
+            }
+        }
+    }
+}
+
+pub async fn trend_chart(
+    State(mtx): State<Arc<Mutex<ModbusState>>>,
+    Query(query): Query<TrendQuery>,
+) -> Markup {
+    let window = query.window.unwrap_or(DEFAULT_WINDOW).clamp(1, MAX_WINDOW);
+    let to = now_millis();
+    let from = to - (window * 1000) as i64;
+    let bucket = ((window * 1000) as i64 / CHART_POINTS).max(1);
+    let paused = query.paused.is_some();
+
+    let tags: Vec<String> = query
+        .tags
+        .split(',')
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+
+    let mtx = mtx.lock().await;
+    let mut buckets: Vec<Vec<Bucket>> = tags
+        .iter()
+        .map(|tag| mtx.trend.buckets(tag, from, to, bucket))
+        .collect();
+    let historian = mtx.historian.clone();
+    drop(mtx);
+    let mut error = None;
+    if window > BUFFER_WINDOW {
+        match historian {
+            Some(historian) => {
+                let tags = tags.clone();
+                let result = historian::query(&historian, move |historian| {
+                    tags.iter()
+                        .map(|tag| historian.buckets(tag, from, to, bucket))
+                        .collect()
+                })
+                .await;
+                match result {
+                    Ok(stored) => buckets = stored,
+                    Err(e) => error = Some(e),
+                }
+            }
+            None => {
+                error = Some("The historian is not available, showing the last hour.".to_string())
+            }
+        }
+    }
+    let series: Vec<Series> = tags
+        .into_iter()
+        .zip(buckets)
+        .enumerate()
+        .map(|(i, (tag, buckets))| Series {
+            tag,
+            color: TREND_COLORS[i % TREND_COLORS.len()],
+            buckets,
+        })
+        .collect();
+    let missing: Vec<&str> = series
+        .iter()
+        .filter(|serie| serie.buckets.is_empty())
+        .map(|serie| serie.tag.as_str())
+        .collect();
+
+    let y_min = parse_bound(&query.y_min);
+    let y_max = parse_bound(&query.y_max);
+    let (y_min, y_max) = y_range(&series, y_min, y_max);
+
+    let plot_width = CHART_WIDTH as f64 - PLOT_LEFT - PLOT_RIGHT;
+    let plot_height = CHART_HEIGHT as f64 - PLOT_TOP - PLOT_BOTTOM;
+    let x =
+        |timestamp: i64| PLOT_LEFT + (timestamp - from) as f64 / (to - from) as f64 * plot_width;
+    let y = |value: f64| {
+        let ratio = ((value - y_min) / (y_max - y_min)).clamp(0.0, 1.0);
+        PLOT_TOP + (1.0 - ratio) * plot_height
+    };
+
+    html! {
+        @if !paused {
+            div hx-get="/trend_chart" hx-include="#trend_form" hx-trigger="load delay:1s" hx-target="#trend_chart" hx-swap="innerHTML" {}
+        }
+        svg width=(CHART_WIDTH) height=(CHART_HEIGHT) style="background: white" {
+            rect x=(PLOT_LEFT) y=(PLOT_TOP) width=(plot_width) height=(plot_height) fill="none" stroke="#808080" {}
+            line x1=(PLOT_LEFT) y1=(PLOT_TOP + plot_height / 2.0) x2=(PLOT_LEFT + plot_width) y2=(PLOT_TOP + plot_height / 2.0) stroke="#c0c0c0" stroke-dasharray="2,2" {}
+            text x=(PLOT_LEFT - 4.0) y=(PLOT_TOP + 8.0) text-anchor="end" font-size="10" { (format!("{:.2}", y_max)) }
+            text x=(PLOT_LEFT - 4.0) y=(PLOT_TOP + plot_height / 2.0 + 4.0) text-anchor="end" font-size="10" { (format!("{:.2}", (y_min + y_max) / 2.0)) }
+            text x=(PLOT_LEFT - 4.0) y=(PLOT_TOP + plot_height) text-anchor="end" font-size="10" { (format!("{:.2}", y_min)) }
+            text x=(PLOT_LEFT) y=(CHART_HEIGHT as f64 - 5.0) font-size="10" { (format!("-{}s", window)) }
+            text x=(PLOT_LEFT + plot_width) y=(CHART_HEIGHT as f64 - 5.0) text-anchor="end" font-size="10" {
+                @if paused { "paused" } @else { "now" }
+            }
+            @for serie in &series {
+                polyline fill="none" stroke=(serie.color) stroke-width="1.5" points=(
+                    serie.buckets.iter()
+                        .map(|b| format!("{:.1},{:.1}", x(b.start + bucket / 2), y(b.avg)))
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                ) {}
+            }
+        }
+        div class="field-row" {
+            @for serie in &series {
+                span style=(format!("color: {}", serie.color)) {
+                    (format!("{} ({})", serie.tag, serie.buckets.last().map(|b| format!("{:.2}", b.avg)).unwrap_or_else(|| "-".to_string())))
+                }
+            }
+        }
+        @if !missing.is_empty() {
+            p { (format!("Not polled: {}", missing.join(", "))) }
+        }
+        @if let Some(error) = error {
+            p { (error) }
+        }
+    }
+}
+
+fn parse_bound(bound: &Option<String>) -> Option<f64> {
+    bound.as_ref().and_then(|bound| bound.trim().parse().ok())
+}
+
+fn y_range(series: &[Series], y_min: Option<f64>, y_max: Option<f64>) -> (f64, f64) {
+    let values = series
+        .iter()
+        .flat_map(|serie| serie.buckets.iter().map(|b| b.avg));
+    let (auto_min, auto_max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+        (min.min(v), max.max(v))
+    });
+    let (auto_min, auto_max) = if auto_min.is_finite() {
+        (auto_min, auto_max)
+    } else {
+        (0.0, 1.0)
+    };
+
+    let min = y_min.unwrap_or(auto_min);
+    let max = y_max.unwrap_or(auto_max);
+    if max > min {
+        (min, max)
+    } else {
+        (min - 1.0, min + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_the_polled_values() {
+        let mut trend = TrendBuffer::default();
+        for (second, value) in [(0, 1.0), (1, 3.0), (2, 5.0), (3, 7.0)] {
+            trend.record(second * 1000, &[("HR1".to_string(), value)]);
+        }
+        let buckets = trend.buckets("HR1", 1000, 3000, 2000);
+        let buckets: Vec<(i64, f64, f64, f64, u32)> = buckets
+            .iter()
+            .map(|b| (b.start, b.min, b.max, b.avg, b.count))
+            .collect();
+        assert_eq!(buckets, [(0, 3.0, 3.0, 3.0, 1), (2000, 5.0, 7.0, 6.0, 2)]);
+        assert!(trend.buckets("HR2", 0, 3000, 1000).is_empty());
+
+        // An hour later only the tag still being polled is kept.
+        let later = 4000 + BUFFER_WINDOW as i64 * 1000;
+        trend.record(later, &[("HR2".to_string(), 1.0)]);
+        assert!(trend.buckets("HR1", 0, later, 1000).is_empty());
+        assert_eq!(trend.buckets("HR2", 0, later, 1000).len(), 1);
+    }
+
+    #[test]
+    fn scales_the_y_axis() {
+        assert_eq!(parse_bound(&Some(" -2.5 ".to_string())), Some(-2.5));
+        assert_eq!(parse_bound(&Some(String::new())), None);
+        assert_eq!(parse_bound(&Some("abc".to_string())), None);
+        assert_eq!(parse_bound(&None), None);
+
+        assert_eq!(y_range(&[], None, None), (0.0, 1.0));
+        let series = [Series {
+            tag: "HR1".to_string(),
+            color: TREND_COLORS[0],
+            buckets: [4.0, -2.0, 10.0]
+                .iter()
+                .map(|&avg| Bucket {
+                    start: 0,
+                    min: avg,
+                    max: avg,
+                    avg,
+                    count: 1,
+                })
+                .collect(),
+        }];
+        assert_eq!(y_range(&series, None, None), (-2.0, 10.0));
+        assert_eq!(y_range(&series, Some(0.0), None), (0.0, 10.0));
+        assert_eq!(y_range(&series, None, Some(5.0)), (-2.0, 5.0));
+        // An empty or inverted range is widened around the minimum.
+        assert_eq!(y_range(&series, Some(20.0), None), (19.0, 21.0));
+        assert_eq!(y_range(&series, Some(3.0), Some(3.0)), (2.0, 4.0));
+    }
+}